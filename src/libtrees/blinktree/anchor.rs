@@ -0,0 +1,101 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// The anchor of the tree as described by Lehman and Yao.
+/// It knows the most left node of every level. The level 0 are the leafs, the root is the only
+/// node of the highest level. The tree only grows and shrinks at the top, so the levels of the
+/// nodes never change.
+#[deriving(Clone)]
+pub struct Anchor<Ptr> {
+    priv most_left: ~[Ptr]
+}
+
+impl<Ptr: Clone + Eq> Anchor<Ptr> {
+    /// an anchor of a tree, whose root is a leaf
+    pub fn new(root: Ptr) -> Anchor<Ptr> {
+        Anchor { most_left: ~[root] }
+    }
+    /// an anchor of a tree with the given most left nodes, starting at the leafs
+    pub fn from_levels(most_left: ~[Ptr]) -> Anchor<Ptr> {
+        assert!(!most_left.is_empty());
+        Anchor { most_left: most_left }
+    }
+    pub fn root<'a>(&'a self) -> &'a Ptr {
+        self.most_left.last()
+    }
+    pub fn is_root(&self, ptr: &Ptr) -> bool {
+        self.root() == ptr
+    }
+    /// the number of levels, a tree with a single leaf has a height of 1
+    pub fn height(&self) -> uint {
+        self.most_left.len()
+    }
+    /// the level of the root is `height() - 1`
+    pub fn root_level(&self) -> uint {
+        self.height() - 1
+    }
+    pub fn most_left<'a>(&'a self, level: uint) -> &'a Ptr {
+        &self.most_left[level]
+    }
+    /// adds a new level on top of the tree. `old_root` is the most left child of the new root.
+    pub fn push_root(&mut self, old_root: &Ptr, new_root: Ptr) {
+        assert!(self.is_root(old_root), "the root was changed concurrently");
+        self.most_left.push(new_root);
+    }
+    /// removes the level of the root `old_root`. Its only child becomes the root.
+    pub fn pop_root(&mut self, old_root: &Ptr) {
+        assert!(self.is_root(old_root), "the root was changed concurrently");
+        assert!(self.height() > 1, "the root is a leaf");
+        self.most_left.pop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Anchor;
+
+    #[test]
+    fn test_push_root() {
+        let mut anchor = Anchor::new(1u);
+        assert!(anchor.height() == 1 && anchor.is_root(&1));
+        anchor.push_root(&1, 5);
+        anchor.push_root(&5, 9);
+        assert!(anchor.height() == 3);
+        assert!(anchor.root_level() == 2);
+        assert!(*anchor.root() == 9 && !anchor.is_root(&5));
+        assert!(*anchor.most_left(0) == 1);
+        assert!(*anchor.most_left(1) == 5);
+    }
+    #[test]
+    fn test_pop_root() {
+        let mut anchor = Anchor::from_levels(~[1u, 5, 9]);
+        anchor.pop_root(&9);
+        assert!(anchor.height() == 2 && anchor.is_root(&5));
+        anchor.pop_root(&5);
+        assert!(anchor.height() == 1 && anchor.is_root(&1));
+        anchor.push_root(&1, 3);
+        assert!(*anchor.root() == 3 && *anchor.most_left(0) == 1);
+    }
+    #[test] #[should_fail]
+    fn test_pop_root_of_leaf() {
+        Anchor::new(1u).pop_root(&1);
+    }
+    #[test] #[should_fail]
+    fn test_push_root_of_old_root() {
+        let mut anchor = Anchor::new(1u);
+        anchor.push_root(&1, 5);
+        anchor.push_root(&1, 9);
+    }
+}
@@ -13,9 +13,13 @@
  * limitations under the License.
  */
 
+use std::cmp;
+use std::util;
+
 use algorithm;
 use node::{Node, Leaf, INode};
 use blinktree::physical_node::{PhysicalNode, T_LEAF, T_INODE};
+use blinktree::split_policy::{SplitPolicy, Midpoint};
 
 #[deriving(Clone)]
 pub enum Movement {
@@ -29,6 +33,11 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
                    Ptr: Clone + ToStr,
                    INODE: PhysicalNode<K,Ptr,Ptr>,
                    LEAF: PhysicalNode<K,V,Ptr>> {
+    /// a tree with duplicate keys is a multimap, otherwise inserting an equal key
+    /// replaces the value.
+    fn allows_duplicates(&self) -> bool {
+        false
+    }
     fn move_right<'a>(&self, node: &'a Node<INODE,LEAF>, key: &K) -> Option<&'a Ptr> {
         let can_contain = match node {
             &Leaf(ref leaf) => self.can_contain_key(leaf, key),
@@ -40,6 +49,15 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
             None
         }
     }
+    /// returns the index of the key in the leaf
+    fn position(&self, leaf: &LEAF, key: &K) -> Option<uint> {
+        let idx = algorithm::bsearch_idx(leaf.keys().slice_from(0), key);
+        if idx < leaf.keys().len() && leaf.keys()[idx].cmp(key) == Equal {
+            Some(idx)
+        } else {
+            None
+        }
+    }
     fn get_value<'a>(&self, leaf: &'a LEAF, key: &K) -> Option<&'a V> {
         if !self.can_contain_key(leaf,key) {
             return None;
@@ -48,7 +66,7 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
         debug!("[get] ptr: {}, keys: {} values: {}, key: {}, idx: {}",
                  leaf.my_ptr().to_str(), leaf.keys().to_str(),
                  leaf.values().to_str(), key.to_str(), idx.to_str());
-        if leaf.keys()[idx].cmp(key) == Equal {
+        if idx < leaf.keys().len() && leaf.keys()[idx].cmp(key) == Equal {
             Some(&leaf.values()[idx])
         } else {
             None
@@ -72,7 +90,14 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
             &INode(ref inode) => self.can_contain_key(inode, key)
         };
         if(! can_contain) {
-            return node.link_ptr().map(|r| (r, Right));
+            return match node.link_ptr() {
+                Some(link_ptr) => Some((link_ptr, Right)),
+                // a collapsed root has no link pointer, it forwards to its only child
+                None => match node {
+                    &INode(ref inode) => Some((&inode.values()[0], Down)),
+                    &Leaf(*) => None
+                }
+            };
         }
         match node {
             &Leaf(*) => None,
@@ -81,9 +106,22 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
         }
     }
 
+    /// Returns the number of keys that stay in the left node, when a node with `keys` is split.
+    /// The new key was inserted at `inserted_at`. See `split_policy`.
+    fn split_position(&self, keys: &[K], inserted_at: uint) -> uint {
+        Midpoint.split_position(keys, inserted_at)
+    }
+    /// The separator of a split leaf in its parent. None stores the max key of the left leaf.
+    /// See `split_policy`.
+    fn separator(&self, _left_max: &K, _right_min: &K) -> Option<K> {
+        None
+    }
+
     fn split_and_insert_leaf(&self, leaf: &mut LEAF, new_page: Ptr, key: K, value: V) -> LEAF {
-        let new_size = leaf.keys().len()/2;
+        let inserted_at = algorithm::bsearch_idx(leaf.keys().slice_from(0), &key);
         self.insert_leaf(leaf, key, value);
+        let new_size = split_position_in_bounds(
+            self.split_position(leaf.keys().slice_from(0), inserted_at), leaf.keys().len());
         let (keys_new, values_new) = leaf.split_at(new_size);
         let link_ptr = leaf.set_link_ptr(new_page.clone());
         PhysicalNode::new(T_LEAF, new_page, link_ptr, keys_new, values_new)
@@ -98,18 +136,35 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
     ///    .      .      .       .       .
     ///
     fn split_and_insert_inode(&self, inode: &mut INODE, new_page: Ptr, key: K, value: Ptr) -> INODE {
-        let new_size = inode.keys().len()/2;
+        let inserted_at = algorithm::bsearch_idx(inode.keys().slice_from(0), &key);
         self.insert_inode(inode, key, value);
+        let new_size = split_position_in_bounds(
+            self.split_position(inode.keys().slice_from(0), inserted_at), inode.keys().len());
         let (keys_new, values_new) = inode.split_at(new_size);
         debug!("[split_and_insert_inode] keys.len: {}, value.len: {}", keys_new.to_str(), values_new.to_str());
         let link_ptr = inode.set_link_ptr(new_page.clone());
         PhysicalNode::new(T_INODE, new_page, link_ptr, keys_new, values_new)
     }
 
-    fn insert_leaf(&self, leaf: &mut LEAF, key: K, value: V) {
+    /// inserts the key or replaces the value of an equal key. Returns the replaced value.
+    fn insert_leaf(&self, leaf: &mut LEAF, key: K, value: V) -> Option<V> {
         let idx = algorithm::bsearch_idx(leaf.keys().slice_from(0), &key);
+        if idx < leaf.keys().len() && leaf.keys()[idx].cmp(&key) == Equal {
+            return Some(util::replace(&mut leaf.mut_values()[idx], value));
+        }
         leaf.mut_keys().insert(idx, key);
         leaf.mut_values().insert(idx, value);
+        None
+    }
+    /// removes `key` from the leaf and returns its value.
+    /// The max key of the leaf may shrink, which only narrows the key range of the leaf.
+    /// Keys above the new max key are then forwarded to the right sibling.
+    fn remove_leaf(&self, leaf: &mut LEAF, key: &K) -> Option<V> {
+        self.position(leaf, key).map(|idx| self.remove_leaf_at(leaf, idx))
+    }
+    fn remove_leaf_at(&self, leaf: &mut LEAF, idx: uint) -> V {
+        leaf.mut_keys().remove(idx);
+        leaf.mut_values().remove(idx)
     }
     fn insert_inode(&self, inode: &mut INODE, key: K, value: Ptr) {
         let mut idx = algorithm::bsearch_idx(inode.keys().slice_from(0), &key);
@@ -125,11 +180,115 @@ pub trait BLinkOps<K: TotalOrd + ToStr,
         V1,
         Ptr1,
         N : PhysicalNode<K1,V1,Ptr1>>(&self, node: &N, key: &K1) -> bool {
-        node.is_root()
-        || (node.is_most_right_node() && key.cmp(node.max_key()) == Greater)
-        || (key.cmp(node.max_key()) == Less ||
-            key.cmp(node.max_key()) == Equal)
+        // an emptied node has no max key, it forwards every key to its right sibling.
+        // A root that was just split is not the most right node anymore and forwards the keys
+        // of its new sibling, like every other node.
+        !node.is_dead() && (
+            node.is_most_right_node()
+            || (!node.keys().is_empty() &&
+                (key.cmp(node.max_key()) == Less ||
+                 key.cmp(node.max_key()) == Equal)))
+    }
+
+    fn merge_right_leaf(&self, leaf: &mut LEAF, right: &mut LEAF) {
+        merge_right::<K, V, Ptr, LEAF>(leaf, right);
+    }
+    fn merge_right_inode(&self, inode: &mut INODE, right: &mut INODE) {
+        merge_right::<K, Ptr, Ptr, INODE>(inode, right);
+    }
+    fn split_leaf(&self, leaf: &mut LEAF, new_page: Ptr) -> LEAF {
+        let position = leaf.keys().len() / 2;
+        self.split_leaf_at(leaf, new_page, position)
+    }
+    fn split_inode(&self, inode: &mut INODE, new_page: Ptr) -> INODE {
+        let position = inode.keys().len() / 2;
+        self.split_inode_at(inode, new_page, position)
+    }
+    /// the leaf keeps the first `position` keys
+    fn split_leaf_at(&self, leaf: &mut LEAF, new_page: Ptr, position: uint) -> LEAF {
+        split_off::<K, V, Ptr, LEAF>(leaf, new_page, T_LEAF, position)
+    }
+    fn split_inode_at(&self, inode: &mut INODE, new_page: Ptr, position: uint) -> INODE {
+        split_off::<K, Ptr, Ptr, INODE>(inode, new_page, T_INODE, position)
+    }
+
+    /// The child at `idx + 1` was merged into the child at `idx`.
+    /// The separator of the child at `idx` is dropped, the child at `idx` takes over the
+    /// separator of the merged child.
+    fn unlink_right_child(&self, inode: &mut INODE, idx: uint) {
+        inode.mut_keys().remove(idx);
+        inode.mut_values().remove(idx + 1);
+    }
+
+    /// The child at `idx` borrowed keys from the child at `idx + 1`, which now lives at `new_ptr`.
+    fn replace_right_child(&self, inode: &mut INODE, idx: uint, separator: K, new_ptr: Ptr) {
+        inode.mut_keys()[idx] = separator;
+        inode.mut_values()[idx + 1] = new_ptr;
+    }
+}
+
+/// Moves all keys and values of the right sibling into `node`. The right sibling
+/// becomes dead and forwards every search to `node` through its link pointer.
+/// Only the node on the left grows, so a search that read the old link pointer or an
+/// old parent still finds the keys.
+///
+///    | node | -> | right | -> | next |     =>     | node + right | -> | next |
+///                                                      ^
+///                                          | dead | ---'
+///
+fn merge_right<K, V, Ptr: Clone, N: PhysicalNode<K,V,Ptr>>(node: &mut N, right: &mut N) {
+    let keys = util::replace(right.mut_keys(), ~[]);
+    let values = util::replace(right.mut_values(), ~[]);
+    node.mut_keys().push_all_move(keys);
+    node.mut_values().push_all_move(values);
+    match right.take_link_ptr() {
+        Some(link_ptr) => { node.set_link_ptr(link_ptr); }
+        None => { node.take_link_ptr(); }
     }
+    right.set_link_ptr(node.my_ptr().clone());
+    right.set_dead();
+}
+
+/// Splits the node after `position` keys without inserting a key.
+/// The upper part is returned as the new right sibling stored at `new_page`.
+fn split_off<K, V, Ptr: Clone, N: PhysicalNode<K,V,Ptr>>(node: &mut N, new_page: Ptr,
+                                                        node_type: uint, position: uint) -> N {
+    let (keys_new, values_new) = node.split_at(position);
+    let link_ptr = node.set_link_ptr(new_page.clone());
+    PhysicalNode::new(node_type, new_page, link_ptr, keys_new, values_new)
+}
+
+// both nodes keep at least one key
+fn split_position_in_bounds(position: uint, len: uint) -> uint {
+    cmp::max(1, cmp::min(position, len - 1))
+}
+
+/// Marks the operations of a multimap, see `DuplicateBLinkOps`.
+pub trait MultiBLinkOps<K: TotalOrd + ToStr,
+                        V: ToStr,
+                        Ptr: Clone + ToStr,
+                        INODE: PhysicalNode<K,Ptr,Ptr>,
+                        LEAF: PhysicalNode<K,V,Ptr>> : BLinkOps<K,V,Ptr,INODE,LEAF> {}
+
+/// Returns the split position nearest to the middle that does not split a run of equal keys.
+/// If all keys are equal, the run has to span both nodes and we split in the middle.
+fn run_boundary<K: TotalOrd>(keys: &[K]) -> uint {
+    let middle = if keys.len() > 2 { (keys.len() - 1) / 2 } else { 1 };
+    let mut distance = 0;
+    while distance < keys.len() {
+        if middle > distance {
+            let i = middle - distance;
+            if keys[i - 1].cmp(&keys[i]) != Equal {
+                return i;
+            }
+        }
+        let i = middle + distance + 1;
+        if i < keys.len() && keys[i - 1].cmp(&keys[i]) != Equal {
+            return i;
+        }
+        distance += 1;
+    }
+    middle
 }
 
 pub struct DefaultBLinkOps<K,V,Ptr, INODE, LEAF>;
@@ -142,10 +301,74 @@ impl <K: TotalOrd + ToStr,
       >
 BLinkOps<K,V,Ptr,INODE, LEAF> for DefaultBLinkOps<K,V,Ptr, INODE, LEAF> {}
 
+/// Splits the nodes with the given `SplitPolicy`.
+pub struct PolicyBLinkOps<K,V,Ptr, INODE, LEAF, P> {
+    policy: P
+}
+
+impl <K: TotalOrd + ToStr,
+      V: ToStr,
+      Ptr: Clone + ToStr,
+      INODE: PhysicalNode<K,Ptr,Ptr>,
+      LEAF: PhysicalNode<K,V,Ptr>,
+      P: SplitPolicy<K>
+      >
+BLinkOps<K,V,Ptr,INODE, LEAF> for PolicyBLinkOps<K,V,Ptr, INODE, LEAF, P> {
+    fn split_position(&self, keys: &[K], inserted_at: uint) -> uint {
+        self.policy.split_position(keys, inserted_at)
+    }
+    fn separator(&self, left_max: &K, right_min: &K) -> Option<K> {
+        self.policy.separator(left_max, right_min)
+    }
+}
+
+/// Keeps all values of equal keys, equal keys stay in insertion order.
+/// A run of equal keys can span several leafs. The max key of the left leaf is then the
+/// separator, so a search for the key always ends on the most left leaf of the run.
+/// The tree moves right to the last leaf of the run, before it appends a value to the run.
+pub struct DuplicateBLinkOps<K,V,Ptr, INODE, LEAF>;
+
+impl <K: TotalOrd + ToStr,
+      V: ToStr,
+      Ptr: Clone + ToStr,
+      INODE: PhysicalNode<K,Ptr,Ptr>,
+      LEAF: PhysicalNode<K,V,Ptr>
+      >
+BLinkOps<K,V,Ptr,INODE, LEAF> for DuplicateBLinkOps<K,V,Ptr, INODE, LEAF> {
+    fn allows_duplicates(&self) -> bool {
+        true
+    }
+    /// inserts the key behind all equal keys, nothing is ever replaced.
+    fn insert_leaf(&self, leaf: &mut LEAF, key: K, value: V) -> Option<V> {
+        let mut idx = algorithm::bsearch_idx(leaf.keys().slice_from(0), &key);
+        while idx < leaf.keys().len() && leaf.keys()[idx].cmp(&key) == Equal {
+            idx += 1;
+        }
+        leaf.mut_keys().insert(idx, key);
+        leaf.mut_values().insert(idx, value);
+        None
+    }
+    fn split_and_insert_leaf(&self, leaf: &mut LEAF, new_page: Ptr, key: K, value: V) -> LEAF {
+        self.insert_leaf(leaf, key, value);
+        let new_size = run_boundary(leaf.keys().slice_from(0));
+        let (keys_new, values_new) = leaf.split_at(new_size);
+        let link_ptr = leaf.set_link_ptr(new_page.clone());
+        PhysicalNode::new(T_LEAF, new_page, link_ptr, keys_new, values_new)
+    }
+}
+
+impl <K: TotalOrd + ToStr,
+      V: ToStr,
+      Ptr: Clone + ToStr,
+      INODE: PhysicalNode<K,Ptr,Ptr>,
+      LEAF: PhysicalNode<K,V,Ptr>
+      >
+MultiBLinkOps<K,V,Ptr,INODE, LEAF> for DuplicateBLinkOps<K,V,Ptr, INODE, LEAF> {}
+
 #[cfg(test)]
 mod test {
-    use super::{BLinkOps, DefaultBLinkOps};
-    use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_ROOT, T_LEAF};
+    use super::{BLinkOps, DefaultBLinkOps, DuplicateBLinkOps, run_boundary};
+    use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_ROOT, T_LEAF, T_INODE};
     macro_rules! can_contains_range(
         ($node:ident, $from:expr, $to:expr) => (
             for i in range($from, $to+1) {
@@ -161,6 +384,10 @@ mod test {
         fn test(&self) {
             self.test_can_contain_key();
             self.test_needs_split();
+            self.test_remove_leaf();
+            self.test_insert_leaf_replaces();
+            self.test_merge_right();
+            self.test_unlink_right_child();
             self.test_insert_into_inode_ptr_must_be_off_by_one();
         }
         fn test_can_contain_key(&self) {
@@ -170,12 +397,72 @@ mod test {
             can_contains_range!(root, 0u, 10);
             assert!(self.can_contain_key(&root, &10000));
 
+            let split_root : DefaultBLinkNode<uint, uint, uint> =
+                PhysicalNode::new(tpe, 0u, Some(1u), ~[2u],~[0u]);
+            can_contains_range!(split_root, 0u, 2);
+            assert!(!self.can_contain_key(&split_root, &3));
+
             let leaf : DefaultBLinkNode<uint, uint, uint> =
                 PhysicalNode::new(T_LEAF, 0u, None, ~[2u,4],~[0,1]);
             can_contains_range!(leaf, 0u, 4);
         }
         fn test_needs_split(&self) {
         }
+        fn test_remove_leaf(&self) {
+            let mut leaf: LEAF = PhysicalNode::new(T_LEAF, 0u, Some(1u), ~[1, 3, 5], ~[10, 30, 50]);
+            assert!(self.remove_leaf(&mut leaf, &4).is_none());
+            assert!(self.remove_leaf(&mut leaf, &6).is_none());
+            assert!(self.remove_leaf(&mut leaf, &3) == Some(30));
+            assert!(leaf.keys() == &~[1, 5]);
+            assert!(leaf.values() == &~[10, 50]);
+
+            assert!(self.remove_leaf(&mut leaf, &1) == Some(10));
+            assert!(self.remove_leaf(&mut leaf, &5) == Some(50));
+            assert!(leaf.keys().is_empty());
+            // an empty leaf with a right sibling cannot contain any key
+            assert!(!self.can_contain_key(&leaf, &1));
+            assert!(self.get_value(&leaf, &1).is_none());
+        }
+        fn test_insert_leaf_replaces(&self) {
+            let mut leaf: LEAF = PhysicalNode::new(T_LEAF, 0u, None, ~[], ~[]);
+            assert!(self.insert_leaf(&mut leaf, 3, 30).is_none());
+            assert!(self.insert_leaf(&mut leaf, 1, 10).is_none());
+            assert!(self.insert_leaf(&mut leaf, 3, 31) == Some(30));
+            assert!(self.insert_leaf(&mut leaf, 1, 11) == Some(10));
+            assert!(leaf.keys() == &~[1, 3]);
+            assert!(leaf.values() == &~[11, 31]);
+        }
+        fn test_merge_right(&self) {
+            let mut leaf: LEAF = PhysicalNode::new(T_LEAF, 0u, Some(1u), ~[1], ~[10]);
+            let mut right: LEAF = PhysicalNode::new(T_LEAF, 1u, Some(2u), ~[3, 5], ~[30, 50]);
+            self.merge_right_leaf(&mut leaf, &mut right);
+            assert!(leaf.keys() == &~[1, 3, 5]);
+            assert!(leaf.values() == &~[10, 30, 50]);
+            assert!(leaf.link_ptr() == Some(&2));
+            assert!(right.is_dead());
+            assert!(right.keys().is_empty());
+            assert!(right.link_ptr() == Some(&0));
+            assert!(!self.can_contain_key(&right, &3));
+
+            // merging the most right node makes the node the most right node
+            let mut leaf: LEAF = PhysicalNode::new(T_LEAF, 0u, Some(1u), ~[1], ~[10]);
+            let mut right: LEAF = PhysicalNode::new(T_LEAF, 1u, None, ~[3], ~[30]);
+            self.merge_right_leaf(&mut leaf, &mut right);
+            assert!(leaf.is_most_right_node());
+            assert!(self.can_contain_key(&leaf, &100));
+        }
+        //  parent keys:    2 | 4 | 6        =>     2 | 6
+        //  parent values: 0  1   2   3           0   1   3
+        fn test_unlink_right_child(&self) {
+            let mut inode: INODE = PhysicalNode::new(T_INODE, 10u, None, ~[2, 4, 6], ~[0, 1, 2, 3]);
+            self.unlink_right_child(&mut inode, 1);
+            assert!(inode.keys() == &~[2, 6]);
+            assert!(inode.values() == &~[0, 1, 3]);
+
+            self.replace_right_child(&mut inode, 1, 7, 4);
+            assert!(inode.keys() == &~[2, 7]);
+            assert!(inode.values() == &~[0, 1, 4]);
+        }
 
         //           ionde                otherwise
         //  keys:    . 4 .                  1 | 2 | 3
@@ -201,4 +488,38 @@ mod test {
         let ops = DefaultBLinkOps;
         ops.test();
     }
+
+    #[test]
+    fn test_run_boundary() {
+        //                  0 1 2 3 4
+        assert!(run_boundary([1, 2, 3, 4, 5]) == 2);
+        assert!(run_boundary([1, 2, 2, 2, 5]) == 1);
+        assert!(run_boundary([1, 1, 2, 2, 2]) == 2);
+        assert!(run_boundary([1, 1, 1, 1, 2]) == 4);
+        assert!(run_boundary([2, 2, 2, 2, 2]) == 2);
+        assert!(run_boundary([1, 2]) == 1);
+    }
+
+    #[test]
+    fn test_duplicate_blink_ops() {
+        let ops: DuplicateBLinkOps<uint, uint, uint,
+                                   DefaultBLinkNode<uint, uint, uint>,
+                                   DefaultBLinkNode<uint, uint, uint>> = DuplicateBLinkOps;
+        let mut leaf: DefaultBLinkNode<uint, uint, uint> =
+            PhysicalNode::new(T_LEAF, 0u, None, ~[], ~[]);
+        assert!(ops.insert_leaf(&mut leaf, 2, 20).is_none());
+        assert!(ops.insert_leaf(&mut leaf, 1, 10).is_none());
+        assert!(ops.insert_leaf(&mut leaf, 2, 21).is_none());
+        assert!(ops.insert_leaf(&mut leaf, 2, 22).is_none());
+        assert!(leaf.keys() == &~[1, 2, 2, 2]);
+        assert!(leaf.values() == &~[10, 20, 21, 22]);
+        assert!(ops.get_value(&leaf, &2) == Some(&20));
+
+        // the run of 2s stays in the new leaf
+        let new_leaf = ops.split_and_insert_leaf(&mut leaf, 1, 3, 30);
+        assert!(leaf.keys() == &~[1]);
+        assert!(new_leaf.keys() == &~[2, 2, 2, 3]);
+        assert!(new_leaf.values() == &~[20, 21, 22, 30]);
+        assert!(leaf.link_ptr() == Some(&1));
+    }
 }
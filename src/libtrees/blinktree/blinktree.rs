@@ -15,16 +15,24 @@
 
 
 use std::container::{Container};
-use std::cast;
+use std::util;
+use std::vec;
+use extra::arc::RWArc;
+use extra::sort::merge_sort;
 
+use algorithm;
 use lock::{LockManager, SimpleLockManager};
 use node::{Node, INode, Leaf};
 use persistent;
-use statistics::{StatisticsManager, AtomicStatistics};
-use storage::{StorageManager, StupidHashmapStorage};
-use blinktree::blink_ops::{BLinkOps, DefaultBLinkOps, Right, Down};
+use statistics::{StatisticsManager, AtomicStatistics, LockStatistics};
+use storage::{StorageManager, PageReaders, PageError, StupidHashmapStorage};
+use blinktree::anchor::Anchor;
+use blinktree::blink_ops::{BLinkOps, MultiBLinkOps, DefaultBLinkOps, DuplicateBLinkOps, Right, Down};
 use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_INODE, T_LEAF};
 
+// the failed validations of a lock-free search, before it waits for the lock of the leaf
+static MAX_OPTIMISTIC_RESTARTS: uint = 8;
+
 macro_rules! node_method(
     ($name:ident, $method:ident) => (
         match $name {
@@ -34,12 +42,78 @@ macro_rules! node_method(
     );
 )
 
+#[deriving(Eq, ToStr)]
+pub enum TreeError {
+    // the storage has no root
+    NoRoot,
+    // the max bytes of a leaf and of an inode, that the storage has stored for the tree
+    WrongNodeSizes(uint, uint),
+    // the smallest max bytes of a node, that the tree accepts
+    NodeSizeTooSmall(uint)
+}
+
+#[deriving(Eq, ToStr)]
+pub enum BulkLoadError {
+    NotEmpty,
+    InvalidFillFactor,
+    // the position of the first pair that is not sorted
+    UnsortedInput(uint)
+}
+
+// While it lives, the storage doesn't reuse the pages we may see.
+struct Pin<'a, Storage> {
+    storage: &'a Storage
+}
+
+#[unsafe_destructor]
+impl<'a, Storage: PageReaders> Drop for Pin<'a, Storage> {
+    fn drop(&mut self) {
+        self.storage.exit();
+    }
+}
+
+// the size of a node, that uses `fill_factor` of the space for its entries
+fn fill_limit(empty_size: uint, max_bytes: uint, fill_factor: f64) -> uint {
+    empty_size + ((max_bytes - empty_size) as f64 * fill_factor) as uint
+}
+
+// the smallest byte limit of a node: an empty node, that is not the most right one, and the
+// pointer to a child. The keys and values are unknown until they are inserted, so the pointer
+// is the only entry, whose size is known.
+fn min_node_bytes<K, Ptr: Clone, INODE: PhysicalNode<K, Ptr, Ptr>>(ptr: &Ptr) -> uint {
+    let inode: INODE = PhysicalNode::new(T_INODE, ptr.clone(), Some(ptr.clone()), ~[],
+                                         ~[ptr.clone()]);
+    inode.byte_size()
+}
+
+// the tree can't go on without the page, a damaged page ends the task with its error
+fn expect_page<Ptr: ToStr, N>(page: Result<Option<N>, PageError<Ptr>>, ptr: &Ptr) -> N {
+    match page {
+        Ok(Some(node)) => node,
+        Ok(None) => fail!("page {} doesn't exist", ptr.to_str()),
+        Err(err) => fail!(err.to_str())
+    }
+}
+
+// the operations, that don't return a `PageError`, end the task with it
+fn or_fail<T, Ptr: ToStr>(result: Result<T, PageError<Ptr>>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => fail!(err.to_str())
+    }
+}
+
+/// A B-link tree, that can be shared between tasks.
+/// The nodes are never changed in place. A writer holds the lock of a page, changes a copy of
+/// the node and writes it back, so readers without locks always see a consistent node.
 pub struct BTree<Ptr, Storage, LockManager, Stats, BLinkOps> {
-    root: Ptr,
+    anchor: RWArc<Anchor<Ptr>>,
     storage: Storage,
     lock_manager: LockManager,
     statistics: Stats,
-    max_size: uint,
+    // the nodes are split, if their serialized size exceeds these limits
+    max_leaf_bytes: uint,
+    max_inode_bytes: uint,
     ops: BLinkOps
 }
 
@@ -58,8 +132,8 @@ impl<'self,
     }
 }
 impl<K: TotalOrd + Clone + ToStr,
-     V: ToStr,
-     Ptr: Clone + Eq + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
      INODE:      PhysicalNode<K, Ptr, Ptr>,
      LEAF:       PhysicalNode<K, V, Ptr>,
      OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
@@ -68,61 +142,281 @@ impl<K: TotalOrd + Clone + ToStr,
      Stats:      StatisticsManager>
 persistent::Map<K,V>
 for BTree<Ptr, Storage, Locks, Stats, OPS> {
-    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
-        let (mut current_node, _) = self.find_leaf(key);
-        while !self.ops.can_contain_key(current_node.getLeaf(), key) {
-            let (current_ptr,_) = self.ops.scannode(current_node, key).unwrap();
-            current_node = self.storage.read(current_ptr).unwrap();
+    fn find(&self, key: &K) -> Option<V> {
+        or_fail(self.try_find(key))
+    }
+
+    fn insert(&self, key: K, value: V) -> Option<V> {
+        or_fail(self.try_insert(key, value))
+    }
+    fn remove(&self, key: &K) -> Option<V> {
+        or_fail(self.try_remove(key))
+    }
+}
+
+impl<K: TotalOrd + Clone + ToStr,
+     V: Eq + Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : MultiBLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+persistent::MultiMap<K,V>
+for BTree<Ptr, Storage, Locks, Stats, OPS> {
+    fn get_all(&self, key: &K) -> ~[V] {
+        let _pin = self.pin();
+        loop {
+            match self.get_all_optimistic(key) {
+                Some(values) => return values,
+                None => self.statistics.inc_restarts()
+            }
         }
-        self.ops.get_value(current_node.getLeaf(), key)
     }
 
-    fn insert(&self, key: K, value: V) {
-        let (leaf, mut visited_nodes) = self.find_leaf(&key);
-        let current_ptr = leaf.my_ptr();
+    fn remove_one(&self, key: &K, value: &V) -> bool {
+        let _pin = self.pin();
+        let (leaf, parents) = self.find_leaf(key);
         self.lock_manager.lock(leaf.my_ptr().clone());
-        let current_node = self.read(current_ptr);
-        let (mut current_ptr, mut current_node) = self.move_right(current_node, &key);
-        unsafe {
-            let mut_self = cast::transmute_mut(self);
-            let mut insert_res = mut_self.insert_into_leaf(current_node, key, value);
+        let (mut current_ptr, mut current_node) = self.move_right(leaf.my_ptr().clone(), key);
+        loop {
+            let (found, run_ends) = {
+                let leaf = current_node.getLeaf();
+                let keys = leaf.keys();
+                let mut idx = algorithm::bsearch_idx(keys.slice_from(0), key);
+                let mut found = None;
+                while idx < keys.len() && keys[idx].cmp(key) == Equal {
+                    if &leaf.values()[idx] == value {
+                        found = Some(idx);
+                        break;
+                    }
+                    idx += 1;
+                }
+                (found, idx < keys.len() || leaf.link_ptr().is_none())
+            };
+            match found {
+                Some(idx) => {
+                    self.ops.remove_leaf_at(current_node.getMutLeaf(), idx);
+                    self.removed_from_leaf(current_ptr, current_node, true, parents);
+                    return true;
+                }
+                None => {}
+            }
+            // the run of equal keys may continue on the right sibling.
+            // It can't be dead, it would have to be merged into the leaf we hold the lock of.
+            if run_ends {
+                self.lock_manager.unlock(&current_ptr);
+                return false;
+            }
+            let next_ptr = current_node.link_ptr().unwrap().clone();
+            self.move_lock(&current_ptr, false, &next_ptr);
+            current_ptr = next_ptr;
+            current_node = self.read(&current_ptr);
+        }
+    }
+}
 
-            self.statistics.inc_elements();
-            while insert_res.is_some() {
-                let (key, ptr) = insert_res.unwrap();
-                let old_ptr = current_ptr;
-                match visited_nodes.pop_opt() {
-                    Some(p) => current_ptr = p,
-                    None => {
-                        if old_ptr == &self.root {   // we need to split the root
-                            mut_self.new_root(cast::transmute_mut(current_node), key, old_ptr, &ptr);
-                            break;
-                        } else { // root was splitted, need a new visited nodes stack to backtrace
-                            let (_, visited_stack) = self.find_node(&key, |n| {n.my_ptr() == old_ptr});
-                            visited_nodes = visited_stack;
-                            visited_nodes.pop();
-                            current_ptr = visited_nodes.pop();
+impl<K: TotalOrd + Clone + ToStr,
+     V: Eq + Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : MultiBLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+BTree<Ptr, Storage, Locks, Stats, OPS> {
+    // collects the run of equal keys without locks. Returns None, if a writer changed a leaf
+    // of the run while we read it.
+    fn get_all_optimistic(&self, key: &K) -> Option<~[V]> {
+        let mut values = ~[];
+        let (mut ptr, mut version, mut node) = match or_fail(self.find_leaf_optimistic(key)) {
+            Some(leaf) => leaf,
+            None => return None
+        };
+        loop {
+            if node.is_dead() {
+                // the leaf was merged into a leaf we have already seen
+                return None;
+            }
+            let next_ptr = {
+                let leaf = node.getLeaf();
+                let keys = leaf.keys();
+                let mut idx = algorithm::bsearch_idx(keys.slice_from(0), key);
+                while idx < keys.len() && keys[idx].cmp(key) == Equal {
+                    values.push(leaf.values()[idx].clone());
+                    idx += 1;
+                }
+                // the run of equal keys may continue on the right sibling
+                if idx < keys.len() {
+                    None
+                } else {
+                    leaf.link_ptr().map(|p| p.clone())
+                }
+            };
+            if self.changed_since(&ptr, version) {
+                return None;
+            }
+            ptr = match next_ptr {
+                Some(next_ptr) => next_ptr,
+                None => return Some(values)
+            };
+            match or_fail(self.read_versioned(&ptr)) {
+                Some((next_version, next_node)) => {
+                    version = next_version;
+                    node = next_node;
+                }
+                None => return None
+            }
+        }
+    }
+}
+
+impl<'a,
+     K: TotalOrd + Clone + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+persistent::IteratableMap<'a, K, V, BTreeIterator<'a, K, V, Ptr, Storage, Locks, Stats, OPS>>
+for BTree<Ptr, Storage, Locks, Stats, OPS> {
+    fn iter(&'a self, from: &K, to: &K) -> BTreeIterator<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+        let (leaf, _) = self.find_leaf(from);
+        BTreeIterator {
+            btree: self,
+            current_ptr: Some(leaf.my_ptr().clone()),
+            current_version: None,
+            lower: Some(from.clone()),
+            lower_inclusive: true,
+            upper: Some(to.clone())
+        }
+    }
+    fn iter_all(&'a self) -> BTreeIterator<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+        BTreeIterator {
+            btree: self,
+            current_ptr: Some(self.most_left_leaf()),
+            current_version: None,
+            lower: None,
+            lower_inclusive: true,
+            upper: None
+        }
+    }
+}
+
+/// Iterates over the leafs by following their link pointers.
+/// No locks are taken. Every call of `next` reads the current leaf again and continues
+/// after the last returned key, so keys that moved to a right sibling by a split are still found.
+/// The leaf is only pinned during a call of `next`. If it changed in between, the iterator
+/// searches the leaf of the last returned key again, because the page may have been freed and
+/// reused.
+pub struct BTreeIterator<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    btree: &'a BTree<Ptr, Storage, Locks, Stats, OPS>,
+    current_ptr: Option<Ptr>,
+    // the version of the current leaf, when the last call of `next` returned
+    current_version: Option<uint>,
+    lower: Option<K>,
+    lower_inclusive: bool,
+    upper: Option<K>
+}
+
+impl<'a,
+     K: TotalOrd + Clone + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+Iterator<(K, V)> for BTreeIterator<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    fn next(&mut self) -> Option<(K, V)> {
+        let _pin = self.btree.pin();
+        loop {
+            let ptr = match self.current_ptr {
+                Some(ref ptr) => ptr.clone(),
+                None => return None
+            };
+            let versioned = match or_fail(self.btree.read_versioned(&ptr)) {
+                Some((version, node)) => {
+                    let unchanged = match self.current_version {
+                        Some(current_version) => current_version == version,
+                        None => true
+                    };
+                    if unchanged && node.isLeaf() { Some((version, node)) } else { None }
+                }
+                None => None
+            };
+            let (version, node) = match versioned {
+                Some(versioned) => versioned,
+                None => {
+                    // the leaf changed since the last call or was freed and maybe reused,
+                    // we search the leaf of the last key again
+                    self.current_ptr = Some(match self.lower {
+                        Some(ref lower) => {
+                            let (leaf, _) = self.btree.find_leaf(lower);
+                            leaf.my_ptr().clone()
                         }
+                        None => self.btree.most_left_leaf()
+                    });
+                    self.current_version = None;
+                    continue;
+                }
+            };
+            let leaf = node.getLeaf();
+            let keys = leaf.keys().slice_from(0);
+            let idx = match self.lower {
+                None => 0,
+                Some(ref lower) => {
+                    let idx = algorithm::bsearch_idx(keys, lower);
+                    if !self.lower_inclusive && idx < keys.len() && keys[idx].cmp(lower) == Equal {
+                        idx + 1
+                    } else {
+                        idx
+                    }
+                }
+            };
+            let entry = if idx < keys.len() {
+                Some((keys[idx].clone(), leaf.values()[idx].clone()))
+            } else {
+                None
+            };
+            // we read the leaf again, if a writer changed it in the meantime
+            if self.btree.changed_since(&ptr, version) {
+                self.btree.statistics.inc_restarts();
+                continue;
+            }
+            match entry {
+                Some((key, value)) => {
+                    match self.upper {
+                        Some(ref upper) if key.cmp(upper) == Greater => {
+                            self.current_ptr = None;
+                            return None;
+                        }
+                        _ => {}
                     }
+                    self.lower = Some(key.clone());
+                    self.lower_inclusive = false;
+                    self.current_version = Some(version);
+                    return Some((key, value));
                 }
-                self.lock_manager.lock(current_ptr.clone());
-                self.lock_manager.unlock(old_ptr);
-                current_node = self.read(current_ptr);
-                insert_res = mut_self.insert_into_inode(current_node, key, ptr);
+                None => {}
             }
+            // the leaf is exhausted or dead, a dead leaf links to the leaf that took its keys
+            self.current_ptr = leaf.link_ptr().map(|p| p.clone());
+            self.current_version = None;
         }
-        self.statistics.inc_insertions();
-        self.lock_manager.unlock(current_ptr);
-    }
-    #[allow(unused_variable)]
-    fn remove(&self, key: &K) {
-        fail!();
     }
 }
 
 impl<K: TotalOrd + Clone + ToStr,
-     V: ToStr,
-     Ptr: Clone + Eq + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
      INODE:      PhysicalNode<K, Ptr, Ptr>,
      LEAF:       PhysicalNode<K, V, Ptr>,
      OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
@@ -130,197 +424,1439 @@ impl<K: TotalOrd + Clone + ToStr,
      Locks:      LockManager<Ptr>,
      Stats:      StatisticsManager>
 BTree<Ptr, Storage, Locks, Stats, OPS> {
-    fn find_node<'a>(&'a self, key: &K, predicate: &fn(n : &Node<INODE, LEAF>) -> bool)
-        -> (&'a Node<INODE,LEAF>, ~[&'a Ptr]) {
-        let mut visited_nodes = ~[&self.root];
-        let mut current_ptr = &self.root;
-        let mut current_node = self.read(current_ptr);
+    /// Creates an empty tree, whose leafs and inodes fit into pages of `page_size` bytes.
+    pub fn new(storage: Storage, lock_manager: Locks, statistics: Stats, ops: OPS, page_size: uint)
+        -> Result<BTree<Ptr, Storage, Locks, Stats, OPS>, TreeError> {
+        BTree::new_with_node_sizes(storage, lock_manager, statistics, ops, page_size, page_size)
+    }
+    /// Creates an empty tree with separate byte limits for leafs and inodes.
+    /// Fails with `NodeSizeTooSmall`, if a limit leaves no room for an entry in a node.
+    pub fn new_with_node_sizes(storage: Storage, lock_manager: Locks, statistics: Stats, ops: OPS,
+                               max_leaf_bytes: uint, max_inode_bytes: uint)
+        -> Result<BTree<Ptr, Storage, Locks, Stats, OPS>, TreeError> {
+        let root_ptr = storage.new_page();
+        let min_bytes = min_node_bytes::<K, Ptr, INODE>(&root_ptr);
+        if max_leaf_bytes < min_bytes || max_inode_bytes < min_bytes {
+            storage.free_page(&root_ptr);
+            return Err(NodeSizeTooSmall(min_bytes));
+        }
+        storage.set_node_sizes(max_leaf_bytes, max_inode_bytes);
+        let root: LEAF = PhysicalNode::new(T_LEAF, root_ptr.clone(), None, ~[], ~[]);
+        storage.write(&root_ptr, Leaf(root));
+        storage.set_root(&root_ptr);
+        statistics.inc_leafs();
+        Ok(BTree {
+            anchor: RWArc::new(Anchor::new(root_ptr)),
+            storage: storage,
+            lock_manager: lock_manager,
+            statistics: statistics,
+            max_leaf_bytes: max_leaf_bytes,
+            max_inode_bytes: max_inode_bytes,
+            ops: ops
+        })
+    }
+    /// Opens the tree, whose root the storage remembers from an earlier process.
+    /// The anchor is rebuilt from the most left node of every level and the statistics
+    /// by following the links of every level.
+    pub fn open(storage: Storage, lock_manager: Locks, statistics: Stats, ops: OPS,
+                page_size: uint) -> Result<BTree<Ptr, Storage, Locks, Stats, OPS>, TreeError> {
+        BTree::open_with_node_sizes(storage, lock_manager, statistics, ops, page_size, page_size)
+    }
+    /// Opens the tree with separate byte limits for leafs and inodes. They must be the limits,
+    /// that the tree was created with, if the storage has stored them.
+    pub fn open_with_node_sizes(storage: Storage, lock_manager: Locks, statistics: Stats,
+                                ops: OPS, max_leaf_bytes: uint, max_inode_bytes: uint)
+        -> Result<BTree<Ptr, Storage, Locks, Stats, OPS>, TreeError> {
+        let root_ptr = match storage.root() {
+            Some(root_ptr) => root_ptr,
+            None => return Err(NoRoot)
+        };
+        let min_bytes = min_node_bytes::<K, Ptr, INODE>(&root_ptr);
+        if max_leaf_bytes < min_bytes || max_inode_bytes < min_bytes {
+            return Err(NodeSizeTooSmall(min_bytes));
+        }
+        match storage.node_sizes() {
+            Some((leaf_bytes, inode_bytes)) if leaf_bytes != max_leaf_bytes ||
+                                               inode_bytes != max_inode_bytes => {
+                return Err(WrongNodeSizes(leaf_bytes, inode_bytes));
+            }
+            _ => {}
+        }
+        let mut most_left = ~[root_ptr.clone()];
+        let mut node = expect_page(storage.read(&root_ptr), &root_ptr);
+        while node.isINode() {
+            let child = node.getINode().values()[0].clone();
+            node = expect_page(storage.read(&child), &child);
+            most_left.push(child);
+        }
+        most_left.reverse();
+        for (level, first) in most_left.iter().enumerate() {
+            let mut next = Some(first.clone());
+            while next.is_some() {
+                let node = expect_page(storage.read(next.get_ref()), next.get_ref());
+                if level == 0 {
+                    statistics.inc_leafs();
+                    for _ in range(0, node.keys().len()) {
+                        statistics.inc_elements();
+                    }
+                } else {
+                    statistics.inc_inodes();
+                }
+                next = node.link_ptr().map(|p| p.clone());
+            }
+        }
+        Ok(BTree {
+            anchor: RWArc::new(Anchor::from_levels(most_left)),
+            storage: storage,
+            lock_manager: lock_manager,
+            statistics: statistics,
+            max_leaf_bytes: max_leaf_bytes,
+            max_inode_bytes: max_inode_bytes,
+            ops: ops
+        })
+    }
+
+    /// Builds the tree bottom-up from pairs sorted by key.
+    /// Every node uses `fill_factor` of the space for its entries and the leafs are linked
+    /// from left to right. The tree has to be empty and consist of its root leaf, a tree that
+    /// was emptied by removals may still have inodes. Unsorted input is rejected and the
+    /// tree stays empty.
+    pub fn bulk_load<I: Iterator<(K, V)>>(&mut self, iter: I, fill_factor: f64)
+        -> Result<(), BulkLoadError> {
+        if self.statistics.elements() != 0 || self.height() != 1 {
+            return Err(NotEmpty);
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(InvalidFillFactor);
+        }
+        let leaf_limit = fill_limit(self.empty_node_size(T_LEAF), self.max_leaf_bytes, fill_factor);
+        let inode_limit = fill_limit(self.empty_node_size(T_INODE), self.max_inode_bytes, fill_factor);
+
+        // every leaf we have written, so we can free them again on unsorted input.
+        // The statistics are only updated, when all leafs are written.
+        let mut pages: ~[Ptr] = ~[];
+        // the max key and the pointer of every node on the current level
+        let mut level: ~[(K, Ptr)] = ~[];
+        // the current leaf is written, when we know the pointer of its right sibling.
+        // Until then it links to itself, so its size already contains the link pointer.
+        let mut current: Option<LEAF> = None;
+        let mut last_key: Option<K> = None;
+        let mut count = 0u;
+        let mut iter = iter;
+        for (key, value) in iter {
+            let sorted = match last_key {
+                Some(ref last_key) => match key.cmp(last_key) {
+                    Greater => true,
+                    Equal => self.ops.allows_duplicates(),
+                    Less => false
+                },
+                None => true
+            };
+            if !sorted {
+                for ptr in pages.iter() {
+                    self.storage.free_page(ptr);
+                }
+                return Err(UnsortedInput(count));
+            }
+            last_key = Some(key.clone());
+            let full = match current {
+                Some(ref leaf) => leaf.overflows_with(&key, &value, leaf_limit),
+                None => true
+            };
+            if full {
+                let ptr = self.storage.new_page();
+                pages.push(ptr.clone());
+                let next: LEAF = PhysicalNode::new(T_LEAF, ptr.clone(), Some(ptr.clone()), ~[], ~[]);
+                match util::replace(&mut current, Some(next)) {
+                    Some(leaf) => {
+                        let mut leaf = leaf;
+                        leaf.set_link_ptr(ptr);
+                        let max_key = leaf.max_key().clone();
+                        self.write_bulk_node(max_key, Leaf(leaf), &mut level);
+                    }
+                    None => {}
+                }
+            }
+            let leaf = current.get_mut_ref();
+            leaf.mut_keys().push(key);
+            leaf.mut_values().push(value);
+            count += 1;
+        }
+        match current {
+            Some(leaf) => {
+                let mut leaf = leaf;
+                leaf.take_link_ptr();
+                let max_key = leaf.max_key().clone();
+                self.write_bulk_node(max_key, Leaf(leaf), &mut level);
+            }
+            None => return Ok(())
+        }
+        for _ in range(0, pages.len()) {
+            self.statistics.inc_leafs();
+        }
+
+        // the most left node of every level, starting at the leafs
+        let mut most_left = ~[];
+        while level.len() > 1 {
+            let children = util::replace(&mut level, ~[]);
+            match children[0] {
+                (_, ref first_child) => most_left.push(first_child.clone())
+            }
+            let mut current: Option<INODE> = None;
+            for (key, ptr) in children.move_iter() {
+                let full = match current {
+                    // an inode needs at least two children, otherwise the levels never shrink
+                    Some(ref inode) => inode.values().len() >= 2 &&
+                        inode.overflows_with(&key, &ptr, inode_limit),
+                    None => true
+                };
+                if full {
+                    let new_ptr = self.storage.new_page();
+                    let next: INODE = PhysicalNode::new(T_INODE, new_ptr.clone(), Some(new_ptr.clone()),
+                                                        ~[], ~[]);
+                    match util::replace(&mut current, Some(next)) {
+                        Some(inode) => {
+                            let mut inode = inode;
+                            inode.set_link_ptr(new_ptr);
+                            let max_key = inode.max_key().clone();
+                            self.write_bulk_node(max_key, INode(inode), &mut level);
+                            self.statistics.inc_inodes();
+                        }
+                        None => {}
+                    }
+                }
+                let inode = current.get_mut_ref();
+                inode.mut_keys().push(key);
+                inode.mut_values().push(ptr);
+            }
+            let mut inode = current.unwrap();
+            inode.take_link_ptr();
+            // the most right inode has no separator for its last child
+            let max_key = inode.mut_keys().pop();
+            self.write_bulk_node(max_key, INode(inode), &mut level);
+            self.statistics.inc_inodes();
+        }
+
+        let (_, root_ptr) = level.pop();
+        most_left.push(root_ptr.clone());
+        let old_root = self.root();
+        let mut new_anchor = Some(Anchor::from_levels(most_left));
+        do self.anchor.write |anchor| {
+            *anchor = new_anchor.take_unwrap();
+        }
+        self.storage.set_root(&root_ptr);
+        self.storage.free_page(&old_root);
+        self.statistics.dec_leafs();
+        for _ in range(0, count) {
+            self.statistics.inc_elements();
+        }
+        Ok(())
+    }
+    fn write_bulk_node(&mut self, max_key: K, node: Node<INODE, LEAF>, level: &mut ~[(K, Ptr)]) {
+        let ptr = node.my_ptr().clone();
+        self.storage.write(&ptr, node);
+        level.push((max_key, ptr));
+    }
+
+    // goes down to the leaf, that can contain the key, without locks. The version of every
+    // inner node is validated after we decided where to go next, the caller validates the
+    // version of the leaf. Returns None, if a writer changed a node, so we have to restart.
+    fn find_leaf_optimistic(&self, key: &K)
+        -> Result<Option<(Ptr, uint, Node<INODE, LEAF>)>, PageError<Ptr>> {
+        let mut ptr = self.root();
+        loop {
+            let (version, node) = match try!(self.read_versioned(&ptr)) {
+                Some(versioned) => versioned,
+                None => return Ok(None)
+            };
+            let next_ptr = match self.ops.scannode(&node, key) {
+                Some((next_ptr, _)) => next_ptr.clone(),
+                None => return Ok(Some((ptr, version, node)))
+            };
+            if self.changed_since(&ptr, version) {
+                return Ok(None);
+            }
+            ptr = next_ptr;
+        }
+    }
+    fn find_optimistic(&self, key: &K) -> Result<Option<Option<V>>, PageError<Ptr>> {
+        let (ptr, version, node) = match try!(self.find_leaf_optimistic(key)) {
+            Some(leaf) => leaf,
+            None => return Ok(None)
+        };
+        let value = self.ops.get_value(node.getLeaf(), key).map(|v| v.clone());
+        if self.changed_since(&ptr, version) {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+    // goes down to the node on `level`, that can contain the key.
+    // Returns the node and the backtrace stack of its parents.
+    fn find_node(&self, key: &K, level: uint) -> (Node<INODE,LEAF>, ~[Ptr]) {
+        or_fail(self.try_find_node(key, level))
+    }
+    fn try_find_node(&self, key: &K, level: uint)
+        -> Result<(Node<INODE,LEAF>, ~[Ptr]), PageError<Ptr>> {
+        let (root, mut current_level) = do self.anchor.read |anchor| {
+            (anchor.root().clone(), anchor.root_level())
+        };
+        let mut current_node = try!(self.try_read(&root));
+        let mut visited_nodes = ~[root];
         // going the tree down
-        while current_node.isINode() && predicate(current_node) {
-            match self.ops.scannode(current_node, key) {
+        while current_level > level {
+            let next_ptr = match self.ops.scannode(&current_node, key) {
                 // link pointer are not saved for backtracing
-                Some((id, Right)) => {
-                    current_ptr = id;
-                }
+                Some((id, Right)) => id.clone(),
                 // was not a link pointer, saving for backtracing
                 Some((id, Down)) => {
-                    visited_nodes.push(id);
-                    current_ptr = id;
+                    visited_nodes.push(id.clone());
+                    current_level -= 1;
+                    id.clone()
                 }
                 None => fail!("inconsistent BTree")
-            }
-            current_node = self.storage.read(current_ptr).unwrap();
+            };
+            current_node = try!(self.try_read(&next_ptr));
         }
         // pops the leaf from the backtrace stack
         visited_nodes.pop_opt();
-        return (current_node, visited_nodes)
+        Ok((current_node, visited_nodes))
     }
-    fn find_leaf<'a>(&'a self, key: &K) -> (&'a Node<INODE,LEAF>, ~[&'a Ptr]) {
-        self.find_node(key, |_| {true})
+    fn find_leaf(&self, key: &K) -> (Node<INODE,LEAF>, ~[Ptr]) {
+        self.find_node(key, 0)
     }
-    // ensures that we are on the node that can contains the key
-    fn move_right<'a>(&'a self, node: &'a Node<INODE, LEAF>, key: &K)
-        -> (&'a Ptr, &'a Node<INODE, LEAF>) {
-
-        let mut current_node = node;
-        let mut current_ptr = node.my_ptr();
+    fn try_find_leaf(&self, key: &K) -> Result<(Node<INODE,LEAF>, ~[Ptr]), PageError<Ptr>> {
+        self.try_find_node(key, 0)
+    }
+    // goes down to the leaf that can contain the key and locks it.
+    // Returns the leaf and its backtrace stack.
+    // A search ends on the most left leaf of a run of equal keys. A multimap appends to the run,
+    // so we move right to the last leaf of the run.
+    fn lock_leaf(&self, key: &K) -> (Ptr, ~[Ptr]) {
+        or_fail(self.try_lock_leaf(key))
+    }
+    // no lock is held, if it returns an error
+    fn try_lock_leaf(&self, key: &K) -> Result<(Ptr, ~[Ptr]), PageError<Ptr>> {
+        let (leaf, parents) = try!(self.try_find_leaf(key));
+        self.lock_manager.lock(leaf.my_ptr().clone());
+        let (mut ptr, mut node) = try!(self.try_move_right(leaf.my_ptr().clone(), key));
+        if !self.ops.allows_duplicates() {
+            return Ok((ptr, parents));
+        }
         loop {
-            let maybe_right_ptr = self.ops.move_right(current_node, key);
-            match maybe_right_ptr {
-                Some(ptr) => {
-                    self.lock_manager.lock(ptr.clone());
-                    self.lock_manager.unlock(current_ptr);
-                    current_ptr = ptr;
-                    current_node = self.storage.read(current_ptr).unwrap();
+            let run_continues = {
+                let keys = node.keys();
+                !keys.is_empty() && keys[keys.len() - 1].cmp(key) == Equal
+            };
+            if !run_continues {
+                return Ok((ptr, parents));
+            }
+            match self.lock_run_continuation(&node, key) {
+                Ok(Some((next_ptr, next_node))) => {
+                    self.lock_manager.unlock(&ptr);
+                    ptr = next_ptr;
+                    node = next_node;
+                }
+                Ok(None) => return Ok((ptr, parents)),
+                Err(err) => {
+                    self.lock_manager.unlock(&ptr);
+                    return Err(err);
                 }
-                _ => break
             }
         }
-        (current_ptr, current_node)
     }
-
-    // if a split was necessary, it returns the pointer and the minimum key of the new leaf.
-    // this method mutates the tree. call it only, if you hold a lock of the `node`.
-    fn insert_into_leaf(&mut self, node: &Node<INODE, LEAF>, key: K, value: V) -> Option<(K, Ptr)> {
-        let leaf = node.getLeaf();
-        let at_least_one_left = self.max_size - 1;
-        if !leaf.needs_split(at_least_one_left) {
-            unsafe {  // not really, becouse we hold a lock of this node
-                self.ops.insert_leaf(cast::transmute_mut(leaf), key, value);
-                let mut_storage = cast::transmute_mut(&self.storage);
-                mut_storage.write(&leaf.my_ptr().clone(), node);
+    // returns the next leaf right of `node`, that has keys, if it starts with `key`.
+    // Emptied leafs are skipped. The returned leaf is locked and the lock of `node` is kept.
+    // call it only, if you hold the lock of `node`.
+    fn lock_run_continuation(&self, node: &Node<INODE, LEAF>, key: &K)
+        -> Result<Option<(Ptr, Node<INODE, LEAF>)>, PageError<Ptr>> {
+        // the siblings can't be dead, they would have to be merged into a leaf we hold the lock of
+        let mut next_ptr = node.link_ptr().map(|p| p.clone());
+        // the emptied leaf we are on
+        let mut empty_ptr: Option<Ptr> = None;
+        while next_ptr.is_some() {
+            let ptr = next_ptr.take_unwrap();
+            self.lock_manager.lock(ptr.clone());
+            match empty_ptr.take() {
+                Some(empty_ptr) => self.lock_manager.unlock(&empty_ptr),
+                None => {}
             }
-            return None;
-        } else {
-            debug!("[insert_into_leaf] spliting: {}", leaf.keys().to_str());
-            let new_child_ptr = self.storage.new_page();
-            unsafe {  // not really, becouse we hold a lock of this node
-                use std::cast::transmute_mut;
-
-                let new_leaf = self.ops.split_and_insert_leaf(
-                    transmute_mut(leaf), new_child_ptr.clone(), key, value);
-                let leaf_max_key = leaf.max_key().clone();
-                let mut_storage = transmute_mut(&self.storage);
-                mut_storage.write(&new_child_ptr, &Leaf(new_leaf));
-                mut_storage.write(&leaf.my_ptr().clone(), node);
-                self.statistics.inc_leafs();
-                return Some((leaf_max_key, new_child_ptr));
+            let next_node = match self.try_read(&ptr) {
+                Ok(next_node) => next_node,
+                Err(err) => {
+                    self.lock_manager.unlock(&ptr);
+                    return Err(err);
+                }
+            };
+            if !next_node.keys().is_empty() {
+                if next_node.keys()[0].cmp(key) == Equal {
+                    return Ok(Some((ptr, next_node)));
+                }
+                self.lock_manager.unlock(&ptr);
+                return Ok(None);
             }
+            next_ptr = next_node.link_ptr().map(|p| p.clone());
+            empty_ptr = Some(ptr);
         }
+        match empty_ptr {
+            Some(empty_ptr) => self.lock_manager.unlock(&empty_ptr),
+            None => {}
+        }
+        Ok(None)
+    }
+    // inserts into the leaf `ptr` and propagates the splits up to the root.
+    // call it only, if you hold the lock of `ptr`. The lock is released when it returns.
+    fn insert_locked(&self, ptr: Ptr, parents: ~[Ptr], key: K, value: V) -> Option<V> {
+        or_fail(self.try_insert_locked(ptr, parents, key, value))
     }
-    fn insert_into_inode(&mut self, node: &Node<INODE, LEAF>, key: K, ptr: Ptr) -> Option<(K, Ptr)> {
-        let inode = node.getINode();
-        let at_least_one_left = self.max_size - 1;
-        if !inode.needs_split(at_least_one_left) {
-            unsafe {  // not really, becouse we hold a lock over this node
-                self.ops.insert_inode(cast::transmute_mut(inode), key, ptr);
-                cast::transmute_mut(&self.storage).write(&inode.my_ptr().clone(), node);
+    fn try_insert_locked(&self, ptr: Ptr, parents: ~[Ptr], key: K, value: V)
+        -> Result<Option<V>, PageError<Ptr>> {
+        let leaf = match self.try_read(&ptr) {
+            Ok(leaf) => leaf,
+            Err(err) => {
+                self.lock_manager.unlock(&ptr);
+                return Err(err);
             }
-            return None;
-        } else { // split is needed
-            let new_page_ptr = self.storage.new_page();
-
-            unsafe {  // not really, becouse we hold a lock of this node
-                let new_inode = self.ops.split_and_insert_inode(
-                    cast::transmute_mut(inode), new_page_ptr.clone(), key, ptr);
-
-                let inode_max_key = inode.max_key().clone();
-                let mut_storage = cast::transmute_mut(&self.storage);
-                mut_storage.write(&new_page_ptr, &INode(new_inode));
-                mut_storage.write(&inode.my_ptr().clone(), node);
-                self.statistics.inc_inodes();
-                return Some((inode_max_key, new_page_ptr));
+        };
+        let (old_value, separators) = self.insert_into_leaf(leaf, key, value);
+        if old_value.is_none() {
+            self.statistics.inc_elements();
+        }
+        self.statistics.inc_insertions();
+        try!(self.try_insert_separators(ptr, parents, separators));
+        Ok(old_value)
+    }
+    // inserts the separators of the new right siblings of `ptr` into the parents and
+    // propagates the splits of the parents up to the root. The separators have to be sorted.
+    // call it only, if you hold the lock of `ptr`. The lock is released when it returns.
+    fn insert_separators(&self, ptr: Ptr, parents: ~[Ptr], separators: ~[(K, Ptr)]) {
+        or_fail(self.try_insert_separators(ptr, parents, separators))
+    }
+    // On an error the remaining separators are not inserted. The new nodes stay reachable over
+    // the link pointers of their left siblings.
+    fn try_insert_separators(&self, ptr: Ptr, parents: ~[Ptr], separators: ~[(K, Ptr)])
+        -> Result<(), PageError<Ptr>> {
+        let mut parents = parents;
+        let mut separators = separators;
+        let mut current_ptr = ptr;
+        // the level of `current_ptr`, the leafs are on level 0
+        let mut level = 0;
+        while !separators.is_empty() {
+            let old_ptr = current_ptr.clone();
+            let parent = match parents.pop_opt() {
+                Some(p) => {
+                    self.lock_manager.lock(p.clone());
+                    match self.try_read(&p) {
+                        Ok(ref node) if self.is_collapsed_root(node) => {
+                            // the levels above were removed with the collapsed root
+                            self.lock_manager.unlock(&p);
+                            parents = ~[];
+                            None
+                        }
+                        Ok(_) => Some(p),
+                        Err(err) => {
+                            self.lock_manager.unlock(&p);
+                            self.lock_manager.unlock(&old_ptr);
+                            return Err(err);
+                        }
+                    }
+                }
+                None => None
+            };
+            match parent {
+                Some(p) => current_ptr = p,
+                None => {
+                    if self.is_root(&old_ptr) {   // we need to split the root
+                        let (key, ptr) = separators.shift();
+                        self.new_root(key, &old_ptr, &ptr);
+                        current_ptr = self.root();
+                    } else { // root was splitted, the anchor knows the level of the parents
+                        let found = {
+                            let (ref key, _) = separators[0];
+                            self.try_find_node(key, level + 1)
+                        };
+                        let (parent, visited_stack) = match found {
+                            Ok(found) => found,
+                            Err(err) => {
+                                self.lock_manager.unlock(&old_ptr);
+                                return Err(err);
+                            }
+                        };
+                        parents = visited_stack;
+                        current_ptr = parent.my_ptr().clone();
+                    }
+                    self.lock_manager.lock(current_ptr.clone());
+                }
             }
+            level += 1;
+            self.lock_manager.unlock(&old_ptr);
+            let mut parent_separators = ~[];
+            for (key, ptr) in separators.move_iter() {
+                // the parent may have been split or merged since we went down
+                let (parent_ptr, parent_node) = try!(self.try_move_right(current_ptr.clone(),
+                                                                         &key));
+                current_ptr = parent_ptr;
+                parent_separators.push_all_move(self.insert_into_inode(parent_node, key, ptr));
+            }
+            separators = parent_separators;
         }
+        self.lock_manager.unlock(&current_ptr);
+        Ok(())
     }
-    fn new_root(&mut self, current_node: &mut Node<INODE,LEAF>, key : K, smaller: &Ptr, bigger: &Ptr) {
-        debug!("new root key: {}", key.to_str());
-        let new_root_ptr = self.storage.new_page();
-        let root = PhysicalNode::new(T_INODE, new_root_ptr.clone(), None,
-                                     ~[key.clone()], ~[smaller.clone(), bigger.clone()]);
-        self.storage.write(&new_root_ptr, &INode(root));
 
-        // we are still holding a lock over the old root, so we can be sure no one else will change
-        // the root pointer
-        self.root = new_root_ptr;
-        current_node.unset_root();
-        self.statistics.inc_inodes();
+    /// Like `find`, but returns the error of a damaged page instead of failing.
+    pub fn try_find(&self, key: &K) -> Result<Option<V>, PageError<Ptr>> {
+        let _pin = self.pin();
+        for _ in range(0, MAX_OPTIMISTIC_RESTARTS) {
+            match try!(self.find_optimistic(key)) {
+                Some(value) => return Ok(value),
+                None => self.statistics.inc_restarts()
+            }
+        }
+        // the writers keep changing the nodes under us, we wait for them at the leaf
+        self.find_shared(key)
     }
-    fn read<'a>(&'a self, ptr: &Ptr) -> &'a Node<INODE, LEAF> {
-        use std::cast;
-        let node = self.storage.read(ptr).unwrap();
-        if ptr == &self.root {
-            unsafe {
-                cast::transmute_mut(node).set_root();
+    // searches the key in the leaf under a shared lock, so no writer can change it
+    fn find_shared(&self, key: &K) -> Result<Option<V>, PageError<Ptr>> {
+        let (leaf, _) = try!(self.try_find_leaf(key));
+        let mut ptr = leaf.my_ptr().clone();
+        self.lock_manager.lock_shared(ptr.clone());
+        loop {
+            let node = match self.try_read(&ptr) {
+                Ok(node) => node,
+                Err(err) => {
+                    self.lock_manager.unlock(&ptr);
+                    return Err(err);
+                }
+            };
+            let right_ptr = match self.ops.move_right(&node, key) {
+                Some(right_ptr) => right_ptr.clone(),
+                None => {
+                    let value = self.ops.get_value(node.getLeaf(), key).map(|v| v.clone());
+                    self.lock_manager.unlock(&ptr);
+                    return Ok(value);
+                }
+            };
+            // like `move_lock`, we never wait for a lock left of a lock we hold
+            if node.is_dead() {
+                self.lock_manager.unlock(&ptr);
+                self.lock_manager.lock_shared(right_ptr.clone());
+            } else {
+                self.lock_manager.lock_shared(right_ptr.clone());
+                self.lock_manager.unlock(&ptr);
             }
+            ptr = right_ptr;
         }
-        return node;
     }
-}
-
-type UintBTree = BTree<uint,
-                       StupidHashmapStorage<
-                           uint,
-                           Node<
-                               DefaultBLinkNode<uint,uint, uint>,
-                               DefaultBLinkNode<uint, uint,uint>
-                           >
-                       >,
-                       SimpleLockManager<uint>,
-                       AtomicStatistics,
-                       DefaultBLinkOps<uint,uint,uint,
-                           DefaultBLinkNode<uint,uint,uint>,
-                           DefaultBLinkNode<uint, uint, uint>>
->;
-
-
-impl BTree<uint,
-           StupidHashmapStorage<
-               uint,
-               Node<
-                   DefaultBLinkNode<uint,uint, uint>,
-                   DefaultBLinkNode<uint, uint,uint>
-               >
-           >,
-           SimpleLockManager<uint>,
-           AtomicStatistics,
-           DefaultBLinkOps<uint,uint,uint,
-                           DefaultBLinkNode<uint,uint,uint>,
-                           DefaultBLinkNode<uint, uint, uint>>
->
-{
-    fn new_test() -> UintBTree {
-        BTree::new_test_with_size(4)
+    /// Like `insert`, but returns the error of a damaged page instead of failing.
+    /// An error after the leaf was written leaves the key inserted.
+    pub fn try_insert(&self, key: K, value: V) -> Result<Option<V>, PageError<Ptr>> {
+        let _pin = self.pin();
+        let (ptr, parents) = try!(self.try_lock_leaf(&key));
+        self.try_insert_locked(ptr, parents, key, value)
     }
-    fn new_test_with_size(max_size: uint) -> UintBTree {
-        let root_ptr = 0;
-        let mut storage = StupidHashmapStorage::new();
-        let root = PhysicalNode::new(T_LEAF, root_ptr, None, ~[], ~[]);
-        storage.write(&root_ptr, &Leaf(root));
-        let btree = BTree {
-            root: root_ptr,
-            storage: storage,
-            lock_manager: SimpleLockManager::new(),
-            statistics: AtomicStatistics::new(),
-            max_size: max_size,
-            ops: DefaultBLinkOps
-        };
-        btree.statistics.inc_leafs();
-        btree
+    /// Like `remove`, but returns the error of a damaged page instead of failing.
+    /// An error after the leaf was written leaves the key removed.
+    pub fn try_remove(&self, key: &K) -> Result<Option<V>, PageError<Ptr>> {
+        let _pin = self.pin();
+        let (leaf, parents) = try!(self.try_find_leaf(key));
+        self.try_remove_from_leaf(leaf.my_ptr().clone(), key, parents)
     }
-}
-#[cfg(test)]
-mod test {
-    use super::{BTree, UintBTree};
-    use persistent::Map;
-    use std::rand::random;
-    use extra::test::BenchHarness;
 
-    fn insert_range(btree: &UintBTree, from: uint, to: uint) {
-        for i in range(from,to) {
-            let key = i;
-            let value = i;
-            btree.insert(key, value);
+    /// Inserts all pairs of the batch and returns the number of new keys.
+    /// The batch is sorted first, so every leaf is locked only once and its splits are
+    /// propagated up together. If a key occurs twice in the batch, the later value wins.
+    pub fn insert_batch<I: Iterator<(K, V)>>(&self, iter: I) -> uint {
+        let mut keys = ~[];
+        let mut values = ~[];
+        let mut iter = iter;
+        for (key, value) in iter {
+            keys.push(key);
+            values.push(Some(value));
+        }
+        // merge sort is stable, equal keys stay in the order of the batch
+        let order = merge_sort(range(0, keys.len()).collect::<~[uint]>(),
+                               |a, b| keys[*a].cmp(&keys[*b]) != Greater);
+        let mut inserted = 0;
+        let mut i = 0;
+        let _pin = self.pin();
+        while i < order.len() {
+            let (ptr, parents) = self.lock_leaf(&keys[order[i]]);
+            let mut node = self.read(&ptr);
+            let separators = {
+                let leaf = node.getMutLeaf();
+                while i < order.len() && self.ops.can_contain_key(&*leaf, &keys[order[i]]) {
+                    let idx = order[i];
+                    let value = values[idx].take_unwrap();
+                    if self.ops.insert_leaf(leaf, keys[idx].clone(), value).is_none() {
+                        self.statistics.inc_elements();
+                        inserted += 1;
+                    }
+                    self.statistics.inc_insertions();
+                    i += 1;
+                }
+                self.split_overfull_leaf(leaf)
+            };
+            self.storage.write(&ptr, node);
+            self.insert_separators(ptr, parents, separators);
+        }
+        inserted
+    }
+    // splits the overfull leaf in halves until every part fits and writes the new leafs.
+    // Returns the separators of the new leafs from left to right.
+    // call it only, if you hold the lock of the leaf.
+    fn split_overfull_leaf(&self, leaf: &mut LEAF) -> ~[(K, Ptr)] {
+        // a single entry, that is bigger than a page, cannot be split
+        if leaf.keys().len() < 2 || !leaf.needs_split(self.max_leaf_bytes) {
+            return ~[];
+        }
+        let new_page = self.storage.new_page();
+        let mut right = self.ops.split_leaf(leaf, new_page.clone());
+        let separator = self.leaf_separator(leaf.max_key(), right.min_key());
+        let mut separators = self.split_overfull_leaf(leaf);
+        separators.push((separator, new_page.clone()));
+        separators.push_all_move(self.split_overfull_leaf(&mut right));
+        self.storage.write(&new_page, Leaf(right));
+        self.statistics.inc_leafs();
+        separators
+    }
+    // the key, that separates a split leaf from its new right sibling in the parent.
+    // A separator above `left_max` narrows the key range of the left leaf like a removal of
+    // its max key, the keys between are forwarded to the right sibling.
+    fn leaf_separator(&self, left_max: &K, right_min: &K) -> K {
+        match self.ops.separator(left_max, right_min) {
+            Some(separator) => separator,
+            None => left_max.clone()
+        }
+    }
+    // splits the overfull inode in halves until every part fits, like `split_overfull_leaf`.
+    // call it only, if you hold the lock of the inode.
+    fn split_overfull_inode(&self, inode: &mut INODE) -> ~[(K, Ptr)] {
+        if inode.keys().len() < 2 || !inode.needs_split(self.max_inode_bytes) {
+            return ~[];
+        }
+        let new_page = self.storage.new_page();
+        let mut right = self.ops.split_inode(inode, new_page.clone());
+        let separator = inode.max_key().clone();
+        let mut separators = self.split_overfull_inode(inode);
+        separators.push((separator, new_page.clone()));
+        separators.push_all_move(self.split_overfull_inode(&mut right));
+        self.storage.write(&new_page, INode(right));
+        self.statistics.inc_inodes();
+        separators
+    }
+    // the number of keys, that stay in the left part of an overfull merged node.
+    // Of the positions, where both parts fit into `max_bytes`, the one with the most even
+    // sizes is taken. The boundary of the two merged nodes always fits.
+    fn merged_split_position<V1, N: PhysicalNode<K, V1, Ptr>>(&self, node: &N, node_type: uint,
+                                                               max_bytes: uint) -> uint {
+        let empty_size = self.empty_node_size(node_type);
+        let total = node.byte_size();
+        let (keys, values) = (node.keys(), node.values());
+        let mut moved = 0;
+        let mut best = None;
+        let mut best_difference = 0;
+        for i in range(1, keys.len()) {
+            moved += node.entry_size(&keys[i - 1], &values[i - 1]);
+            let (left, right) = (empty_size + moved, total - moved);
+            let difference = if left > right { left - right } else { right - left };
+            if left <= max_bytes && right <= max_bytes &&
+                (best.is_none() || difference < best_difference) {
+                best = Some(i);
+                best_difference = difference;
+            }
+        }
+        match best {
+            Some(position) => position,
+            None => keys.len() / 2
+        }
+    }
+
+    /// Returns the entry of the key. The leaf that owns the key stays locked until the entry
+    /// is dropped, so a read-modify-write through the entry is atomic.
+    pub fn entry<'a>(&'a self, key: K) -> Entry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+        // the entry keeps the pages of `parents`, the storage leaves it when it is dropped
+        self.storage.enter();
+        let (ptr, parents) = self.lock_leaf(&key);
+        let node = self.read(&ptr);
+        match self.ops.position(node.getLeaf(), &key) {
+            Some(idx) => Occupied(OccupiedEntry {
+                btree: self,
+                ptr: Some(ptr),
+                parents: parents,
+                key: key,
+                idx: idx,
+                value: node.getLeaf().values()[idx].clone()
+            }),
+            None => Vacant(VacantEntry {
+                btree: self,
+                ptr: Some(ptr),
+                parents: parents,
+                key: Some(key)
+            })
+        }
+    }
+    // the most left leaf is never merged into a left sibling, so it never dies.
+    fn most_left_leaf(&self) -> Ptr {
+        do self.anchor.read |anchor| {
+            anchor.most_left(0).clone()
+        }
+    }
+    // ensures that we are on the node that can contains the key.
+    // call it only, if you hold the lock of `ptr`. Returns the node we hold the lock of.
+    fn move_right(&self, ptr: Ptr, key: &K) -> (Ptr, Node<INODE, LEAF>) {
+        or_fail(self.try_move_right(ptr, key))
+    }
+    // no lock is held, if it returns an error
+    fn try_move_right(&self, ptr: Ptr, key: &K)
+        -> Result<(Ptr, Node<INODE, LEAF>), PageError<Ptr>> {
+        let mut current_ptr = ptr;
+        loop {
+            let current_node = match self.try_read(&current_ptr) {
+                Ok(node) => node,
+                Err(err) => {
+                    self.lock_manager.unlock(&current_ptr);
+                    return Err(err);
+                }
+            };
+            let right_ptr = match self.ops.move_right(&current_node, key) {
+                Some(ptr) => ptr.clone(),
+                None => return Ok((current_ptr, current_node))
+            };
+            self.move_lock(&current_ptr, current_node.is_dead(), &right_ptr);
+            current_ptr = right_ptr;
+        }
+    }
+    // moves our lock from `from` to `to`, which we reached over the link pointer of `from`.
+    // A dead node links to its left neighbour and we never wait for a lock left of a lock we hold.
+    fn move_lock(&self, from: &Ptr, from_is_dead: bool, to: &Ptr) {
+        if from_is_dead {
+            self.lock_manager.unlock(from);
+            self.lock_manager.lock(to.clone());
+        } else {
+            self.lock_manager.lock(to.clone());
+            self.lock_manager.unlock(from);
+        }
+    }
+
+    // returns the replaced value of an equal key.
+    // if a split was necessary, it returns the separators and the pointers of the new leafs.
+    // call it only, if you hold the lock of the `node`.
+    fn insert_into_leaf(&self, node: Node<INODE, LEAF>, key: K, value: V)
+        -> (Option<V>, ~[(K, Ptr)]) {
+        let mut node = node;
+        let ptr = node.my_ptr().clone();
+        let replaces = !self.ops.allows_duplicates() &&
+            self.ops.get_value(node.getLeaf(), &key).is_some();
+        let result = {
+            let leaf = node.getMutLeaf();
+            if replaces || !leaf.overflows_with(&key, &value, self.max_leaf_bytes) {
+                let old_value = self.ops.insert_leaf(leaf, key, value);
+                // a bigger value may have replaced a smaller one
+                (old_value, self.split_overfull_leaf(leaf))
+            } else {
+                debug!("[insert_into_leaf] spliting: {}", leaf.keys().to_str());
+                let new_child_ptr = self.storage.new_page();
+                let mut new_leaf = self.ops.split_and_insert_leaf(
+                    leaf, new_child_ptr.clone(), key, value);
+                let separator = self.leaf_separator(leaf.max_key(), new_leaf.min_key());
+                // variable sized entries may still not fit into the halves
+                let mut separators = self.split_overfull_leaf(leaf);
+                separators.push((separator, new_child_ptr.clone()));
+                separators.push_all_move(self.split_overfull_leaf(&mut new_leaf));
+                self.storage.write(&new_child_ptr, Leaf(new_leaf));
+                self.statistics.inc_leafs();
+                (None, separators)
+            }
+        };
+        // the new leafs are written first, so readers can follow the link pointers
+        self.storage.write(&ptr, node);
+        result
+    }
+    // returns the separators and the pointers of the new inodes, if a split was necessary.
+    // call it only, if you hold the lock of the `node`.
+    fn insert_into_inode(&self, node: Node<INODE, LEAF>, key: K, ptr: Ptr) -> ~[(K, Ptr)] {
+        let mut node = node;
+        let node_ptr = node.my_ptr().clone();
+        let result = {
+            let inode = node.getMutINode();
+            if !inode.overflows_with(&key, &ptr, self.max_inode_bytes) {
+                self.ops.insert_inode(inode, key, ptr);
+                ~[]
+            } else { // split is needed
+                let new_page_ptr = self.storage.new_page();
+                let mut new_inode = self.ops.split_and_insert_inode(
+                    inode, new_page_ptr.clone(), key, ptr);
+                let inode_max_key = inode.max_key().clone();
+                // variable sized keys may still not fit into the halves
+                let mut separators = self.split_overfull_inode(inode);
+                separators.push((inode_max_key, new_page_ptr.clone()));
+                separators.push_all_move(self.split_overfull_inode(&mut new_inode));
+                self.storage.write(&new_page_ptr, INode(new_inode));
+                self.statistics.inc_inodes();
+                separators
+            }
+        };
+        self.storage.write(&node_ptr, node);
+        result
+    }
+    // removes the key starting at the leaf `ptr` points to. The key may have moved to a right
+    // sibling since `ptr` was read, so we move right until we hold the lock of the leaf that
+    // can contain the key. `parents` is the backtrace stack of the leaf.
+    fn remove_from_leaf(&self, ptr: Ptr, key: &K, parents: ~[Ptr]) -> Option<V> {
+        or_fail(self.try_remove_from_leaf(ptr, key, parents))
+    }
+    fn try_remove_from_leaf(&self, ptr: Ptr, key: &K, parents: ~[Ptr])
+        -> Result<Option<V>, PageError<Ptr>> {
+        self.lock_manager.lock(ptr.clone());
+        let (current_ptr, mut current_node) = try!(self.try_move_right(ptr, key));
+        let removed = self.ops.remove_leaf(current_node.getMutLeaf(), key);
+        try!(self.try_removed_from_leaf(current_ptr, current_node, removed.is_some(), parents));
+        Ok(removed)
+    }
+    // writes the leaf after a removal and rebalances it, if it is underfull.
+    // call it only, if you hold the lock of `ptr`. The lock is released when it returns.
+    fn removed_from_leaf(&self, ptr: Ptr, node: Node<INODE, LEAF>, removed: bool,
+                         parents: ~[Ptr]) {
+        or_fail(self.try_removed_from_leaf(ptr, node, removed, parents))
+    }
+    // On an error of the rebalancing the key is removed, but the leaf stays underfull.
+    fn try_removed_from_leaf(&self, ptr: Ptr, node: Node<INODE, LEAF>, removed: bool,
+                             parents: ~[Ptr]) -> Result<(), PageError<Ptr>> {
+        let underflows = removed && self.underflows(&node);
+        if removed {
+            self.storage.write(&ptr, node);
+            self.statistics.dec_elements();
+            self.statistics.inc_deletions();
+        }
+        if underflows {
+            self.try_rebalance(ptr, parents)
+        } else {
+            self.lock_manager.unlock(&ptr);
+            Ok(())
+        }
+    }
+
+    // the size of a node without entries, that is not the most right node
+    fn empty_node_size(&self, node_type: uint) -> uint {
+        let root = self.root();
+        if node_type == T_LEAF {
+            let leaf: LEAF = PhysicalNode::new(T_LEAF, root.clone(), Some(root), ~[], ~[]);
+            leaf.byte_size()
+        } else {
+            let inode: INODE = PhysicalNode::new(T_INODE, root.clone(), Some(root), ~[], ~[]);
+            inode.byte_size()
+        }
+    }
+    // nodes that use less than half of the space for their entries are merged with their
+    // right sibling
+    fn underflows(&self, node: &Node<INODE, LEAF>) -> bool {
+        let (node_type, max_bytes) = if node.isLeaf() {
+            (T_LEAF, self.max_leaf_bytes)
+        } else {
+            (T_INODE, self.max_inode_bytes)
+        };
+        node.byte_size() < fill_limit(self.empty_node_size(node_type), max_bytes, 0.5)
+    }
+
+    // Rebalances the underfull node `ptr` with its right sibling in two phases:
+    //  1. the right sibling is merged into the node, marked as dead and forwards every
+    //     search to the node.
+    //  2. the right sibling is unlinked from the parent and its page is freed.
+    // If both nodes don't fit into one node, the merged node is split again onto a new page, so
+    // the node borrows keys from its right sibling. It is split by the byte size of the entries,
+    // so both parts fit. If the new separator doesn't fit into the parent, nothing is changed.
+    // The most right nodes are never merged, they don't have a right sibling.
+    //
+    // call it only, if you hold the lock of `ptr`. All locks are released when it returns.
+    // A page error is returned before anything is written, the node stays underfull.
+    fn try_rebalance(&self, ptr: Ptr, mut parents: ~[Ptr]) -> Result<(), PageError<Ptr>> {
+        let mut node = match self.try_read(&ptr) {
+            Ok(node) => node,
+            Err(err) => {
+                self.lock_manager.unlock(&ptr);
+                return Err(err);
+            }
+        };
+        if node.is_root() || node.is_most_right_node() || parents.is_empty() {
+            // an empty backtrace stack means that the root was split since we went down,
+            // the node stays underfull.
+            self.lock_manager.unlock(&ptr);
+            return Ok(());
+        }
+        let right_ptr = node.link_ptr().unwrap().clone();
+        self.lock_manager.lock(right_ptr.clone());
+        let parent_ptr = match self.lock_parent(parents.pop(), &ptr, &right_ptr) {
+            Ok(Some(parent_ptr)) => parent_ptr,
+            Ok(None) => {
+                // the right sibling belongs to another parent
+                self.lock_manager.unlock(&right_ptr);
+                self.lock_manager.unlock(&ptr);
+                return Ok(());
+            }
+            Err(err) => {
+                self.lock_manager.unlock(&right_ptr);
+                self.lock_manager.unlock(&ptr);
+                return Err(err);
+            }
+        };
+        let read = match self.try_read(&right_ptr) {
+            Ok(right) => match self.try_read(&parent_ptr) {
+                Ok(parent) => Ok((right, parent)),
+                Err(err) => Err(err)
+            },
+            Err(err) => Err(err)
+        };
+        let (mut right, mut parent) = match read {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                self.lock_manager.unlock(&parent_ptr);
+                self.lock_manager.unlock(&right_ptr);
+                self.lock_manager.unlock(&ptr);
+                return Err(err);
+            }
+        };
+        let is_leaf = node.isLeaf();
+        // the position and the new separator, if the merged node is split again
+        let split = if is_leaf {
+            let leaf = node.getMutLeaf();
+            self.ops.merge_right_leaf(leaf, right.getMutLeaf());
+            if leaf.needs_split(self.max_leaf_bytes) {
+                let position = self.merged_split_position(&*leaf, T_LEAF, self.max_leaf_bytes);
+                let keys = leaf.keys();
+                Some((position, self.leaf_separator(&keys[position - 1], &keys[position])))
+            } else {
+                None
+            }
+        } else {
+            let inode = node.getMutINode();
+            self.ops.merge_right_inode(inode, right.getMutINode());
+            if inode.needs_split(self.max_inode_bytes) {
+                let position = self.merged_split_position(&*inode, T_INODE,
+                                                          self.max_inode_bytes);
+                Some((position, inode.keys()[position - 1].clone()))
+            } else {
+                None
+            }
+        };
+        let idx = parent.getINode().values().iter().position(|p| p == &ptr).unwrap();
+        let separator_fits = match split {
+            Some((_, ref separator)) => self.separator_fits(parent.getINode(), idx, separator),
+            None => true
+        };
+        if !separator_fits {
+            // a longer separator would overflow the parent, nothing was written yet and
+            // the node stays underfull
+            self.lock_manager.unlock(&parent_ptr);
+            self.lock_manager.unlock(&right_ptr);
+            self.lock_manager.unlock(&ptr);
+            return Ok(());
+        }
+        let new_right = match split {
+            Some((position, separator)) => {
+                let new_page = self.storage.new_page();
+                if is_leaf {
+                    let new_leaf = self.ops.split_leaf_at(node.getMutLeaf(), new_page.clone(),
+                                                          position);
+                    self.storage.write(&new_page, Leaf(new_leaf));
+                } else {
+                    let new_inode = self.ops.split_inode_at(node.getMutINode(), new_page.clone(),
+                                                            position);
+                    self.storage.write(&new_page, INode(new_inode));
+                }
+                Some((new_page, separator))
+            }
+            None => None
+        };
+        // both at once, a crash between would leave the keys of the right sibling in both nodes
+        self.storage.write_all(~[(ptr.clone(), node), (right_ptr.clone(), right)]);
+
+        {
+            let parent_inode = parent.getMutINode();
+            match new_right {
+                Some((new_right_ptr, separator)) => {
+                    debug!("[rebalance] {} borrowed from {}", ptr.to_str(), right_ptr.to_str());
+                    self.ops.replace_right_child(parent_inode, idx, separator, new_right_ptr);
+                }
+                None => {
+                    debug!("[rebalance] merged {} into {}", right_ptr.to_str(), ptr.to_str());
+                    self.ops.unlink_right_child(parent_inode, idx);
+                    if is_leaf {
+                        self.statistics.dec_leafs();
+                    } else {
+                        self.statistics.dec_inodes();
+                    }
+                }
+            }
+        }
+        // a root with a single child is replaced by the child
+        let collapses = parent.getINode().values().len() == 1 && self.is_root(&parent_ptr);
+        if collapses {
+            parent.getMutINode().set_dead();
+        }
+        let parent_underflows = self.underflows(&parent);
+        self.storage.write(&parent_ptr, parent);
+        // the storage keeps the dead page for the readers, that are still on it
+        self.storage.free_page(&right_ptr);
+        self.lock_manager.unlock(&right_ptr);
+
+        if collapses {
+            self.collapse_root(&parent_ptr, &ptr);
+            self.lock_manager.unlock(&parent_ptr);
+            self.lock_manager.unlock(&ptr);
+            return Ok(());
+        }
+        self.lock_manager.unlock(&ptr);
+        if parent_underflows {
+            self.try_rebalance(parent_ptr, parents)
+        } else {
+            self.lock_manager.unlock(&parent_ptr);
+            Ok(())
+        }
+    }
+
+    // true if the parent still fits into a page, after the separator of the child at `idx`
+    // is replaced by `separator`.
+    fn separator_fits(&self, parent: &INODE, idx: uint, separator: &K) -> bool {
+        let old_separator = &parent.keys()[idx];
+        let value = &parent.values()[idx];
+        let size = parent.byte_size() + parent.entry_size(separator, value)
+            - parent.entry_size(old_separator, value);
+        size <= self.max_inode_bytes
+    }
+    // locks the parent of `child`, starting at `ptr` and moving right.
+    // Returns None if `right` is not the next child after `child` in the parent.
+    // The parent is only locked, if it returns Some.
+    fn lock_parent(&self, ptr: Ptr, child: &Ptr, right: &Ptr)
+        -> Result<Option<Ptr>, PageError<Ptr>> {
+        let mut current_ptr = ptr;
+        self.lock_manager.lock(current_ptr.clone());
+        loop {
+            let current_node = match self.try_read(&current_ptr) {
+                Ok(node) => node,
+                Err(err) => {
+                    self.lock_manager.unlock(&current_ptr);
+                    return Err(err);
+                }
+            };
+            let inode = current_node.getINode();
+            match inode.values().iter().position(|p| p == child) {
+                Some(idx) => {
+                    if idx + 1 < inode.values().len() && &inode.values()[idx + 1] == right {
+                        return Ok(Some(current_ptr));
+                    }
+                    self.lock_manager.unlock(&current_ptr);
+                    return Ok(None);
+                }
+                None => match inode.link_ptr() {
+                    Some(next_ptr) => {
+                        self.move_lock(&current_ptr, inode.is_dead(), next_ptr);
+                        current_ptr = next_ptr.clone();
+                    }
+                    None => {
+                        self.lock_manager.unlock(&current_ptr);
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+    // call it only, if you hold the lock of the old root `smaller`
+    fn new_root(&self, key : K, smaller: &Ptr, bigger: &Ptr) {
+        debug!("new root key: {}", key.to_str());
+        let new_root_ptr = self.storage.new_page();
+        let root = PhysicalNode::new(T_INODE, new_root_ptr.clone(), None,
+                                     ~[key.clone()], ~[smaller.clone(), bigger.clone()]);
+        self.storage.write(&new_root_ptr, INode(root));
+
+        // we are still holding a lock over the old root, so we can be sure no one else will change
+        // the root pointer
+        self.storage.set_root(&new_root_ptr);
+        let mut new_root_ptr = Some(new_root_ptr);
+        do self.anchor.write |anchor| {
+            anchor.push_root(smaller, new_root_ptr.take_unwrap());
+        }
+        self.statistics.inc_inodes();
+    }
+    // makes the only child of the root the new root and frees the old root.
+    // The old root was written dead without a link pointer, it forwards the readers, that
+    // are still on it, to its child. A writer, that finds it on its backtrace stack, knows
+    // that the levels above were removed.
+    // call it only, if you hold the locks of the old root and of the child.
+    fn collapse_root(&self, old_root: &Ptr, child: &Ptr) {
+        debug!("[collapse_root] {} replaces {}", child.to_str(), old_root.to_str());
+        self.storage.set_root(child);
+        do self.anchor.write |anchor| {
+            anchor.pop_root(old_root);
+        }
+        self.storage.free_page(old_root);
+        self.statistics.dec_inodes();
+    }
+    fn is_collapsed_root(&self, node: &Node<INODE, LEAF>) -> bool {
+        node.is_dead() && node.is_most_right_node()
+    }
+    // call it at the start of every operation, that reads pages
+    fn pin<'a>(&'a self) -> Pin<'a, Storage> {
+        self.storage.enter();
+        Pin { storage: &self.storage }
+    }
+    fn root(&self) -> Ptr {
+        do self.anchor.read |anchor| {
+            anchor.root().clone()
+        }
+    }
+    fn is_root(&self, ptr: &Ptr) -> bool {
+        do self.anchor.read |anchor| {
+            anchor.is_root(ptr)
+        }
+    }
+    /// a snapshot of the acquisitions and wait times of the page locks
+    pub fn lock_statistics(&self) -> LockStatistics<Ptr> {
+        self.lock_manager.statistics()
+    }
+    /// the number of levels of the tree, a tree with a single leaf has a height of 1
+    pub fn height(&self) -> uint {
+        do self.anchor.read |anchor| {
+            anchor.height()
+        }
+    }
+    // returns a copy of the node and the version of its page, None if the page was freed
+    fn read_versioned(&self, ptr: &Ptr)
+        -> Result<Option<(uint, Node<INODE, LEAF>)>, PageError<Ptr>> {
+        match try!(self.storage.read_versioned(ptr)) {
+            Some((version, node)) => {
+                let mut node = node;
+                if self.is_root(ptr) {
+                    node.set_root();
+                } else {
+                    node.unset_root();
+                }
+                Ok(Some((version, node)))
+            }
+            None => Ok(None)
+        }
+    }
+    // true, if the page was written or freed since we read it with `version`
+    fn changed_since(&self, ptr: &Ptr, version: uint) -> bool {
+        self.storage.version(ptr) != version
+    }
+    // returns a copy of the node. The root flag is not stored, it is set if `ptr` is the root.
+    fn read(&self, ptr: &Ptr) -> Node<INODE, LEAF> {
+        or_fail(self.try_read(ptr))
+    }
+    // a missing page is still a bug of the tree, only a damaged page is returned as error
+    fn try_read(&self, ptr: &Ptr) -> Result<Node<INODE, LEAF>, PageError<Ptr>> {
+        let mut node = match self.storage.read(ptr) {
+            Ok(Some(node)) => node,
+            Ok(None) => fail!("page {} doesn't exist", ptr.to_str()),
+            Err(err) => return Err(err)
+        };
+        if self.is_root(ptr) {
+            node.set_root();
+        } else {
+            node.unset_root();
+        }
+        Ok(node)
+    }
+}
+
+impl<K: TotalOrd + Clone + ToStr,
+     V: Eq + Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+BTree<Ptr, Storage, Locks, Stats, OPS> {
+    /// Sets the value of the key to `new`, if its current value is `expected`.
+    /// `None` stands for a missing key, so `expected = None` only inserts and `new = None`
+    /// removes the key. Fails with the current value, if it is not the expected one.
+    /// The check and the update happen under one lock of the leaf.
+    pub fn compare_and_swap(&self, key: K, expected: Option<&V>, new: Option<V>)
+        -> Result<(), Option<V>> {
+        match self.entry(key) {
+            Occupied(mut entry) => {
+                if expected != Some(entry.get()) {
+                    return Err(Some(entry.get().clone()));
+                }
+                match new {
+                    Some(value) => { entry.set(value); }
+                    None => { entry.remove(); }
+                }
+            }
+            Vacant(entry) => {
+                if expected.is_some() {
+                    return Err(None);
+                }
+                match new {
+                    Some(value) => entry.insert(value),
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts the key, if it is not in the tree. Fails with the current value otherwise.
+    pub fn insert_if_absent(&self, key: K, value: V) -> Result<(), V> {
+        match self.entry(key) {
+            Occupied(entry) => Err(entry.get().clone()),
+            Vacant(entry) => {
+                entry.insert(value);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub enum Entry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    Occupied(OccupiedEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS>),
+    Vacant(VacantEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS>)
+}
+
+/// An entry of a key in the tree. We hold the lock of the leaf, `ptr` is None after we
+/// released it. `value` is a copy of the value, nobody else can change it while we hold the lock.
+pub struct OccupiedEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    btree: &'a BTree<Ptr, Storage, Locks, Stats, OPS>,
+    ptr: Option<Ptr>,
+    parents: ~[Ptr],
+    key: K,
+    idx: uint,
+    value: V
+}
+
+/// A key that is not in the tree. We hold the lock of the leaf the key belongs to.
+pub struct VacantEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    btree: &'a BTree<Ptr, Storage, Locks, Stats, OPS>,
+    ptr: Option<Ptr>,
+    parents: ~[Ptr],
+    key: Option<K>
+}
+
+impl<'a,
+     K: TotalOrd + Clone + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+Entry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    pub fn is_occupied(&self) -> bool {
+        match self {
+            &Occupied(*) => true,
+            &Vacant(*) => false
+        }
+    }
+    /// modifies the value of an occupied entry.
+    pub fn and_modify(self, f: &fn(&mut V)) -> Entry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+        match self {
+            Occupied(mut entry) => {
+                entry.modify(f);
+                Occupied(entry)
+            }
+            vacant => vacant
+        }
+    }
+    /// inserts the default value, if the entry is vacant.
+    pub fn or_insert(self, default: V) {
+        match self {
+            Occupied(_) => {},
+            Vacant(entry) => entry.insert(default)
+        }
+    }
+    pub fn remove(self) -> Option<V> {
+        match self {
+            Occupied(entry) => Some(entry.remove()),
+            Vacant(_) => None
+        }
+    }
+}
+
+impl<'a,
+     K: TotalOrd + Clone + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+OccupiedEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    pub fn key<'b>(&'b self) -> &'b K {
+        &self.key
+    }
+    pub fn get<'b>(&'b self) -> &'b V {
+        &self.value
+    }
+    /// replaces the value and returns the old one
+    pub fn set(&mut self, value: V) -> V {
+        let mut value = Some(value);
+        let mut old_value = None;
+        do self.modify |v| {
+            old_value = Some(util::replace(v, value.take_unwrap()));
+        }
+        old_value.unwrap()
+    }
+    pub fn modify(&mut self, f: &fn(&mut V)) {
+        f(&mut self.value);
+        let ptr = self.ptr.get_ref();
+        let mut node = self.btree.read(ptr);
+        node.getMutLeaf().mut_values()[self.idx] = self.value.clone();
+        self.btree.storage.write(ptr, node);
+    }
+    pub fn remove(self) -> V {
+        let mut entry = self;
+        let ptr = entry.ptr.take_unwrap();
+        let parents = util::replace(&mut entry.parents, ~[]);
+        let mut node = entry.btree.read(&ptr);
+        let value = entry.btree.ops.remove_leaf_at(node.getMutLeaf(), entry.idx);
+        entry.btree.removed_from_leaf(ptr, node, true, parents);
+        value
+    }
+}
+
+impl<'a,
+     K: TotalOrd + Clone + ToStr,
+     V: Clone + ToStr,
+     Ptr: Clone + Eq + ToStr + Freeze + Send,
+     INODE:      PhysicalNode<K, Ptr, Ptr>,
+     LEAF:       PhysicalNode<K, V, Ptr>,
+     OPS : BLinkOps<K,V,Ptr, INODE, LEAF>,
+     Storage:    StorageManager<Ptr, Node<INODE, LEAF>>,
+     Locks:      LockManager<Ptr>,
+     Stats:      StatisticsManager>
+VacantEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    pub fn key<'b>(&'b self) -> &'b K {
+        self.key.get_ref()
+    }
+    pub fn insert(self, value: V) {
+        let mut entry = self;
+        let ptr = entry.ptr.take_unwrap();
+        let parents = util::replace(&mut entry.parents, ~[]);
+        let key = entry.key.take_unwrap();
+        entry.btree.insert_locked(ptr, parents, key, value);
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, K, V, Ptr, Storage: PageReaders, Locks: LockManager<Ptr>, Stats, OPS>
+Drop for OccupiedEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    fn drop(&mut self) {
+        match self.ptr.take() {
+            Some(ptr) => self.btree.lock_manager.unlock(&ptr),
+            None => {}
+        }
+        self.btree.storage.exit();
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, K, V, Ptr, Storage: PageReaders, Locks: LockManager<Ptr>, Stats, OPS>
+Drop for VacantEntry<'a, K, V, Ptr, Storage, Locks, Stats, OPS> {
+    fn drop(&mut self) {
+        match self.ptr.take() {
+            Some(ptr) => self.btree.lock_manager.unlock(&ptr),
+            None => {}
+        }
+        self.btree.storage.exit();
+    }
+}
+
+type UintMultiBTree = BTree<uint,
+                            StupidHashmapStorage<
+                                uint,
+                                Node<
+                                    DefaultBLinkNode<uint,uint, uint>,
+                                    DefaultBLinkNode<uint, uint,uint>
+                                >
+                            >,
+                            SimpleLockManager<uint>,
+                            AtomicStatistics,
+                            DuplicateBLinkOps<uint,uint,uint,
+                                DefaultBLinkNode<uint,uint,uint>,
+                                DefaultBLinkNode<uint, uint, uint>>
+>;
+
+type UintBTree = BTree<uint,
+                       StupidHashmapStorage<
+                           uint,
+                           Node<
+                               DefaultBLinkNode<uint,uint, uint>,
+                               DefaultBLinkNode<uint, uint,uint>
+                           >
+                       >,
+                       SimpleLockManager<uint>,
+                       AtomicStatistics,
+                       DefaultBLinkOps<uint,uint,uint,
+                           DefaultBLinkNode<uint,uint,uint>,
+                           DefaultBLinkNode<uint, uint, uint>>
+>;
+
+
+impl BTree<uint,
+           StupidHashmapStorage<
+               uint,
+               Node<
+                   DefaultBLinkNode<uint,uint, uint>,
+                   DefaultBLinkNode<uint, uint,uint>
+               >
+           >,
+           SimpleLockManager<uint>,
+           AtomicStatistics,
+           DefaultBLinkOps<uint,uint,uint,
+                           DefaultBLinkNode<uint,uint,uint>,
+                           DefaultBLinkNode<uint, uint, uint>>
+>
+{
+    fn new_test() -> UintBTree {
+        BTree::new_test_with_size(4)
+    }
+    // the nodes of the tree hold at most `max_size` keys
+    fn new_test_with_size(max_size: uint) -> UintBTree {
+        BTree::new(StupidHashmapStorage::new(), SimpleLockManager::new(), AtomicStatistics::new(),
+                   DefaultBLinkOps, BTree::page_size_for(max_size)).unwrap()
+    }
+    /// the page size that fits `entries` keys and values, that are all uints
+    pub fn page_size_for(entries: uint) -> uint {
+        let node: DefaultBLinkNode<uint, uint, uint> =
+            PhysicalNode::new(T_LEAF, 0, Some(0), vec::from_elem(entries, 0u),
+                              vec::from_elem(entries, 0u));
+        node.byte_size()
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::{BTree, UintBTree, UintMultiBTree, Occupied, Vacant};
+    use super::{NotEmpty, InvalidFillFactor, UnsortedInput};
+    use super::{TreeError, NodeSizeTooSmall, MAX_OPTIMISTIC_RESTARTS};
+    use persistent::{Map, MultiMap, IteratableMap};
+    use lock::SimpleLockManager;
+    use statistics::{StatisticsManager, AtomicStatistics};
+    use storage::{StorageManager, PageReaders, PageError, StupidHashmapStorage};
+    use epoch::EpochStorage;
+    use node::Node;
+    use blinktree::blink_ops::{BLinkOps, DefaultBLinkOps, DuplicateBLinkOps, PolicyBLinkOps};
+    use blinktree::physical_node::DefaultBLinkNode;
+    use blinktree::split_policy::{Midpoint, ShortSeparator};
+    use encoded_size::EncodedSize;
+    use std::rand::random;
+    use extra::test::BenchHarness;
+    use extra::arc::Arc;
+    use std::comm::{stream, SharedChan};
+    use std::task;
+    use extra::sort::merge_sort;
+
+    fn insert_range(btree: &UintBTree, from: uint, to: uint) {
+        for i in range(from,to) {
+            let key = i;
+            let value = i;
+            btree.insert(key, value);
         }
     }
 
@@ -335,118 +1871,955 @@ mod test {
         assert!(btree.find(&2).is_none());
 
         btree.insert(2, 5);
-        let expected = &5;
+        let expected = 5;
+        assert!(btree.find(&2) == Some(expected));
+
+
+        btree.insert(4, 5);
+        assert!(btree.find(&4).is_some());
+        let expected = 5;
+        assert!(btree.find(&4) == Some(expected));
+
+
+        btree.insert(1204260299403256469, 17554158702358192490);
+        assert!(btree.find(&1204260299403256469).is_some());
+        assert!(btree.statistics.elements() == 4);
+    }
+    #[test]
+    fn test_lock_statistics() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 100);
+        let stats = btree.lock_statistics();
+        // every insertion locks its leaf, a single task never waits
+        assert!(stats.acquisitions >= 100);
+        assert!(stats.contended == 0 && stats.max_wait_ns == 0);
+        assert!(stats.hot_pages.is_empty());
+    }
+    #[test]
+    fn test_insert_replaces() {
+        let btree = BTree::new_test_with_size(4);
+        assert!(btree.insert(3, 30).is_none());
+        assert!(btree.insert(3, 31) == Some(30));
+        assert!(btree.len() == 1);
+        let expected = 31;
+        assert!(btree.find(&3) == Some(expected));
+
+        insert_range(&btree, 0, 100);
+        assert!(btree.len() == 100);
+        for i in range(0u, 100) {
+            assert!(btree.insert(i, i + 1) == Some(i));
+        }
+        assert!(btree.len() == 100);
+        assert!(btree.statistics.insertions() == 202);
+        for i in range(0u, 100) {
+            let expected = i + 1;
+            assert!(btree.find(&i) == Some(expected));
+        }
+        assert!(btree.remove(&5) == Some(6));
+        assert!(btree.remove(&5).is_none());
+        assert!(btree.len() == 99);
+    }
+    #[test]
+    fn test_random_insertion_len() {
+        use std::hashmap::HashMap;
+        let btree = BTree::new_test_with_size(4);
+        let mut expected = HashMap::new();
+        for _ in range(0, 10000) {
+            let r1: uint = random();
+            let r2: uint = random();
+            let key = r1 % 1000;
+            let value = r2 % 1000;
+            assert!(btree.insert(key, value) == expected.swap(key, value));
+        }
+        assert!(btree.len() == expected.len());
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+    }
+    #[test]
+    fn test_new_root() {
+        let max_size = 4;
+        let btree = BTree::new_test_with_size(max_size);
+        let old_root = btree.root();
+        let overflow_root = max_size + 2;
+        insert_range(&btree, 1, overflow_root); // insert 1,2,3,4,5
+        assert!(btree.root() != old_root);
+        assert!(btree.height() == 2);
+
+        let overflow_first_level = 14;
+        let old_root = btree.root();
+        insert_range(&btree, overflow_root, overflow_first_level); // insert 1,2,3,4,5
+        assert!(btree.root() != old_root);
+        assert!(btree.height() == 3);
+        check_anchor(&btree);
+    }
+    #[test]
+    fn test_insert_after_root_splits() {
+        let btree = BTree::new_test_with_size(4);
+        // the root leaf, its backtrace stack is empty
+        let stale_ptr = btree.root();
+        for i in range(1u, 101) {
+            btree.insert(i * 10, i * 10);
+        }
+        assert!(btree.height() >= 3);
+        // the most left leaf [10,20] splits, the separator belongs to level 1
+        for &key in [1u, 2, 3].iter() {
+            btree.lock_manager.lock(stale_ptr.clone());
+            let (ptr, _) = btree.move_right(stale_ptr.clone(), &key);
+            btree.insert_locked(ptr, ~[], key, key);
+        }
+        assert!(btree.len() == 103);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        check_anchor(&btree);
+        for &key in [1u, 2, 3].iter() {
+            assert!(btree.find(&key) == Some(key));
+        }
+        for i in range(1u, 101) {
+            assert!(btree.find(&(i * 10)) == Some(i * 10));
+        }
+    }
+    #[test]
+    fn test_range_insertion() {
+        let btree = BTree::new_test_with_size(4);
+        for i in range(1u,1000) {
+            let key = i;
+            let value = i;
+            btree.insert(key, value);
+            assert!(btree.len() == i);
+            let found = btree.find(&key);
+            assert!(found.is_some(), format!("key: {}, value: {}, i: {}", key, value, i));
+            assert!(found == Some(value));
+        }
+    }
+
+    #[test]
+    fn test_long_insertion() {
+        let btree = BTree::new_test_with_size(4);
+        let keys_values: ~[(uint, uint)] = ~[ (123u,344u), (431,78), (134,789),(2,30),(103,104),(853,10), (343,0), (0,103), (13,54), (309,844), (567,999),(898,78),(211,234)];
+        for &(key,value) in keys_values.iter() {
+            btree.insert(key, value);
+            let found = btree.find(&key);
+            assert!(found.is_some(), format!("key: {}, value: {}", key, value));
+            assert!(found == Some(value));
+        }
+    }
+    #[test]
+    fn test_random_insertion() {
+        let btree = BTree::new_test_with_size(4);
+        for _ in range(0,10000) {
+            let r1: uint= random();
+            let r2: uint= random();
+            let key = r1 % 100000;
+            let value = r2 % 100000;
+            btree.insert(key, value);
+            let found = btree.find(&key);
+            assert!(found.is_some(), format!("key: {}, value: {}", key, value));
+            assert!(found == Some(value));
+        }
+    }
+
+    #[test]
+    fn test_leaf_split() {
+        let btree = BTree::new_test_with_size(4);
+        let size_leaf_needs_split = 7;
+        insert_range(&btree, 1, size_leaf_needs_split); // insert 1,2,3,4,5,6
+        btree.insert(7,7);
+        let root = btree.read(&btree.root());
+        let root = root.getINode();
+        assert!(btree.statistics.leafs() == 3);
+        assert!(btree.statistics.inodes() == 1);
+
+        let exp = ~[2,4];
+        assert!(root.keys == exp, format!("root.keys {} != {}", root.keys.to_str(), exp.to_str()));
+        let leaf = btree.read(&root.values[2]);
+        let leaf = leaf.getLeaf();
+
+        assert!(leaf.keys == ~[5,6,7], format!("leaf.keys {} != [6,7]", leaf.keys.to_str()));
+    }
+    #[test]
+    fn test_find() {
+        let btree = BTree::new_test_with_size(4);
+        let root_ptr = btree.root();
+        let mut root = btree.read(&root_ptr);
+        btree.ops.insert_leaf(root.getMutLeaf(), 1u,2u);
+        btree.ops.insert_leaf(root.getMutLeaf(), 3u,5u);
+        btree.ops.insert_leaf(root.getMutLeaf(), 4u,9u);
+        btree.storage.write(&root_ptr, root);
+        let expected = 2;
+        assert!(btree.find(&1) == Some(expected));
+        let expected = 5;
+        assert!(btree.find(&3) == Some(expected));
+        let expected = 9;
+        assert!(btree.find(&4) == Some(expected));
+    }
+    #[test]
+    fn test_find_after_leaf_split() {
+        let btree = BTree::new_test_with_size(4);
+        let size_leaf_needs_split = 5;
+        insert_range(&btree, 1, size_leaf_needs_split+1); // insert 1,2,3,4,5
+
+        assert!(btree.statistics.leafs() == 2, format!("{} != 2", btree.statistics.leafs()));
+        assert!(btree.statistics.inodes() == 1);
+
+        let expected = 5;
+        assert!(btree.find(&5) == Some(expected));
+    }
+
+    #[test]
+    fn test_remove() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 1, 5); // insert 1,2,3,4
+        btree.remove(&2);
+        assert!(btree.find(&2).is_none());
+        assert!(btree.len() == 3);
+        assert!(btree.statistics.deletions() == 1);
+        for &i in [1u, 3, 4].iter() {
+            assert!(btree.find(&i) == Some(i));
+        }
+
+        // removing a missing key changes nothing
+        btree.remove(&2);
+        btree.remove(&100);
+        assert!(btree.len() == 3);
+        assert!(btree.statistics.deletions() == 1);
+
+        btree.insert(2, 20);
+        let expected = 20;
         assert!(btree.find(&2) == Some(expected));
+    }
+    #[test]
+    fn test_remove_after_splits() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 1000);
+        for i in range(0u, 500) {
+            btree.remove(&(i * 2));
+        }
+        assert!(btree.len() == 500, format!("{} != 500", btree.len()));
+        for i in range(0u, 1000) {
+            if i % 2 == 0 {
+                assert!(btree.find(&i).is_none(), format!("found removed key {}", i));
+            } else {
+                assert!(btree.find(&i) == Some(i), format!("lost key {}", i));
+            }
+        }
+    }
+    #[test]
+    fn test_remove_all_and_reinsert() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 100);
+        for i in range(0u, 100) {
+            btree.remove(&i);
+        }
+        assert!(btree.len() == 0);
+        for i in range(0u, 100) {
+            assert!(btree.find(&i).is_none());
+        }
+        // the emptied leafs forward the keys to their right siblings
+        insert_range(&btree, 0, 100);
+        assert!(btree.len() == 100);
+        for i in range(0u, 100) {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
+    #[test]
+    fn test_remove_moved_to_right_sibling() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 1, 5); // insert 1,2,3,4 into the root leaf
+        let stale_ptr = {
+            let (leaf, _) = btree.find_leaf(&4);
+            leaf.my_ptr().clone()
+        };
+        insert_range(&btree, 5, 8); // the root leaf splits, 4 moves to the new right sibling
+        assert!(!btree.read(&stale_ptr).getLeaf().keys.contains(&4));
+
+        assert!(btree.remove_from_leaf(stale_ptr.clone(), &4, ~[]) == Some(4));
+        assert!(btree.find(&4).is_none());
+        assert!(btree.len() == 6);
+        assert!(btree.remove_from_leaf(stale_ptr.clone(), &4, ~[]).is_none());
+        for &i in [1u, 2, 3, 5, 6, 7].iter() {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
+
+    // walks down to the most left leaf and follows the link pointers.
+    // Returns the number of leafs and checks that the keys are sorted.
+    fn check_leaf_chain(btree: &UintBTree) -> uint {
+        let mut node = btree.read(&btree.root());
+        while node.isINode() {
+            let next_ptr = node.getINode().values[0].clone();
+            node = btree.read(&next_ptr);
+        }
+        let mut leafs = 1;
+        let mut keys = node.getLeaf().keys.clone();
+        while node.link_ptr().is_some() {
+            let next_ptr = node.link_ptr().unwrap().clone();
+            node = btree.read(&next_ptr);
+            assert!(!node.is_dead());
+            keys.push_all(node.getLeaf().keys.slice_from(0));
+            leafs += 1;
+        }
+        for i in range(1, keys.len()) {
+            assert!(keys[i - 1] < keys[i], format!("unsorted keys: {}", keys.to_str()));
+        }
+        assert!(keys.len() == btree.len());
+        leafs
+    }
+
+    // checks that the anchor knows the most left node of every level and that the children
+    // of every inode are on the level below.
+    fn check_anchor(btree: &UintBTree) {
+        let anchor = do btree.anchor.read |anchor| { anchor.clone() };
+        assert!(anchor.root() == &btree.root());
+        for level in range(0, anchor.height()) {
+            let mut ptr = Some(anchor.most_left(level).clone());
+            while ptr.is_some() {
+                let node = btree.read(ptr.get_ref());
+                assert!(node.isLeaf() == (level == 0));
+                if level > 0 {
+                    for child in node.getINode().values.iter() {
+                        let child = btree.read(child);
+                        assert!(child.isLeaf() == (level == 1));
+                    }
+                    let first_child = &node.getINode().values[0];
+                    if ptr.get_ref() == anchor.most_left(level) {
+                        assert!(first_child == anchor.most_left(level - 1));
+                    }
+                }
+                ptr = node.link_ptr().map(|p| p.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_on_underflow() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 1, 5); // insert 1,2,3,4
+        insert_range(&btree, 5, 8); // leafs: [1,2] [3,4] [5,6,7]
+        assert!(btree.statistics.leafs() == 3);
+
+        btree.remove(&1); // [2] merges with [3,4]
+        assert!(btree.statistics.leafs() == 2);
+        assert!(check_leaf_chain(&btree) == 2);
+        let root = btree.read(&btree.root());
+        let root = root.getINode();
+        assert!(root.keys == ~[4], format!("root.keys {} != [4]", root.keys.to_str()));
+        assert!(btree.height() == 2);
+        for &i in [2u, 3, 4, 5, 6, 7].iter() {
+            assert!(btree.find(&i) == Some(i));
+        }
+
+        btree.remove(&2);
+        btree.remove(&3); // [4] merges with the most right leaf [5,6,7], the leaf becomes the root
+        assert!(btree.statistics.leafs() == 1);
+        assert!(check_leaf_chain(&btree) == 1);
+        assert!(btree.height() == 1 && btree.statistics.inodes() == 0);
+        assert!(btree.read(&btree.root()).isLeaf());
+        assert!(btree.storage.nb_pages() == 1);
+        check_anchor(&btree);
+        for &i in [4u, 5, 6, 7].iter() {
+            assert!(btree.find(&i) == Some(i));
+        }
+        btree.insert(1, 1); // the root leaf is split again
+        assert!(btree.find(&1) == Some(1));
+        assert!(btree.height() == 2);
+        check_anchor(&btree);
+    }
+    #[test]
+    fn test_split_with_collapsed_root_on_stack() {
+        let btree = BTree::new_test_with_size(4);
+        // the collapsed root stays readable until we leave the storage
+        let _pin = btree.pin();
+        insert_range(&btree, 1, 8); // leafs: [1,2] [3,4] [5,6,7]
+        let (_, stale_parents) = btree.find_leaf(&7);
+        for i in range(1u, 4) {
+            btree.remove(&i); // the root collapses into the leaf [4,5,6,7]
+        }
+        assert!(btree.height() == 1);
+        // a writer, that went down before the collapse, splits the leaf
+        let leaf_ptr = btree.root();
+        btree.lock_manager.lock(leaf_ptr.clone());
+        btree.insert_locked(leaf_ptr, stale_parents, 8, 8);
+        assert!(btree.height() == 2);
+        check_anchor(&btree);
+        assert!(check_leaf_chain(&btree) == 2);
+        for i in range(4u, 9) {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
+    #[test]
+    fn test_borrow_from_right_sibling() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 1, 11); // leafs: [1,2] [3,4] [5,6] [7,8,9,10]
+        assert!(btree.statistics.leafs() == 4);
 
+        btree.remove(&5); // [6] and [7,8,9,10] don't fit into one leaf
+        assert!(btree.statistics.leafs() == 4);
+        assert!(check_leaf_chain(&btree) == 4);
+        let root = btree.read(&btree.root());
+        let root = root.getINode();
+        assert!(root.keys == ~[2, 4, 7], format!("root.keys {} != [2,4,7]", root.keys.to_str()));
+        let leaf = btree.read(&root.values[3]);
+        let leaf = leaf.getLeaf();
+        assert!(leaf.keys == ~[8, 9, 10], format!("leaf.keys {} != [8,9,10]", leaf.keys.to_str()));
+        assert!(btree.find(&5).is_none());
+        for &i in [1u, 2, 3, 4, 6, 7, 8, 9, 10].iter() {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
+    #[test]
+    fn test_merge_inodes() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 1000);
+        let inodes = btree.statistics.inodes();
+        let leafs = btree.statistics.leafs();
+        for i in range(0u, 1000) {
+            if i % 10 != 0 {
+                btree.remove(&i);
+            }
+        }
+        assert!(btree.len() == 100);
+        assert!(btree.statistics.leafs() < leafs);
+        assert!(btree.statistics.inodes() < inodes);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        check_anchor(&btree);
+        for i in range(0u, 1000) {
+            if i % 10 == 0 {
+                assert!(btree.find(&i) == Some(i), format!("lost key {}", i));
+            } else {
+                assert!(btree.find(&i).is_none());
+            }
+        }
+        for i in range(0u, 1000) {
+            if i % 10 != 0 {
+                btree.insert(i, i);
+            }
+        }
+        assert!(btree.len() == 1000);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+    }
+    #[test]
+    fn test_splits_reuse_merged_pages() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 400);
+        for i in range(0u, 400) {
+            if i % 4 != 0 {
+                btree.remove(&i);
+            }
+        }
+        let nb_free = btree.storage.nb_free();
+        assert!(nb_free > 0);
+        insert_range(&btree, 0, 400);
+        assert!(btree.storage.nb_free() < nb_free);
+        assert!(btree.storage.nb_pages() == btree.statistics.leafs() + btree.statistics.inodes());
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        for i in range(0u, 400) {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
 
-        btree.insert(4, 5);
-        assert!(btree.find(&4).is_some());
-        let expected = &5;
-        assert!(btree.find(&4) == Some(expected));
+    #[test]
+    fn test_iter() {
+        let btree = BTree::new_test_with_size(4);
+        assert!(btree.iter_all().next().is_none());
+        for i in range(0u, 100) {
+            btree.insert(i * 2, i);
+        }
+        let all: ~[(uint, uint)] = btree.iter_all().collect();
+        let expected: ~[(uint, uint)] = range(0u, 100).map(|i| (i * 2, i)).collect();
+        assert!(all == expected);
 
+        let range: ~[(uint, uint)] = btree.iter(&10, &20).collect();
+        assert!(range == ~[(10, 5), (12, 6), (14, 7), (16, 8), (18, 9), (20, 10)],
+                format!("{}", range.to_str()));
+        // the bounds don't have to be keys of the tree
+        let range: ~[(uint, uint)] = btree.iter(&11, &19).collect();
+        assert!(range == ~[(12, 6), (14, 7), (16, 8), (18, 9)]);
+        assert!(btree.iter(&199, &1000).next().is_none());
+        assert!(btree.iter(&20, &10).next().is_none());
+        assert!(btree.iter(&198, &198).collect::<~[(uint, uint)]>() == ~[(198, 99)]);
+    }
+    #[test]
+    fn test_iter_while_splitting() {
+        let btree = BTree::new_test_with_size(4);
+        for i in range(0u, 100) {
+            btree.insert(i * 2, i * 2);
+        }
+        let mut iter = btree.iter_all();
+        let mut seen = ~[];
+        for _ in range(0, 10) {
+            let (key, _) = iter.next().unwrap();
+            seen.push(key);
+        }
+        // every leaf splits while the iterator waits between two keys
+        for i in range(0u, 100) {
+            btree.insert(i * 2 + 1, i * 2 + 1);
+        }
+        for (key, value) in iter {
+            assert!(key == value);
+            seen.push(key);
+        }
+        for i in range(1, seen.len()) {
+            assert!(seen[i - 1] < seen[i], format!("unsorted keys: {}", seen.to_str()));
+        }
+        // all keys behind the last key we have seen before the splits are returned
+        let last = seen[9];
+        for i in range(last + 1, 200) {
+            assert!(seen.contains(&i), format!("missed key {}", i));
+        }
+    }
+    #[test]
+    fn test_iter_while_merging() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 200);
+        let mut iter = btree.iter(&50, &150);
+        assert!(iter.next() == Some((50, 50)));
+        for i in range(0u, 200) {
+            if i % 3 != 0 {
+                btree.remove(&i);
+            }
+        }
+        let rest: ~[(uint, uint)] = iter.collect();
+        let expected: ~[(uint, uint)] = range(51u, 151).filter(|&i| i % 3 == 0).map(|i| (i, i)).collect();
+        assert!(rest == expected, format!("{} != {}", rest.to_str(), expected.to_str()));
+    }
+    #[test]
+    fn test_iter_after_page_reuse() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 200);
+        let mut iter = btree.iter(&50, &150);
+        assert!(iter.next() == Some((50, 50)));
+        // the leaf of the iterator is merged and freed, the new keys reuse the freed pages
+        for i in range(0u, 200) {
+            if i % 10 != 0 {
+                btree.remove(&i);
+            }
+        }
+        insert_range(&btree, 1000, 1200);
+        let rest: ~[(uint, uint)] = iter.collect();
+        let expected: ~[(uint, uint)] = range(51u, 151).filter(|&i| i % 10 == 0).map(|i| (i, i)).collect();
+        assert!(rest == expected, format!("{} != {}", rest.to_str(), expected.to_str()));
+    }
 
-        btree.insert(1204260299403256469, 17554158702358192490);
-        assert!(btree.find(&1204260299403256469).is_some());
-        assert!(btree.statistics.elements() == 4);
+    fn new_multi_test(max_size: uint) -> UintMultiBTree {
+        BTree::new(StupidHashmapStorage::new(), SimpleLockManager::new(), AtomicStatistics::new(),
+                   DuplicateBLinkOps, BTree::page_size_for(max_size)).unwrap()
     }
+
     #[test]
-    fn test_new_root() {
-        let max_size = 4;
-        let btree = BTree::new_test_with_size(max_size);
-        let old_root = btree.root;
-        let overflow_root = max_size + 2;
-        insert_range(&btree, 1, overflow_root); // insert 1,2,3,4,5
-        assert!(btree.root != old_root);
+    fn test_multimap() {
+        let btree = new_multi_test(4);
+        assert!(btree.insert(1, 10).is_none());
+        assert!(btree.insert(2, 20).is_none());
+        assert!(btree.insert(1, 11).is_none());
+        assert!(btree.len() == 3);
+        assert!(btree.get_all(&1) == ~[10, 11]);
+        assert!(btree.get_all(&2) == ~[20]);
+        assert!(btree.get_all(&3).is_empty());
+        assert!(btree.count(&1) == 2);
 
-        let overflow_first_level = 14;
-        let old_root = btree.root;
-        insert_range(&btree, overflow_root, overflow_first_level); // insert 1,2,3,4,5
-        assert!(btree.root != old_root);
+        assert!(!btree.remove_one(&1, &12));
+        assert!(btree.remove_one(&1, &10));
+        assert!(btree.get_all(&1) == ~[11]);
+        assert!(btree.len() == 2);
     }
     #[test]
-    fn test_range_insertion() {
-        let btree = BTree::new_test_with_size(4);
-        for i in range(1u,1000) {
-            let key = i;
-            let value = i;
-            btree.insert(key, value);
-            assert!(btree.len() == i);
-            let found = btree.find(&key);
-            assert!(found.is_some(), format!("key: {}, value: {}, i: {}", key, value, i));
-            assert!(found == Some(&value));
+    fn test_multimap_runs_span_leafs() {
+        let btree = new_multi_test(4);
+        for i in range(0u, 50) {
+            btree.insert(i, i);
+            btree.insert(25, 1000 + i);
+        }
+        assert!(btree.len() == 100);
+        assert!(btree.count(&25) == 51);
+        // the values of the run are in insertion order
+        let values: ~[uint] = btree.get_all(&25);
+        let mut expected: ~[uint] = range(0u, 25).map(|i| 1000 + i).collect();
+        expected.push(25);
+        expected.push_all_move(range(25u, 50).map(|i| 1000 + i).collect::<~[uint]>());
+        assert!(values == expected, format!("{} != {}", values.to_str(), expected.to_str()));
+        for i in range(0u, 50) {
+            if i != 25 {
+                assert!(btree.get_all(&i) == ~[i]);
+            }
+        }
+
+        // removes the values from the end of the run
+        for i in range(0u, 50).invert() {
+            assert!(btree.remove_one(&25, &(1000 + i)), format!("lost value {}", 1000 + i));
+            assert!(btree.count(&25) == 1 + i);
+        }
+        assert!(btree.get_all(&25) == ~[25]);
+        assert!(btree.len() == 50);
+        for i in range(0u, 50) {
+            assert!(btree.get_all(&i) == ~[i]);
         }
     }
 
     #[test]
-    fn test_long_insertion() {
+    fn test_entry() {
         let btree = BTree::new_test_with_size(4);
-        let keys_values: ~[(uint, uint)] = ~[ (123u,344u), (431,78), (134,789),(2,30),(103,104),(853,10), (343,0), (0,103), (13,54), (309,844), (567,999),(898,78),(211,234)];
-        for &(key,value) in keys_values.iter() {
-            btree.insert(key, value);
-            let found = btree.find(&key);
-            assert!(found.is_some(), format!("key: {}, value: {}", key, value));
-            assert!(found == Some(&value));
+        {
+            let entry = btree.entry(1);
+            assert!(!entry.is_occupied());
+            match entry {
+                Vacant(entry) => {
+                    assert!(*entry.key() == 1);
+                    entry.insert(10);
+                }
+                Occupied(_) => fail!("1 is not in the tree")
+            }
+        }
+        let expected = 10;
+        assert!(btree.find(&1) == Some(expected));
+        match btree.entry(1) {
+            Occupied(mut entry) => {
+                assert!(*entry.get() == 10);
+                assert!(entry.set(11) == 10);
+                assert!(*entry.get() == 11);
+            }
+            Vacant(_) => fail!("1 is in the tree")
         }
+        let expected = 11;
+        assert!(btree.find(&1) == Some(expected));
+        assert!(btree.entry(1).remove() == Some(11));
+        assert!(btree.entry(1).remove().is_none());
+        assert!(btree.find(&1).is_none());
+        assert!(btree.len() == 0);
     }
     #[test]
-    fn test_random_insertion() {
+    fn test_entry_counter() {
         let btree = BTree::new_test_with_size(4);
-        for _ in range(0,10000) {
-            let r1: uint= random();
-            let r2: uint= random();
-            let key = r1 % 100000;
-            let value = r2 % 100000;
-            btree.insert(key, value);
-            let found = btree.find(&key);
-            assert!(found.is_some(), format!("key: {}, value: {}", key, value));
-            assert!(found == Some(&value));
+        for i in range(0u, 1000) {
+            btree.entry(i % 100).and_modify(|v| *v += 1).or_insert(1);
+        }
+        assert!(btree.len() == 100);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        for i in range(0u, 100) {
+            let expected = 10;
+            assert!(btree.find(&i) == Some(expected));
         }
+        for i in range(0u, 100) {
+            btree.entry(i).remove();
+        }
+        assert!(btree.len() == 0);
     }
 
     #[test]
-    fn test_leaf_split() {
+    fn test_compare_and_swap() {
         let btree = BTree::new_test_with_size(4);
-        let size_leaf_needs_split = 7;
-        insert_range(&btree, 1, size_leaf_needs_split); // insert 1,2,3,4,5,6
-        btree.insert(7,7);
-        let root = btree.storage.read(&btree.root).unwrap().getINode();
-        assert!(btree.statistics.leafs() == 3);
-        assert!(btree.statistics.inodes() == 1);
+        // expected a missing key
+        assert!(btree.compare_and_swap(1, Some(&10), Some(11)) == Err(None));
+        assert!(btree.compare_and_swap(1, None, Some(10)) == Ok(()));
+        assert!(btree.compare_and_swap(1, None, Some(10)) == Err(Some(10)));
 
-        let exp = ~[2,4];
-        assert!(root.keys == exp, format!("root.keys {} != {}", root.keys.to_str(), exp.to_str()));
-        let leaf = btree.storage.read(&root.values[2]).unwrap().getLeaf();
+        assert!(btree.compare_and_swap(1, Some(&9), Some(11)) == Err(Some(10)));
+        assert!(btree.compare_and_swap(1, Some(&10), Some(11)) == Ok(()));
+        let expected = 11;
+        assert!(btree.find(&1) == Some(expected));
 
-        assert!(leaf.keys == ~[5,6,7], format!("leaf.keys {} != [6,7]", leaf.keys.to_str()));
+        // removes the key
+        assert!(btree.compare_and_swap(1, Some(&11), None) == Ok(()));
+        assert!(btree.find(&1).is_none());
+        assert!(btree.compare_and_swap(1, None, None) == Ok(()));
+        assert!(btree.len() == 0);
     }
     #[test]
-    fn test_find() {
-        use std::cast;
+    fn test_compare_and_swap_counter() {
         let btree = BTree::new_test_with_size(4);
-        unsafe {
-            let root = cast::transmute_mut(btree.storage.read(&btree.root).unwrap().getLeaf());
-            btree.ops.insert_leaf(root, 1u,2u);
-            btree.ops.insert_leaf(root, 3u,5u);
-            btree.ops.insert_leaf(root, 4u,9u);
-            let expected = &2;
-            assert!(btree.find(&1) == Some(expected));
-            let expected = &5;
-            assert!(btree.find(&3) == Some(expected));
-            let expected = &9;
-            assert!(btree.find(&4) == Some(expected));
+        insert_range(&btree, 0, 100);
+        for i in range(0u, 100) {
+            loop {
+                let current = btree.find(&i).unwrap();
+                if btree.compare_and_swap(i, Some(&current), Some(current * 2)).is_ok() {
+                    break;
+                }
+            }
+        }
+        for i in range(0u, 100) {
+            let expected = i * 2;
+            assert!(btree.find(&i) == Some(expected));
         }
     }
     #[test]
-    fn test_find_after_leaf_split() {
+    fn test_insert_if_absent() {
         let btree = BTree::new_test_with_size(4);
-        let size_leaf_needs_split = 5;
-        insert_range(&btree, 1, size_leaf_needs_split+1); // insert 1,2,3,4,5
+        for i in range(0u, 100) {
+            assert!(btree.insert_if_absent(i, i) == Ok(()));
+        }
+        for i in range(0u, 100) {
+            assert!(btree.insert_if_absent(i, i + 1) == Err(i));
+        }
+        assert!(btree.len() == 100);
+        assert!(btree.statistics.insertions() == 100);
+    }
 
-        assert!(btree.statistics.leafs() == 2, format!("{} != 2", btree.statistics.leafs()));
-        assert!(btree.statistics.inodes() == 1);
+    #[test]
+    fn test_nodes_fit_into_pages() {
+        // some keys are so long, that an inode only holds one of them
+        fn key_of(i: uint) -> ~str {
+            if i % 7 == 0 {
+                format!("{:04u}{}", i, "y".repeat(150))
+            } else if i % 3 == 0 {
+                format!("{:04u}{}", i, "x".repeat(60))
+            } else {
+                format!("{:04u}", i)
+            }
+        }
+        let page_size = 256;
+        let btree = BTree::new(StupidHashmapStorage::new(), SimpleLockManager::new(),
+                               AtomicStatistics::new(), DefaultBLinkOps, page_size).unwrap();
+        for i in range(0u, 500) {
+            btree.insert(key_of(i), i);
+        }
+        for &(nb_keys, remove) in [(500u, false), (125u, true)].iter() {
+            if remove {
+                // the removals merge the nodes and split the merged nodes again
+                for i in range(0u, 500).filter(|&i| i % 4 != 0) {
+                    assert!(btree.remove(&key_of(i)) == Some(i));
+                }
+            }
+            assert!(btree.len() == nb_keys);
+            // every node on every level fits into a page
+            let mut level_ptr = btree.root();
+            let mut nb_leaf_keys = 0;
+            loop {
+                let mut node = btree.read(&level_ptr);
+                let is_leaf = node.isLeaf();
+                if !is_leaf {
+                    level_ptr = node.getINode().values()[0].clone();
+                }
+                loop {
+                    assert!(node.byte_size() <= page_size);
+                    if is_leaf {
+                        nb_leaf_keys += node.keys().len();
+                    }
+                    let next_ptr = match node.link_ptr() {
+                        Some(next_ptr) => next_ptr.clone(),
+                        None => break
+                    };
+                    node = btree.read(&next_ptr);
+                }
+                if is_leaf {
+                    break;
+                }
+            }
+            assert!(nb_leaf_keys == nb_keys);
+        }
+        for i in range(0u, 500) {
+            let expected = if i % 4 == 0 { Some(i) } else { None };
+            assert!(btree.find(&key_of(i)) == expected);
+        }
+    }
+    // inserts string keys with a long common suffix and returns the encoded size of all keys
+    // in the inodes
+    fn inode_key_bytes<OPS: BLinkOps<~str, uint, uint,
+                                     DefaultBLinkNode<~str, uint, uint>,
+                                     DefaultBLinkNode<~str, uint, uint>>>(ops: OPS) -> uint {
+        let storage: StupidHashmapStorage<uint, Node<DefaultBLinkNode<~str, uint, uint>,
+                                                     DefaultBLinkNode<~str, uint, uint>>>
+            = StupidHashmapStorage::new();
+        let btree = BTree::new(storage, SimpleLockManager::new(), AtomicStatistics::new(), ops,
+                               512).unwrap();
+        let suffix = "x".repeat(40);
+        for i in range(0u, 2000) {
+            btree.insert(format!("{:06u}{}", i * 7919 % 2000, suffix), i);
+        }
+        for i in range(0u, 2000) {
+            assert!(btree.find(&format!("{:06u}{}", i * 7919 % 2000, suffix)) == Some(i));
+        }
+        let mut bytes = 0;
+        let mut level_ptr = btree.root();
+        loop {
+            let mut node = btree.read(&level_ptr);
+            if node.isLeaf() {
+                return bytes;
+            }
+            level_ptr = node.getINode().values()[0].clone();
+            loop {
+                for key in node.keys().iter() {
+                    bytes += key.encoded_size();
+                }
+                let next_ptr = match node.link_ptr() {
+                    Some(next_ptr) => next_ptr.clone(),
+                    None => break
+                };
+                node = btree.read(&next_ptr);
+            }
+        }
+    }
+    #[test]
+    fn test_short_separators_in_inodes() {
+        let midpoint_bytes = inode_key_bytes(PolicyBLinkOps { policy: Midpoint });
+        let short_bytes = inode_key_bytes(PolicyBLinkOps { policy: ShortSeparator });
+        // a separator keeps about 6 of the 46 bytes of a key. ShortSeparator fills the leafs
+        // less, so there are a few more separators.
+        assert!(short_bytes * 2 < midpoint_bytes,
+                format!("short separators: {} bytes, midpoint: {} bytes", short_bytes,
+                        midpoint_bytes));
+    }
+    #[test]
+    fn test_rejects_too_small_node_sizes() {
+        let empty_size = BTree::page_size_for(0);
+        let result: Result<UintBTree, TreeError> =
+            BTree::new(StupidHashmapStorage::new(), SimpleLockManager::new(),
+                       AtomicStatistics::new(), DefaultBLinkOps, empty_size);
+        match result {
+            Err(NodeSizeTooSmall(min_bytes)) => assert!(min_bytes > empty_size),
+            _ => fail!("an empty leaf leaves no room for an entry")
+        }
+        let result: Result<UintBTree, TreeError> =
+            BTree::new_with_node_sizes(StupidHashmapStorage::new(), SimpleLockManager::new(),
+                                       AtomicStatistics::new(), DefaultBLinkOps,
+                                       BTree::page_size_for(4), 0);
+        assert!(result.is_err());
+        let result: Result<UintBTree, TreeError> =
+            BTree::new(StupidHashmapStorage::new(), SimpleLockManager::new(),
+                       AtomicStatistics::new(), DefaultBLinkOps, BTree::page_size_for(1));
+        assert!(result.is_ok());
+    }
+    #[test]
+    fn test_page_size_for() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 4);
+        let root = btree.read(&btree.root());
+        assert!(root.isLeaf() && root.keys().len() == 4);
+        btree.insert(4, 4);
+        assert!(btree.read(&btree.root()).isINode());
+    }
+    #[test]
+    fn test_bulk_load() {
+        let mut btree = BTree::new_test_with_size(4);
+        let pairs = range(0u, 1000).map(|i| (i * 2, i));
+        assert!(btree.bulk_load(pairs, 1.0) == Ok(()));
+        assert!(btree.len() == 1000);
+        assert!(btree.statistics.leafs() == 250);
+        assert!(check_leaf_chain(&btree) == 250);
+        check_anchor(&btree);
+        for i in range(0u, 1000) {
+            assert!(btree.find(&(i * 2)) == Some(i));
+            assert!(btree.find(&(i * 2 + 1)).is_none());
+        }
+        // the full nodes split on the next insertions
+        for i in range(0u, 1000) {
+            btree.insert(i * 2 + 1, i);
+        }
+        assert!(btree.len() == 2000);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        for i in range(0u, 2000) {
+            let expected = i / 2;
+            assert!(btree.find(&i) == Some(expected));
+        }
+    }
+    #[test]
+    fn test_bulk_load_fill_factor() {
+        let mut btree = BTree::new_test_with_size(4);
+        assert!(btree.bulk_load(range(0u, 1000).map(|i| (i, i)), 0.5) == Ok(()));
+        assert!(btree.statistics.leafs() == 500);
+        assert!(check_leaf_chain(&btree) == 500);
+        for i in range(0u, 1000) {
+            btree.remove(&i);
+        }
+        assert!(btree.len() == 0);
 
-        let expected = 5;
-        assert!(btree.find(&5) == Some(&expected));
+        let mut btree = BTree::new_test_with_size(4);
+        assert!(btree.bulk_load(range(0u, 3).map(|i| (i, i)), 1.0) == Ok(()));
+        assert!(btree.read(&btree.root()).isLeaf());
+        assert!(btree.find(&2) == Some(2));
+    }
+    #[test]
+    fn test_bulk_load_rejects_unsorted_input() {
+        let mut btree = BTree::new_test_with_size(4);
+        let pairs = ~[(1u, 1u), (2, 2), (3, 3), (4, 4), (5, 5), (4, 4), (6, 6)];
+        assert!(btree.bulk_load(pairs.move_iter(), 1.0) == Err(UnsortedInput(5)));
+        let pairs = ~[(1u, 1u), (1, 1)];
+        assert!(btree.bulk_load(pairs.move_iter(), 1.0) == Err(UnsortedInput(1)));
+        assert!(btree.bulk_load(range(0u, 10).map(|i| (i, i)), 0.0) == Err(InvalidFillFactor));
+        // the tree is still empty and usable
+        assert!(btree.len() == 0);
+        assert!(btree.statistics.leafs() == 1 && btree.statistics.inodes() == 0);
+        assert!(btree.storage.nb_pages() == 1);
+        assert!(btree.find(&1).is_none());
+        insert_range(&btree, 0, 10);
+        assert!(btree.bulk_load(range(10u, 20).map(|i| (i, i)), 1.0) == Err(NotEmpty));
+        // the emptied tree collapses into its root leaf and can be loaded
+        for i in range(0u, 10) {
+            btree.remove(&i);
+        }
+        assert!(btree.len() == 0 && btree.height() == 1);
+        assert!(btree.bulk_load(range(10u, 20).map(|i| (i, i)), 1.0) == Ok(()));
+        assert!(btree.storage.nb_pages() == btree.statistics.leafs() + btree.statistics.inodes());
+        assert!(btree.find(&15) == Some(15));
+    }
+    #[test]
+    fn test_bulk_load_duplicates() {
+        let mut btree = new_multi_test(4);
+        let pairs = range(0u, 100).map(|i| (i / 10, i));
+        assert!(btree.bulk_load(pairs, 1.0) == Ok(()));
+        assert!(btree.len() == 100);
+        for i in range(0u, 10) {
+            let values: ~[uint] = btree.get_all(&i);
+            let expected: ~[uint] = range(i * 10, i * 10 + 10).collect();
+            assert!(values == expected, format!("{} != {}", values.to_str(), expected.to_str()));
+        }
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let btree = BTree::new_test();
+        let batch: ~[(uint, uint)] = range(0u, 1000).map(|_| {
+            let key = random::<uint>() % 500;
+            (key, key)
+        }).collect();
+        let mut expected = batch.iter().map(|&(k, _)| k).collect::<~[uint]>();
+        expected = merge_sort(expected, |a, b| a <= b);
+        expected.dedup();
+        assert!(btree.insert_batch(batch.move_iter()) == expected.len());
+        assert!(btree.len() == expected.len());
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        for key in expected.iter() {
+            assert!(btree.find(key) == Some(*key));
+        }
+        // a second batch goes into the existing leafs
+        assert!(btree.insert_batch(range(0u, 1000).map(|i| (i, i))) == 1000 - expected.len());
+        assert!(btree.len() == 1000);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        for i in range(0u, 1000) {
+            assert!(btree.find(&i) == Some(i));
+        }
+    }
+    #[test]
+    fn test_insert_batch_later_value_wins() {
+        let btree = BTree::new_test();
+        insert_range(&btree, 0, 10);
+        let batch = ~[(5u, 50u), (20, 1), (5, 51), (20, 2), (3, 30)];
+        assert!(btree.insert_batch(batch.move_iter()) == 1);
+        assert!(btree.find(&5) == Some(51));
+        assert!(btree.find(&20) == Some(2));
+        assert!(btree.find(&3) == Some(30));
+        assert!(btree.len() == 11);
+    }
+    #[test]
+    fn test_insert_batch_splits_one_leaf_many_times() {
+        let btree = BTree::new_test();
+        // all keys go into the root leaf, that has to be split into many leafs at once
+        assert!(btree.insert_batch(range(0u, 100).map(|i| (i, i))) == 100);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+        assert!(btree.statistics.leafs() >= 25);
+        for i in range(0u, 100) {
+            assert!(btree.find(&i) == Some(i));
+        }
+        btree.insert(100, 100);
+        btree.remove(&0);
+        assert!(btree.len() == 100);
+        assert!(check_leaf_chain(&btree) == btree.statistics.leafs());
+    }
+    #[test]
+    fn test_insert_batch_multimap() {
+        let btree = new_multi_test(4);
+        assert!(btree.insert_batch(range(0u, 40).map(|i| (i % 4, i))) == 40);
+        for k in range(0u, 4) {
+            let values: ~[uint] = btree.get_all(&k);
+            let expected: ~[uint] = range(0u, 10).map(|i| i * 4 + k).collect();
+            assert!(values == expected, format!("{} != {}", values.to_str(), expected.to_str()));
+        }
     }
 
     #[bench]  #[ignore] // meaningless until we have hard disk storage
@@ -458,23 +2831,281 @@ mod test {
             i += 1;
         }
     }
-    /*
-    #[test] #[ignore]
+    #[test]
     fn test_concurrent() {
-        let btree = ~BTree::new_test();
+        let arc_btree = Arc::new(BTree::new_test());
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for t in range(0u, 10) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                for i in range(0u, 1000) {
+                    local_btree.get().insert(i * 10 + t, t);
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, 10) {
+            port.recv();
+        }
+        let btree = arc_btree.get();
+        assert!(btree.len() == 10000);
+        assert!(check_leaf_chain(btree) == btree.statistics.leafs());
+        for i in range(0u, 10000) {
+            let t = i % 10;
+            assert!(btree.find(&i) == Some(t));
+        }
+    }
+    #[test]
+    fn test_changed_since() {
+        let btree = BTree::new_test_with_size(4);
+        insert_range(&btree, 0, 3);
+        let (ptr, version, _) = btree.find_leaf_optimistic(&1).unwrap();
+        assert!(!btree.changed_since(&ptr, version));
+        btree.insert(1, 10);
+        assert!(btree.changed_since(&ptr, version));
+        assert!(btree.find(&1) == Some(10));
+    }
+    #[test]
+    fn test_concurrent_readers_and_writers() {
+        let btree = BTree::new_test_with_size(4);
+        for i in range(0u, 500) {
+            btree.insert(i * 2, i);
+        }
         let arc_btree = Arc::new(btree);
-        for i in range(0,10) {
-            let local_btree = arc_btree.clone();
-            let mut task = task::task();
-            task.watched();
-            do task.spawn {
-                for i in range(0,1000) {
-                    local_btree.get().insert(random(), random());
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for t in range(0u, 4) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                // the writers split the leafs of the readers
+                for i in range(0u, 125) {
+                    local_btree.get().insert((i * 4 + t) * 2 + 1, t);
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, 4) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                let btree = local_btree.get();
+                for _ in range(0u, 10) {
+                    for i in range(0u, 500) {
+                        assert!(btree.find(&(i * 2)) == Some(i));
+                    }
+                    let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+                    assert!(keys.len() >= 500);
+                    for i in range(1, keys.len()) {
+                        assert!(keys[i - 1] < keys[i]);
+                    }
                 }
+                chan.send(());
             }
         }
+        for _ in range(0u, 8) {
+            port.recv();
+        }
+        let btree = arc_btree.get();
+        assert!(btree.len() == 1000);
+        assert!(check_leaf_chain(btree) == btree.statistics.leafs());
+    }
+
+    #[test]
+    fn test_concurrent_inserts_and_removes() {
+        // the default storage keeps the merged pages, while the other tasks may still see them
+        let btree = BTree::new_test_with_size(4);
+        for i in range(0u, 1000) {
+            btree.insert(i * 2, i);
+        }
+        let arc_btree = Arc::new(btree);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for t in range(0u, 4) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                let btree = local_btree.get();
+                // the even keys of one task are removed, while the others insert odd keys.
+                // The merges free pages, that the splits take again.
+                for i in range(0u, 250) {
+                    let key = i * 4 + t;
+                    assert!(btree.remove(&(key * 2)) == Some(key));
+                    btree.insert(key * 2 + 1, key);
+                    assert!(btree.find(&(key * 2 + 1)) == Some(key));
+                }
+                for i in range(0u, 250) {
+                    let key = i * 4 + t;
+                    if i % 2 == 0 {
+                        assert!(btree.remove(&(key * 2 + 1)) == Some(key));
+                    }
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, 4) {
+            port.recv();
+        }
+        let btree = arc_btree.get();
+        assert!(btree.len() == 500);
+        assert!(check_leaf_chain(btree) == btree.statistics.leafs());
+        for key in range(0u, 1000) {
+            assert!(btree.find(&(key * 2)).is_none());
+            let expected = if (key / 4) % 2 == 0 { None } else { Some(key) };
+            assert!(btree.find(&(key * 2 + 1)) == expected);
+        }
+        assert!(btree.storage.nb_retired() == 0);
+        assert!(btree.storage.nb_pages() == btree.statistics.leafs() + btree.statistics.inodes());
+    }
+
+    type UintNode = Node<DefaultBLinkNode<uint, uint, uint>, DefaultBLinkNode<uint, uint, uint>>;
+
+    // a storage, whose pages always change between the read and the validation
+    struct ChangingStorage {
+        storage: StupidHashmapStorage<uint, UintNode>
+    }
+
+    impl PageReaders for ChangingStorage {}
+
+    impl StorageManager<uint, UintNode> for ChangingStorage {
+        fn new_page(&self) -> uint {
+            self.storage.new_page()
+        }
+        fn read(&self, id: &uint) -> Result<Option<UintNode>, PageError<uint>> {
+            self.storage.read(id)
+        }
+        fn read_versioned(&self, id: &uint) -> Result<Option<(uint, UintNode)>, PageError<uint>> {
+            self.storage.read_versioned(id)
+        }
+        fn write(&self, id: &uint, node: UintNode) {
+            self.storage.write(id, node)
+        }
+        fn free_page(&self, id: &uint) {
+            self.storage.free_page(id)
+        }
+        fn version(&self, id: &uint) -> uint {
+            self.storage.version(id) + 1
+        }
+    }
+
+    #[test]
+    fn test_find_falls_back_to_shared_lock() {
+        let btree = BTree::new(ChangingStorage { storage: StupidHashmapStorage::new() },
+                               SimpleLockManager::new(), AtomicStatistics::new(),
+                               DefaultBLinkOps, BTree::page_size_for(4)).unwrap();
+        for i in range(0u, 100) {
+            btree.insert(i, i);
+        }
+        let acquisitions = btree.lock_statistics().acquisitions;
+        assert!(btree.find(&42) == Some(42));
+        assert!(btree.statistics.restarts() == MAX_OPTIMISTIC_RESTARTS);
+        // the leaf and maybe its right siblings were locked
+        assert!(btree.lock_statistics().acquisitions > acquisitions);
+        assert!(btree.find(&100).is_none());
+        assert!(btree.statistics.restarts() == 2 * MAX_OPTIMISTIC_RESTARTS);
     }
-    */
-}
 
+    type EpochBTree = BTree<uint,
+                            EpochStorage<uint, StupidHashmapStorage<
+                                uint,
+                                Node<
+                                    DefaultBLinkNode<uint, uint, uint>,
+                                    DefaultBLinkNode<uint, uint, uint>
+                                >
+                            >>,
+                            SimpleLockManager<uint>,
+                            AtomicStatistics,
+                            DefaultBLinkOps<uint, uint, uint,
+                                DefaultBLinkNode<uint, uint, uint>,
+                                DefaultBLinkNode<uint, uint, uint>>
+    >;
+
+    fn new_epoch_test(max_size: uint) -> EpochBTree {
+        BTree::new(EpochStorage::new(StupidHashmapStorage::new()), SimpleLockManager::new(),
+                   AtomicStatistics::new(), DefaultBLinkOps,
+                   BTree::page_size_for(max_size)).unwrap()
+    }
 
+    // the writers remove the odd keys and merge the leafs under the readers.
+    // The even keys stay, every scan must see all of them.
+    fn delete_while_scanning(nb_keys: uint, nb_writers: uint, nb_readers: uint) {
+        let btree = new_epoch_test(4);
+        for i in range(0u, nb_keys) {
+            btree.insert(i, i);
+        }
+        let arc_btree = Arc::new(btree);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for t in range(0u, nb_writers) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                let btree = local_btree.get();
+                let mut i = t * 2 + 1;
+                while i < nb_keys {
+                    assert!(btree.remove(&i) == Some(i));
+                    i += nb_writers * 2;
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, nb_readers) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                let btree = local_btree.get();
+                for _ in range(0u, 10) {
+                    let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+                    let even: ~[uint] = keys.iter().filter(|&k| k % 2 == 0).map(|k| *k).collect();
+                    assert!(even.len() == nb_keys / 2);
+                    for i in range(1, keys.len()) {
+                        assert!(keys[i - 1] < keys[i]);
+                    }
+                    for i in range(0u, nb_keys / 2) {
+                        assert!(btree.find(&(i * 2)) == Some(i * 2));
+                    }
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, nb_writers + nb_readers) {
+            port.recv();
+        }
+        let btree = arc_btree.get();
+        assert!(btree.len() == nb_keys / 2);
+        let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+        assert!(keys == range(0u, nb_keys / 2).map(|i| i * 2).collect());
+        // nobody reads anymore, the next allocation gives all freed pages back
+        assert!(btree.storage.nb_readers() == 0);
+        btree.storage.new_page();
+        assert!(btree.storage.nb_retired() == 0);
+        assert!(btree.storage.inner().nb_pages() ==
+                btree.statistics.leafs() + btree.statistics.inodes());
+    }
+    #[test]
+    fn test_concurrent_delete_and_scan() {
+        delete_while_scanning(2000, 4, 4);
+    }
+    #[test]
+    fn test_concurrent_delete_and_scan_single_writer() {
+        delete_while_scanning(4000, 1, 8);
+    }
+    #[test]
+    fn test_entry_keeps_pages() {
+        let btree = new_epoch_test(4);
+        for i in range(0u, 100) {
+            btree.insert(i, i);
+        }
+        {
+            // the most left leaf is never merged, the removals don't need its lock
+            let entry = btree.entry(0);
+            assert!(entry.is_occupied());
+            for i in range(10u, 100) {
+                btree.remove(&i);
+            }
+            // the pages freed by the merges are kept, while the entry lives
+            assert!(btree.storage.nb_readers() == 1);
+            assert!(btree.storage.nb_retired() > 0);
+        }
+        assert!(btree.storage.nb_readers() == 0);
+        btree.storage.new_page();
+        assert!(btree.storage.nb_retired() == 0);
+        assert!(btree.len() == 10);
+    }
+}
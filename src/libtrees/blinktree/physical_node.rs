@@ -12,6 +12,7 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use encoded_size::EncodedSize;
 use utils;
 
 pub trait PhysicalNode<K,V,Ptr> {
@@ -25,6 +26,8 @@ pub trait PhysicalNode<K,V,Ptr> {
     fn link_ptr<'a>(&'a self) -> Option<&'a Ptr>;
     // returns the old link pointer
     fn set_link_ptr(&mut self, new_link_ptr: Ptr) -> Option<Ptr>;
+    // removes the link pointer, the node becomes the most right node of its level
+    fn take_link_ptr(&mut self) -> Option<Ptr>;
 
     fn max_key<'a>(&'a self) -> &'a K;
     fn min_key<'a>(&'a self) -> &'a K;
@@ -40,21 +43,38 @@ pub trait PhysicalNode<K,V,Ptr> {
     fn unset_root(&mut self);
     fn is_leaf(&self) -> bool;
     fn is_inode(&self) -> bool;
+    /// a dead node was merged into its left neighbour. It holds no keys and
+    /// its link pointer points to the left neighbour.
+    fn is_dead(&self) -> bool;
+    fn set_dead(&mut self);
 
     fn is_most_right_node(&self) -> bool {
         self.link_ptr().is_none()
     }
 
-    fn needs_split(&self, max_size: uint) -> bool;
+    /// the size of the node in a serialized page
+    fn byte_size(&self) -> uint;
+    /// the size that the key and the value add to the node
+    fn entry_size(&self, key: &K, value: &V) -> uint;
+
+    fn needs_split(&self, max_bytes: uint) -> bool {
+        max_bytes < self.byte_size()
+    }
+    /// true if the node does not fit into `max_bytes` anymore, after the key and the value
+    /// are inserted.
+    fn overflows_with(&self, key: &K, value: &V, max_bytes: uint) -> bool {
+        max_bytes < self.byte_size() + self.entry_size(key, value)
+    }
     fn split_at(&mut self, position: uint) -> (~[K],~[V]);
 
 }
 
-pub static T_ROOT: uint = 1 >> 0;
-pub static T_LEAF: uint = 1 >> 1;
-pub static T_INODE: uint = 1 >> 2;
+pub static T_ROOT: uint = 1 << 0;
+pub static T_LEAF: uint = 1 << 1;
+pub static T_INODE: uint = 1 << 2;
+pub static T_DEAD: uint = 1 << 3;
 
-#[deriving(Clone)]
+#[deriving(Clone, Eq)]
 pub struct DefaultBLinkNode<K, V, Ptr> {
     node_type: uint,
     my_ptr: Ptr,
@@ -81,8 +101,12 @@ fn test_set_node_type() {
     let tpe = &mut 0;
     set_node_type(tpe, T_ROOT);
     assert!(*tpe == T_ROOT);
+    set_node_type(tpe, T_DEAD);
+    assert!(is_node_type(*tpe, T_ROOT));
+    assert!(is_node_type(*tpe, T_DEAD));
+    assert!(! is_node_type(*tpe, T_LEAF));
 }
-impl <K,V,Ptr>
+impl <K: EncodedSize, V: EncodedSize, Ptr: EncodedSize>
 PhysicalNode<K,V,Ptr> for DefaultBLinkNode<K,V,Ptr> {
     fn new(node_type: uint, ptr: Ptr, link_ptr: Option<Ptr>,
            keys: ~[K], values: ~[V]) -> DefaultBLinkNode<K, V, Ptr> {
@@ -108,6 +132,9 @@ PhysicalNode<K,V,Ptr> for DefaultBLinkNode<K,V,Ptr> {
         self.link_ptr = Some(new_link_ptr);
         return old_link_ptr;
     }
+    fn take_link_ptr(&mut self) -> Option<Ptr> {
+        self.link_ptr.take()
+    }
     fn max_key<'a>(&'a self) -> &'a K {
         &self.keys[self.keys.len()-1]
     }
@@ -141,12 +168,22 @@ PhysicalNode<K,V,Ptr> for DefaultBLinkNode<K,V,Ptr> {
     fn is_leaf(&self) -> bool {
         is_node_type(self.node_type,T_LEAF)
     }
+    fn is_dead(&self) -> bool {
+        is_node_type(self.node_type,T_DEAD)
+    }
+    fn set_dead(&mut self) {
+        set_node_type(&mut self.node_type, T_DEAD);
+    }
     fn set_root(&mut self) {
         set_node_type(&mut self.node_type, T_ROOT);
     }
 
-    fn needs_split(&self, max_size: uint) -> bool {
-        max_size < self.keys.len()
+    fn byte_size(&self) -> uint {
+        self.node_type.encoded_size() + self.my_ptr.encoded_size() +
+            self.link_ptr.encoded_size() + self.keys.encoded_size() + self.values.encoded_size()
+    }
+    fn entry_size(&self, key: &K, value: &V) -> uint {
+        key.encoded_size() + value.encoded_size()
     }
     fn split_at(&mut self, position: uint) -> (~[K],~[V]) {
         let ret_keys = utils::split_at(&mut self.keys, position);
@@ -154,3 +191,23 @@ PhysicalNode<K,V,Ptr> for DefaultBLinkNode<K,V,Ptr> {
         (ret_keys, ret_values)
     }
 }
+
+#[test]
+fn test_byte_size() {
+    let size = 0u.encoded_size();
+    let mut leaf: DefaultBLinkNode<uint, uint, uint> =
+        PhysicalNode::new(T_LEAF, 0u, None, ~[1u, 2], ~[10u, 20]);
+    // node type, my_ptr, the tag of the link ptr and both lengths
+    assert!(leaf.byte_size() == 4 * size + 1 + 4 * size);
+    assert!(leaf.entry_size(&3, &30) == 2 * size);
+    leaf.set_link_ptr(1);
+    assert!(leaf.byte_size() == 5 * size + 1 + 4 * size);
+
+    let max_bytes = leaf.byte_size();
+    assert!(!leaf.needs_split(max_bytes));
+    assert!(leaf.overflows_with(&3, &30, max_bytes));
+    assert!(!leaf.overflows_with(&3, &30, max_bytes + 2 * size));
+    leaf.mut_keys().push(3);
+    leaf.mut_values().push(30);
+    assert!(leaf.needs_split(max_bytes));
+}
@@ -0,0 +1,268 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::uint;
+
+/// Decides where an overfull node is split.
+pub trait SplitPolicy<K> {
+    /// Returns the number of keys that stay in the left node. `keys` are the keys of the
+    /// overfull node, the new key was inserted at `inserted_at`.
+    fn split_position(&self, keys: &[K], inserted_at: uint) -> uint;
+    /// Returns the key, that is stored in the parent to separate the left node with the max
+    /// key `left_max` from the right node with the min key `right_min`.
+    /// None stores `left_max`.
+    fn separator(&self, _left_max: &K, _right_min: &K) -> Option<K> {
+        None
+    }
+}
+
+/// Splits in the middle, so both nodes are half full.
+/// Monotonically increasing keys leave every node half empty.
+pub struct Midpoint;
+
+impl<K> SplitPolicy<K> for Midpoint {
+    fn split_position(&self, keys: &[K], _: uint) -> uint {
+        (keys.len() - 1) / 2
+    }
+}
+
+/// The left node keeps `left_percent` percent of the keys.
+/// A ratio of 90 fills nodes well for ascending keys, but splits random inserts unevenly.
+pub struct FixedRatio {
+    priv left_percent: uint
+}
+
+impl FixedRatio {
+    /// Returns None, if `left_percent` is not between 1 and 99, so both nodes get keys.
+    pub fn new(left_percent: uint) -> Option<FixedRatio> {
+        if left_percent >= 1 && left_percent <= 99 {
+            Some(FixedRatio { left_percent: left_percent })
+        } else {
+            None
+        }
+    }
+}
+
+impl<K> SplitPolicy<K> for FixedRatio {
+    fn split_position(&self, keys: &[K], _: uint) -> uint {
+        keys.len() * self.left_percent / 100
+    }
+}
+
+/// If the new key was appended at the tail of the node, the left node keeps all old keys and
+/// only the new key moves to the right node. Otherwise it splits in the middle.
+/// Ascending keys always hit the tail of the most right node, so they fill the nodes completely.
+pub struct RightmostAppend;
+
+impl<K> SplitPolicy<K> for RightmostAppend {
+    fn split_position(&self, keys: &[K], inserted_at: uint) -> uint {
+        if inserted_at == keys.len() - 1 {
+            keys.len() - 1
+        } else {
+            Midpoint.split_position(keys, inserted_at)
+        }
+    }
+}
+
+/// The length of the prefix of a key, that is needed to separate it from a smaller key.
+pub trait SeparatorLen {
+    fn separator_len(&self, next: &Self) -> uint;
+    /// the shortest key, that is at least `self` and smaller than `next`
+    fn shortest_separator(&self, next: &Self) -> Self;
+}
+
+impl SeparatorLen for uint {
+    // the keys are compared byte by byte, the most significant byte first
+    fn separator_len(&self, next: &uint) -> uint {
+        let bytes = uint::bits / 8;
+        let mut diff = *self ^ *next;
+        let mut equal_bytes = bytes;
+        while diff > 0 {
+            diff = diff >> 8;
+            equal_bytes -= 1;
+        }
+        cmp::min(equal_bytes + 1, bytes)
+    }
+    // every uint has the same encoded size
+    fn shortest_separator(&self, _next: &uint) -> uint {
+        *self
+    }
+}
+
+impl SeparatorLen for ~str {
+    fn separator_len(&self, next: &~str) -> uint {
+        let mut equal_bytes = 0;
+        while equal_bytes < self.len() && equal_bytes < next.len() &&
+            self[equal_bytes] == next[equal_bytes] {
+            equal_bytes += 1;
+        }
+        cmp::min(equal_bytes + 1, next.len())
+    }
+    // the prefix of `next` up to the first byte, that differs from `self`. It is bigger than
+    // `self` and smaller than `next`, if it is shorter than `next`.
+    fn shortest_separator(&self, next: &~str) -> ~str {
+        let mut len = self.separator_len(next);
+        // the prefix ends after a whole character
+        while len < next.len() && !next.is_char_boundary(len) {
+            len += 1;
+        }
+        if len < next.len() {
+            next.slice_to(len).to_owned()
+        } else {
+            self.clone()
+        }
+    }
+}
+
+/// Chooses the split position with the shortest separator in the middle half of the node,
+/// so the keys in the inodes stay short. Ties are broken by the distance to the middle.
+/// Only the prefix of the right key, that separates it from the left key, is stored in the
+/// parent (suffix truncation).
+pub struct ShortSeparator;
+
+impl<K: SeparatorLen> SplitPolicy<K> for ShortSeparator {
+    fn split_position(&self, keys: &[K], inserted_at: uint) -> uint {
+        // two keys can only be split after the first one
+        if keys.len() < 3 {
+            return 1;
+        }
+        let middle = Midpoint.split_position(keys, inserted_at);
+        let from = cmp::max(1, keys.len() / 4);
+        let to = cmp::max(from, keys.len() - keys.len() / 4);
+        let mut best = middle;
+        let mut best_len = keys[middle - 1].separator_len(&keys[middle]);
+        for position in range(from, to) {
+            let len = keys[position - 1].separator_len(&keys[position]);
+            let distance = if position > middle { position - middle } else { middle - position };
+            let best_distance = if best > middle { best - middle } else { middle - best };
+            if len < best_len || (len == best_len && distance < best_distance) {
+                best = position;
+                best_len = len;
+            }
+        }
+        best
+    }
+    fn separator(&self, left_max: &K, right_min: &K) -> Option<K> {
+        Some(left_max.shortest_separator(right_min))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SplitPolicy, Midpoint, FixedRatio, RightmostAppend, ShortSeparator, SeparatorLen};
+    use blinktree::blinktree::BTree;
+    use blinktree::blink_ops::{BLinkOps, PolicyBLinkOps};
+    use blinktree::physical_node::DefaultBLinkNode;
+    use lock::SimpleLockManager;
+    use node::Node;
+    use persistent::Map;
+    use statistics::{StatisticsManager, AtomicStatistics};
+    use storage::StupidHashmapStorage;
+    use std::rand::{Rng, XorShiftRng};
+
+    static MAX_SIZE: uint = 16;
+
+    // inserts the keys and returns the average fill factor of the leafs
+    fn fill_factor<OPS: BLinkOps<uint, uint, uint,
+                                 DefaultBLinkNode<uint, uint, uint>,
+                                 DefaultBLinkNode<uint, uint, uint>>>(ops: OPS, keys: ~[uint]) -> f64 {
+        let storage: StupidHashmapStorage<uint, Node<DefaultBLinkNode<uint, uint, uint>,
+                                                     DefaultBLinkNode<uint, uint, uint>>>
+            = StupidHashmapStorage::new();
+        let btree = BTree::new(storage, SimpleLockManager::new(), AtomicStatistics::new(),
+                               ops, BTree::page_size_for(MAX_SIZE)).unwrap();
+        for key in keys.iter() {
+            btree.insert(*key, *key);
+        }
+        for key in keys.iter() {
+            assert!(btree.find(key) == Some(*key));
+        }
+        btree.statistics.elements() as f64 / (btree.statistics.leafs() * MAX_SIZE) as f64
+    }
+    fn sequential() -> ~[uint] {
+        range(0u, 10000).collect()
+    }
+    // the same keys in every run, so the fill factors are reproducible
+    fn random_keys() -> ~[uint] {
+        let mut rng = XorShiftRng::new_seeded(1, 2, 3, 4);
+        range(0u, 10000).map(|_| rng.gen::<uint>() % 1000000).collect()
+    }
+
+    #[test]
+    fn test_midpoint() {
+        assert!(Midpoint.split_position([1u, 2, 3, 4, 5], 4) == 2);
+        assert!(Midpoint.split_position([1u, 2, 3, 4], 0) == 1);
+
+        let sequential_fill = fill_factor(PolicyBLinkOps { policy: Midpoint }, sequential());
+        let random_fill = fill_factor(PolicyBLinkOps { policy: Midpoint }, random_keys());
+        assert!(sequential_fill < 0.6, format!("sequential fill factor: {}", sequential_fill));
+        assert!(random_fill > 0.6, format!("random fill factor: {}", random_fill));
+    }
+    #[test]
+    fn test_fixed_ratio() {
+        let ratio = FixedRatio::new(90).unwrap();
+        assert!(ratio.split_position([0u, ..10], 3) == 9);
+        assert!(FixedRatio::new(50).unwrap().split_position([0u, ..10], 3) == 5);
+        assert!(FixedRatio::new(0).is_none());
+        assert!(FixedRatio::new(100).is_none());
+
+        let sequential_fill = fill_factor(PolicyBLinkOps { policy: ratio }, sequential());
+        let random_fill = fill_factor(PolicyBLinkOps { policy: ratio }, random_keys());
+        assert!(sequential_fill > 0.85, format!("sequential fill factor: {}", sequential_fill));
+        assert!(random_fill > 0.4, format!("random fill factor: {}", random_fill));
+    }
+    #[test]
+    fn test_rightmost_append() {
+        assert!(RightmostAppend.split_position([1u, 2, 3, 4, 5], 4) == 4);
+        assert!(RightmostAppend.split_position([1u, 2, 3, 4, 5], 3) == 2);
+
+        let sequential_fill = fill_factor(PolicyBLinkOps { policy: RightmostAppend }, sequential());
+        let random_fill = fill_factor(PolicyBLinkOps { policy: RightmostAppend }, random_keys());
+        assert!(sequential_fill > 0.95, format!("sequential fill factor: {}", sequential_fill));
+        assert!(random_fill > 0.6, format!("random fill factor: {}", random_fill));
+    }
+    #[test]
+    fn test_separator_len() {
+        assert!(1u.separator_len(&2) == 8);
+        assert!(255u.separator_len(&256) == 7);
+        assert!(0u.separator_len(&(1 << 16)) == 6);
+        assert!((~"apple").separator_len(&~"apricot") == 3);
+        assert!((~"b").separator_len(&~"banana") == 2);
+        assert!((~"a").separator_len(&~"b") == 1);
+        assert!((~"apple").shortest_separator(&~"apricot") == ~"apr");
+        assert!((~"b").shortest_separator(&~"banana") == ~"ba");
+        // "b" has no shorter prefix, that is smaller than itself
+        assert!((~"a").shortest_separator(&~"b") == ~"a");
+        // the prefix is not cut within the two bytes of a character
+        assert!((~"a\xe4").shortest_separator(&~"a\xf6x") == ~"a\xf6");
+        assert!(1u.shortest_separator(&2) == 1);
+    }
+    #[test]
+    fn test_short_separator() {
+        let keys = [~"aaa", ~"aab", ~"aac", ~"aad", ~"abz", ~"abza", ~"abzb", ~"abzc"];
+        // "abz" only needs two bytes to separate it from "aad"
+        assert!(ShortSeparator.split_position(keys, 0) == 4);
+        let keys = [~"a", ~"b", ~"c", ~"d", ~"e"];
+        assert!(ShortSeparator.split_position(keys, 0) == 2);
+        // a leaf with a single big key splits with two keys
+        assert!(ShortSeparator.split_position([~"a", ~"b"], 1) == 1);
+
+        let sequential_fill = fill_factor(PolicyBLinkOps { policy: ShortSeparator }, sequential());
+        let random_fill = fill_factor(PolicyBLinkOps { policy: ShortSeparator }, random_keys());
+        assert!(sequential_fill > 0.45, format!("sequential fill factor: {}", sequential_fill));
+        assert!(random_fill > 0.55, format!("random fill factor: {}", random_fill));
+    }
+}
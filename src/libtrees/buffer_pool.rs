@@ -0,0 +1,545 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::hash::Hash;
+use std::hashmap::HashMap;
+use extra::arc::MutexArc;
+
+use statistics::BufferStatistics;
+use storage::{StorageManager, PageReaders, PageError};
+
+struct Frame<Ptr, N> {
+    ptr: Ptr,
+    node: N,
+    // set by every access, the clock hand gives a referenced frame a second chance
+    referenced: bool,
+    // a pinned frame is never evicted
+    pins: uint
+}
+
+struct Frames<Ptr, N> {
+    capacity: uint,
+    frames: ~[Option<Frame<Ptr, N>>],
+    // the indices of the empty frames
+    empty: ~[uint],
+    // the frame of every page in the pool
+    pages: HashMap<Ptr, uint>,
+    hand: uint,
+    // the writes, that didn't reach the storage yet, in the order they were made. The pages
+    // of one `write_all` stay in one group, so the storage sees the order and the groups of
+    // the tree. There are at most as many groups as frames.
+    pending: ~[~[(Ptr, N)]],
+    // the number of pending groups of every dirty page
+    unwritten: HashMap<Ptr, uint>,
+    // the versions of the pages. The storage only sees the writes, when a dirty page is
+    // written back, so the pool counts them itself.
+    versions: HashMap<Ptr, uint>,
+    counters: BufferStatistics
+}
+
+fn ptr_of<'a, Ptr, N>(write: &'a (Ptr, N)) -> &'a Ptr {
+    let &(ref ptr, _) = write;
+    ptr
+}
+
+impl<Ptr: Hash + Eq + Clone, N: Clone> Frames<Ptr, N> {
+    fn frame<'a>(&'a mut self, ptr: &Ptr) -> Option<&'a mut Frame<Ptr, N>> {
+        match self.pages.find(ptr) {
+            Some(idx) => Some(self.frames[*idx].get_mut_ref()),
+            None => None
+        }
+    }
+    // returns the frame of the page, the page is read from the storage if it is not in the pool
+    fn fetch<'a, S: StorageManager<Ptr, N>>(&'a mut self, ptr: &Ptr, storage: &S)
+        -> Result<Option<&'a mut Frame<Ptr, N>>, PageError<Ptr>> {
+        if self.pages.contains_key(ptr) {
+            self.counters.hits += 1;
+        } else {
+            self.counters.misses += 1;
+            match storage.read(ptr) {
+                Ok(Some(node)) => self.load(ptr.clone(), node, storage),
+                Ok(None) => return Ok(None),
+                Err(err) => return Err(err)
+            }
+        }
+        let frame = self.frame(ptr).unwrap();
+        frame.referenced = true;
+        Ok(Some(frame))
+    }
+    fn load<S: StorageManager<Ptr, N>>(&mut self, ptr: Ptr, node: N, storage: &S) {
+        let idx = self.empty_frame(storage);
+        self.pages.insert(ptr.clone(), idx);
+        self.frames[idx] = Some(Frame {
+            ptr: ptr,
+            node: node,
+            referenced: true,
+            pins: 0
+        });
+    }
+    // puts the node into the frame of the page, the page is not read from the storage
+    fn store<S: StorageManager<Ptr, N>>(&mut self, ptr: &Ptr, node: N, storage: &S) {
+        let mut node = Some(node);
+        match self.frame(ptr) {
+            Some(frame) => {
+                frame.node = node.take_unwrap();
+                frame.referenced = true;
+            }
+            None => {}
+        }
+        if node.is_some() {
+            self.load(ptr.clone(), node.take_unwrap(), storage);
+        }
+        self.bump_version(ptr);
+    }
+    // queues a group of writes. Consecutive writes of the same page are merged.
+    fn push_writes<S: StorageManager<Ptr, N>>(&mut self, group: ~[(Ptr, N)], storage: &S) {
+        if group.len() == 1 && !self.pending.is_empty() {
+            let last = self.pending.len() - 1;
+            if self.pending[last].len() == 1 &&
+               ptr_of(&self.pending[last][0]) == ptr_of(&group[0]) {
+                self.pending[last] = group;
+                return;
+            }
+        }
+        for write in group.iter() {
+            *self.unwritten.find_or_insert(ptr_of(write).clone(), 0) += 1;
+        }
+        self.pending.push(group);
+        if self.pending.len() > self.capacity {
+            self.write_back_oldest(storage);
+        }
+    }
+    fn write_back_oldest<S: StorageManager<Ptr, N>>(&mut self, storage: &S) {
+        let mut group = self.pending.shift();
+        for write in group.iter() {
+            let left = {
+                let count = self.unwritten.get_mut(ptr_of(write));
+                *count -= 1;
+                *count
+            };
+            if left == 0 {
+                self.unwritten.remove(ptr_of(write));
+            }
+        }
+        self.counters.write_backs += group.len();
+        if group.len() == 1 {
+            let (ptr, node) = group.pop();
+            storage.write(&ptr, node);
+        } else {
+            storage.write_all(group);
+        }
+    }
+    // writes back the groups in their order, until no write of the page is pending
+    fn write_back<S: StorageManager<Ptr, N>>(&mut self, ptr: &Ptr, storage: &S) {
+        while self.unwritten.contains_key(ptr) {
+            self.write_back_oldest(storage);
+        }
+    }
+    fn write_back_all<S: StorageManager<Ptr, N>>(&mut self, storage: &S) {
+        while !self.pending.is_empty() {
+            self.write_back_oldest(storage);
+        }
+    }
+    // returns an empty frame. If the pool is full, the clock hand moves over the frames until
+    // it finds one, that is neither pinned nor referenced, and evicts its page.
+    fn empty_frame<S: StorageManager<Ptr, N>>(&mut self, storage: &S) -> uint {
+        match self.empty.pop_opt() {
+            Some(idx) => return idx,
+            None => {}
+        }
+        if self.frames.len() < self.capacity {
+            self.frames.push(None);
+            return self.frames.len() - 1;
+        }
+        // after one round every frame lost its reference bit, after two rounds all are pinned
+        let mut steps = 0;
+        let mut victim = None;
+        while victim.is_none() {
+            if steps == 2 * self.capacity {
+                fail!("all {} frames of the buffer pool are pinned", self.capacity);
+            }
+            let idx = self.hand;
+            self.hand = (self.hand + 1) % self.capacity;
+            steps += 1;
+            let frame = self.frames[idx].get_mut_ref();
+            if frame.pins == 0 {
+                if frame.referenced {
+                    frame.referenced = false;
+                } else {
+                    victim = Some(idx);
+                }
+            }
+        }
+        let victim = victim.unwrap();
+        self.counters.evictions += 1;
+        self.evict(victim, storage);
+        victim
+    }
+    fn evict<S: StorageManager<Ptr, N>>(&mut self, idx: uint, storage: &S) {
+        let frame = self.frames[idx].take_unwrap();
+        self.pages.remove(&frame.ptr);
+        self.write_back(&frame.ptr, storage);
+    }
+    // removes the page of a freed page. A dirty page is still written back, because a storage
+    // that defers freeing may serve it to the readers, that are still on it.
+    fn discard<S: StorageManager<Ptr, N>>(&mut self, ptr: &Ptr, storage: &S) {
+        match self.pages.find_copy(ptr) {
+            Some(idx) => {
+                assert!(self.frames[idx].get_ref().pins == 0, "a pinned page was freed");
+                self.evict(idx, storage);
+                self.empty.push(idx);
+            }
+            None => {}
+        }
+    }
+    fn version(&self, ptr: &Ptr) -> uint {
+        match self.versions.find(ptr) {
+            Some(version) => *version,
+            None => 0
+        }
+    }
+    fn bump_version(&mut self, ptr: &Ptr) {
+        *self.versions.find_or_insert(ptr.clone(), 0) += 1;
+    }
+}
+
+/// Caches the nodes of another storage in a fixed number of frames, so a tree that is
+/// bigger than the memory can be used with a file storage.
+/// The frames are evicted with the CLOCK algorithm. Writes only change the frame and are
+/// queued, a dirty node is written back to the storage, when it is evicted or the pool is
+/// flushed. The queued writes reach the storage in the order they were made, together with
+/// the writes before them, and the pages of a `write_all` stay together. So a storage, that
+/// survives a crash, sees the order of the tree, e.g. that the right half of a split is
+/// written first. Before the root is set, all queued writes are written back.
+pub struct BufferPool<Ptr, N, S> {
+    priv storage: S,
+    priv frames: MutexArc<Frames<Ptr, N>>
+}
+
+impl<Ptr: Hash + Eq + Clone + Freeze + Send,
+     N: Clone + Freeze + Send,
+     S: StorageManager<Ptr, N>>
+BufferPool<Ptr, N, S> {
+    /// a pool, that holds at most `nb_frames` nodes in memory
+    pub fn new(storage: S, nb_frames: uint) -> BufferPool<Ptr, N, S> {
+        assert!(nb_frames > 0);
+        BufferPool {
+            storage: storage,
+            frames: MutexArc::new(Frames {
+                capacity: nb_frames,
+                frames: ~[],
+                empty: ~[],
+                pages: HashMap::new(),
+                hand: 0,
+                pending: ~[],
+                unwritten: HashMap::new(),
+                versions: HashMap::new(),
+                counters: BufferStatistics::new()
+            })
+        }
+    }
+    pub fn inner<'a>(&'a self) -> &'a S {
+        &self.storage
+    }
+    /// the number of pages in the frames
+    pub fn nb_cached(&self) -> uint {
+        do self.frames.access |frames| {
+            frames.pages.len()
+        }
+    }
+    pub fn statistics(&self) -> BufferStatistics {
+        do self.frames.access |frames| {
+            frames.counters.clone()
+        }
+    }
+    /// Keeps the page in its frame until the guard is dropped.
+    /// Returns None, if the page doesn't exist.
+    pub fn pin<'a>(&'a self, id: &Ptr)
+        -> Result<Option<PageGuard<'a, Ptr, N, S>>, PageError<Ptr>> {
+        let pinned = do self.frames.access |frames| {
+            match frames.fetch(id, &self.storage) {
+                Ok(Some(frame)) => {
+                    frame.pins += 1;
+                    Ok(true)
+                }
+                Ok(None) => Ok(false),
+                Err(err) => Err(err)
+            }
+        };
+        match pinned {
+            Ok(true) => Ok(Some(PageGuard { pool: self, ptr: id.clone() })),
+            Ok(false) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+    /// writes all dirty pages back to the storage, they stay in their frames
+    pub fn flush(&self) {
+        do self.frames.access |frames| {
+            frames.write_back_all(&self.storage);
+        }
+    }
+}
+
+impl<Ptr, N, S: PageReaders> PageReaders for BufferPool<Ptr, N, S> {
+    fn enter(&self) {
+        self.storage.enter();
+    }
+    fn exit(&self) {
+        self.storage.exit();
+    }
+}
+
+impl<Ptr: Hash + Eq + Clone + Freeze + Send,
+     N: Clone + Freeze + Send,
+     S: StorageManager<Ptr, N>>
+StorageManager<Ptr, N> for BufferPool<Ptr, N, S> {
+    fn new_page(&self) -> Ptr {
+        self.storage.new_page()
+    }
+    fn read(&self, id: &Ptr) -> Result<Option<N>, PageError<Ptr>> {
+        do self.frames.access |frames| {
+            match frames.fetch(id, &self.storage) {
+                Ok(Some(frame)) => Ok(Some(frame.node.clone())),
+                Ok(None) => Ok(None),
+                Err(err) => Err(err)
+            }
+        }
+    }
+    fn read_versioned(&self, id: &Ptr) -> Result<Option<(uint, N)>, PageError<Ptr>> {
+        do self.frames.access |frames| {
+            let node = match frames.fetch(id, &self.storage) {
+                Ok(Some(frame)) => frame.node.clone(),
+                Ok(None) => return Ok(None),
+                Err(err) => return Err(err)
+            };
+            Ok(Some((frames.version(id), node)))
+        }
+    }
+    // a write replaces the whole page, so a page that is not in the pool is not read.
+    // The write is queued first, so loading the page can't evict the new node unwritten.
+    fn write(&self, id: &Ptr, node: N) {
+        let mut node = Some(node);
+        do self.frames.access |frames| {
+            let node = node.take_unwrap();
+            frames.push_writes(~[(id.clone(), node.clone())], &self.storage);
+            frames.store(id, node, &self.storage);
+        }
+    }
+    fn write_all(&self, nodes: ~[(Ptr, N)]) {
+        let mut nodes = Some(nodes);
+        do self.frames.access |frames| {
+            let nodes = nodes.take_unwrap();
+            frames.push_writes(nodes.clone(), &self.storage);
+            for (id, node) in nodes.move_iter() {
+                frames.store(&id, node, &self.storage);
+            }
+        }
+    }
+    fn free_page(&self, id: &Ptr) {
+        do self.frames.access |frames| {
+            frames.discard(id, &self.storage);
+            frames.bump_version(id);
+        }
+        self.storage.free_page(id);
+    }
+    fn version(&self, id: &Ptr) -> uint {
+        do self.frames.access |frames| {
+            frames.version(id)
+        }
+    }
+    fn root(&self) -> Option<Ptr> {
+        self.storage.root()
+    }
+    fn set_root(&self, root: &Ptr) {
+        self.flush();
+        self.storage.set_root(root)
+    }
+    fn node_sizes(&self) -> Option<(uint, uint)> {
+        self.storage.node_sizes()
+    }
+    fn set_node_sizes(&self, max_leaf_bytes: uint, max_inode_bytes: uint) {
+        self.storage.set_node_sizes(max_leaf_bytes, max_inode_bytes)
+    }
+    // checks the pages in the storage, the queued writes are not written yet
+    fn scrub(&self) -> ~[PageError<Ptr>] {
+        self.storage.scrub()
+    }
+}
+
+// the dirty pages must reach the storage, before it is dropped
+#[unsafe_destructor]
+impl<Ptr: Hash + Eq + Clone + Freeze + Send,
+     N: Clone + Freeze + Send,
+     S: StorageManager<Ptr, N>>
+Drop for BufferPool<Ptr, N, S> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A page, that stays in its frame until the guard is dropped.
+pub struct PageGuard<'a, Ptr, N, S> {
+    priv pool: &'a BufferPool<Ptr, N, S>,
+    priv ptr: Ptr
+}
+
+impl<'a,
+     Ptr: Hash + Eq + Clone + Freeze + Send,
+     N: Clone + Freeze + Send,
+     S: StorageManager<Ptr, N>>
+PageGuard<'a, Ptr, N, S> {
+    pub fn ptr<'b>(&'b self) -> &'b Ptr {
+        &self.ptr
+    }
+    /// a copy of the node, it is never read from the storage
+    pub fn read(&self) -> N {
+        do self.pool.frames.access |frames| {
+            frames.frame(&self.ptr).unwrap().node.clone()
+        }
+    }
+    pub fn write(&self, node: N) {
+        self.pool.write(&self.ptr, node);
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, Ptr: Hash + Eq + Clone + Freeze + Send, N: Clone + Freeze + Send, S>
+Drop for PageGuard<'a, Ptr, N, S> {
+    fn drop(&mut self) {
+        do self.pool.frames.access |frames| {
+            frames.frame(&self.ptr).unwrap().pins -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferPool;
+    use storage::{StorageManager, StupidHashmapStorage};
+    use blinktree::blinktree::BTree;
+    use blinktree::blink_ops::DefaultBLinkOps;
+    use blinktree::physical_node::DefaultBLinkNode;
+    use lock::SimpleLockManager;
+    use node::Node;
+    use persistent::Map;
+    use statistics::AtomicStatistics;
+
+    type Pool = BufferPool<uint, ~str, StupidHashmapStorage<uint, ~str>>;
+    type TestNode = Node<DefaultBLinkNode<uint, uint, uint>, DefaultBLinkNode<uint, uint, uint>>;
+
+    fn pool(nb_frames: uint) -> Pool {
+        BufferPool::new(StupidHashmapStorage::new(), nb_frames)
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let pool = pool(2);
+        let page = pool.inner().new_page();
+        pool.inner().write(&page, ~"a");
+        assert!(pool.read(&page) == Ok(Some(~"a")));
+        assert!(pool.read(&page) == Ok(Some(~"a")));
+        assert!(pool.read(&5) == Ok(None));
+        let statistics = pool.statistics();
+        assert!(statistics.hits == 1 && statistics.misses == 2);
+        assert!(statistics.hit_ratio() == 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_set_root_writes_back() {
+        let pool = pool(4);
+        let (root, child) = (pool.new_page(), pool.new_page());
+        pool.write(&child, ~"child");
+        pool.write(&root, ~"root");
+        assert!(pool.inner().nb_pages() == 0);
+        pool.set_root(&root);
+        assert!(pool.inner().read(&root) == Ok(Some(~"root")));
+        assert!(pool.inner().read(&child) == Ok(Some(~"child")));
+    }
+
+    #[test]
+    fn test_dirty_pages_are_written_back() {
+        let pool = pool(2);
+        let pages = ~[pool.new_page(), pool.new_page(), pool.new_page()];
+        pool.write(&pages[0], ~"a");
+        pool.write(&pages[1], ~"b");
+        assert!(pool.inner().nb_pages() == 0);
+        // the third page evicts one of the others
+        pool.write(&pages[2], ~"c");
+        assert!(pool.nb_cached() == 2);
+        assert!(pool.inner().nb_pages() == 1);
+        let statistics = pool.statistics();
+        assert!(statistics.evictions == 1 && statistics.write_backs == 1);
+        for (page, node) in pages.iter().zip((~[~"a", ~"b", ~"c"]).iter()) {
+            assert!(pool.read(page) == Ok(Some(node.clone())));
+        }
+        pool.flush();
+        assert!(pool.inner().nb_pages() == 3);
+    }
+
+    #[test]
+    fn test_versions_count_buffered_writes() {
+        let pool = pool(1);
+        let page = pool.new_page();
+        pool.write(&page, ~"a");
+        pool.write(&page, ~"b");
+        assert!(pool.read_versioned(&page) == Ok(Some((2, ~"b"))));
+        // the versions survive the eviction
+        let other = pool.new_page();
+        pool.write(&other, ~"c");
+        assert!(pool.version(&page) == 2);
+        pool.free_page(&page);
+        assert!(pool.read(&page) == Ok(None));
+        assert!(pool.version(&page) == 3);
+    }
+
+    #[test]
+    fn test_pinned_page_stays() {
+        let pool = pool(2);
+        let pages = ~[pool.new_page(), pool.new_page(), pool.new_page()];
+        for page in pages.iter() {
+            pool.write(page, page.to_str());
+        }
+        let guard = pool.pin(&pages[0]).unwrap().unwrap();
+        guard.write(~"pinned");
+        for _ in range(0u, 10) {
+            pool.read(&pages[1]);
+            pool.read(&pages[2]);
+        }
+        assert!(guard.read() == ~"pinned");
+        assert!(pool.statistics().misses > 0);
+        assert!(pool.pin(&10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tree_in_bounded_memory() {
+        let pool: BufferPool<uint, TestNode, StupidHashmapStorage<uint, TestNode>> =
+            BufferPool::new(StupidHashmapStorage::new(), 16);
+        let btree = BTree::new(pool, SimpleLockManager::new(), AtomicStatistics::new(),
+                               DefaultBLinkOps, BTree::page_size_for(4)).unwrap();
+        for i in range(0u, 2000) {
+            btree.insert(i, i);
+        }
+        for i in range(0u, 500) {
+            btree.remove(&(i * 4));
+        }
+        for i in range(0u, 2000) {
+            let expected = if i % 4 == 0 { None } else { Some(i) };
+            assert!(btree.find(&i) == expected);
+        }
+        assert!(btree.storage.nb_cached() <= 16);
+        let statistics = btree.storage.statistics();
+        assert!(statistics.evictions > 0 && statistics.hits > 0);
+    }
+}
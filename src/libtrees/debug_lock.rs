@@ -0,0 +1,599 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::comm::stream;
+use std::hashmap::{HashMap, HashSet};
+use std::task;
+use extra::arc::MutexArc;
+use extra::time::precise_time_ns;
+
+use lock::LockManager;
+use node::Node;
+use statistics::{LockStatistics, LockCounters};
+use storage::StorageManager;
+use blinktree::physical_node::PhysicalNode;
+use utils::task_id;
+
+#[deriving(Eq, ToStr)]
+pub enum LockError<T> {
+    /// the lock was not granted in time
+    Timeout(T),
+    /// the tasks wait for each other in a cycle, starting with the current task
+    Deadlock(~[uint]),
+    /// the task holds the first page and must not wait for the second one
+    WrongOrder(T, T),
+    /// the task does not hold the page it unlocks
+    NotLocked(T)
+}
+
+/// Decides, if a task that holds the lock of `held` may wait for the lock of `wanted`.
+/// The B-link tree only locks from left to right and from the bottom up.
+pub trait LockOrder<T> {
+    fn may_follow(&self, held: &T, wanted: &T) -> bool;
+}
+
+/// Accepts every order, only the wait-for cycles are detected.
+#[deriving(Clone)]
+pub struct AnyOrder;
+
+impl<T> LockOrder<T> for AnyOrder {
+    fn may_follow(&self, _: &T, _: &T) -> bool {
+        true
+    }
+}
+
+/// A task only waits for pages that are bigger than the pages it holds.
+/// It doesn't fit the B-link tree, whose pages are numbered in the order they are allocated,
+/// see `TreeOrder`.
+#[deriving(Clone)]
+pub struct IncreasingOrder;
+
+impl<T: Ord> LockOrder<T> for IncreasingOrder {
+    fn may_follow(&self, held: &T, wanted: &T) -> bool {
+        held < wanted
+    }
+}
+
+/// The order of the B-link tree: a task only waits for pages on a higher level than the pages
+/// it holds or for pages right of them on the same level.
+/// The level and the position in the chain of siblings are looked up in the pages of the tree.
+/// The tree must not reuse the pages, while a task may lock them, see `EpochStorage`.
+#[deriving(Clone)]
+pub struct TreeOrder<S> {
+    storage: S
+}
+
+impl<S> TreeOrder<S> {
+    pub fn new(storage: S) -> TreeOrder<S> {
+        TreeOrder { storage: storage }
+    }
+}
+
+impl<K, V,
+     Ptr: Clone + Eq,
+     INODE: PhysicalNode<K, Ptr, Ptr>,
+     LEAF: PhysicalNode<K, V, Ptr>,
+     S: StorageManager<Ptr, Node<INODE, LEAF>>>
+LockOrder<Ptr> for TreeOrder<S> {
+    fn may_follow(&self, held: &Ptr, wanted: &Ptr) -> bool {
+        match (level(&self.storage, held), level(&self.storage, wanted)) {
+            (Some(held_level), Some(wanted_level)) =>
+                held_level < wanted_level ||
+                (held_level == wanted_level && is_right_of(&self.storage, held, wanted)),
+            // a freed page has no place in the tree
+            _ => true
+        }
+    }
+}
+
+// the node of the page, None if it is freed or damaged
+fn read_node<Ptr, N, S: StorageManager<Ptr, N>>(storage: &S, ptr: &Ptr) -> Option<N> {
+    match storage.read(ptr) {
+        Ok(node) => node,
+        Err(_) => None
+    }
+}
+
+// the number of levels below the page, a leaf is on level 0
+fn level<K, V,
+         Ptr: Clone,
+         INODE: PhysicalNode<K, Ptr, Ptr>,
+         LEAF: PhysicalNode<K, V, Ptr>,
+         S: StorageManager<Ptr, Node<INODE, LEAF>>>(storage: &S, ptr: &Ptr) -> Option<uint> {
+    let mut level = 0;
+    let mut next = ptr.clone();
+    loop {
+        let node: Node<INODE, LEAF> = match read_node(storage, &next) {
+            Some(node) => node,
+            None => return None
+        };
+        if node.isLeaf() {
+            return Some(level);
+        }
+        next = if node.is_dead() {
+            // a dead inode links to its left sibling, that took its children
+            node.link_ptr().unwrap().clone()
+        } else {
+            level += 1;
+            node.getINode().values()[0].clone()
+        };
+    }
+}
+
+// true, if `right` is reached from `left` over the link pointers.
+// A dead page links back to the page, that took its keys and links past the dead page.
+fn is_right_of<K, V,
+               Ptr: Clone + Eq,
+               INODE: PhysicalNode<K, Ptr, Ptr>,
+               LEAF: PhysicalNode<K, V, Ptr>,
+               S: StorageManager<Ptr, Node<INODE, LEAF>>>(storage: &S, left: &Ptr,
+                                                          right: &Ptr) -> bool {
+    let mut next = left.clone();
+    loop {
+        let node: Node<INODE, LEAF> = match read_node(storage, &next) {
+            Some(node) => node,
+            None => return false
+        };
+        next = match node.link_ptr() {
+            Some(link_ptr) => link_ptr.clone(),
+            None => return false
+        };
+        if &next == right {
+            return true;
+        }
+    }
+}
+
+struct DebugState<T> {
+    // the pages every task holds, in the order they were locked
+    held: HashMap<uint, ~[T]>,
+    // the page every task waits for
+    waiting: HashMap<uint, T>,
+    // the waits, that timed out. Their helper tasks release the page, when it is granted.
+    abandoned: HashSet<uint>,
+    next_wait: uint,
+    // the wrapped manager sees the waits of the helper tasks, so we count the waits ourselves
+    counters: LockCounters<T>
+}
+
+impl<T: Hash + Eq> DebugState<T> {
+    // follows the wait-for edges from `task`. Returns the tasks of a cycle back to `task`.
+    fn find_cycle(&self, task: uint) -> Option<~[uint]> {
+        let mut path = ~[task];
+        let mut visited = HashSet::new();
+        if self.visit(task, task, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+    fn visit(&self, start: uint, current: uint, path: &mut ~[uint],
+             visited: &mut HashSet<uint>) -> bool {
+        let page = match self.waiting.find(&current) {
+            Some(page) => page,
+            None => return false
+        };
+        for (holder, pages) in self.held.iter() {
+            if !pages.contains(page) {
+                continue;
+            }
+            if *holder == start {
+                return true;
+            }
+            if visited.insert(*holder) {
+                path.push(*holder);
+                if self.visit(start, *holder, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+}
+
+/// Wraps a lock manager and records which task holds which pages.
+/// It checks the lock order and detects wait-for cycles, instead of blocking forever it
+/// returns an error. `lock` and `lock_shared` fail with the error, when it is used by a tree.
+/// A task waits in the queue of the wrapped manager, so its fairness is kept.
+/// Clones share the recorded state.
+pub struct DebugLockManager<T, Locks, Order> {
+    locks: Locks,
+    order: Order,
+    state: MutexArc<DebugState<T>>,
+    // the timeout of `lock` and `lock_shared`
+    timeout_ms: u64
+}
+
+impl<T: Hash + Eq + Clone + Freeze + Send + ToStr,
+     Locks: LockManager<T> + Clone + Send,
+     Order: LockOrder<T>>
+DebugLockManager<T, Locks, Order> {
+    pub fn new(locks: Locks, order: Order, timeout_ms: u64) -> DebugLockManager<T, Locks, Order> {
+        DebugLockManager {
+            locks: locks,
+            order: order,
+            state: MutexArc::new(DebugState {
+                held: HashMap::new(),
+                waiting: HashMap::new(),
+                abandoned: HashSet::new(),
+                next_wait: 0,
+                counters: LockCounters::new()
+            }),
+            timeout_ms: timeout_ms
+        }
+    }
+    pub fn lock_with_timeout(&self, id: T, timeout_ms: u64) -> Result<(), LockError<T>> {
+        self.acquire(id, true, timeout_ms)
+    }
+    pub fn lock_shared_with_timeout(&self, id: T, timeout_ms: u64) -> Result<(), LockError<T>> {
+        self.acquire(id, false, timeout_ms)
+    }
+    pub fn try_unlock(&self, id: &T) -> Result<(), LockError<T>> {
+        let task = task_id();
+        let held = do self.state.access |state| {
+            let (held, now_empty) = match state.held.find_mut(&task) {
+                Some(pages) => match pages.iter().position(|p| p == id) {
+                    Some(idx) => {
+                        pages.remove(idx);
+                        (true, pages.is_empty())
+                    }
+                    None => (false, false)
+                },
+                None => (false, false)
+            };
+            if now_empty {
+                state.held.remove(&task);
+            }
+            held
+        };
+        if !held {
+            return Err(NotLocked(id.clone()));
+        }
+        self.locks.unlock(id);
+        Ok(())
+    }
+    /// the pages the current task holds, in the order they were locked
+    pub fn held_by_current_task(&self) -> ~[T] {
+        let task = task_id();
+        do self.state.access |state| {
+            match state.held.find(&task) {
+                Some(pages) => pages.clone(),
+                None => ~[]
+            }
+        }
+    }
+    /// the number of tasks, that wait for a lock
+    pub fn nb_waiting(&self) -> uint {
+        do self.state.access |state| {
+            state.waiting.len()
+        }
+    }
+
+    fn acquire(&self, id: T, exclusive: bool, timeout_ms: u64) -> Result<(), LockError<T>> {
+        let task = task_id();
+        let wrong_order = do self.state.access |state| {
+            match state.held.find(&task) {
+                Some(pages) => pages.iter().find(|held| !self.order.may_follow(*held, &id))
+                                           .map(|held| held.clone()),
+                None => None
+            }
+        };
+        match wrong_order {
+            Some(held) => return Err(WrongOrder(held, id)),
+            None => {}
+        }
+        let locked = if exclusive {
+            self.locks.try_lock(id.clone())
+        } else {
+            self.locks.try_lock_shared(id.clone())
+        };
+        // a cycle, that is closed later, is found by the task that closes it
+        let wait = do self.state.access |state| {
+            if locked {
+                state.counters.acquired();
+                state.held.find_or_insert(task, ~[]).push(id.clone());
+                Ok(None)
+            } else {
+                state.waiting.insert(task, id.clone());
+                match state.find_cycle(task) {
+                    Some(cycle) => {
+                        state.waiting.remove(&task);
+                        Err(Deadlock(cycle))
+                    }
+                    None if timeout_ms == 0 => {
+                        state.waiting.remove(&task);
+                        Err(Timeout(id.clone()))
+                    }
+                    None => {
+                        state.next_wait += 1;
+                        Ok(Some(state.next_wait))
+                    }
+                }
+            }
+        };
+        let wait = match wait {
+            Ok(Some(wait)) => wait,
+            Ok(None) => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        // a helper task waits in the queue of the wrapped manager for us. If we give up
+        // before the page is granted, the helper releases it again.
+        let (port, chan) = stream();
+        let (locks, state, page) = (self.locks.clone(), self.state.clone(), id.clone());
+        do task::spawn {
+            if exclusive {
+                locks.lock(page.clone());
+            } else {
+                locks.lock_shared(page.clone());
+            }
+            do state.access |state| {
+                if state.abandoned.remove(&wait) {
+                    locks.unlock(&page);
+                } else {
+                    chan.send(());
+                }
+            }
+        }
+        let start = precise_time_ns();
+        let deadline = start + timeout_ms * 1000000;
+        loop {
+            let result = do self.state.access |state| {
+                if port.peek() {
+                    state.waiting.remove(&task);
+                    state.counters.acquired_after(&id, precise_time_ns() - start);
+                    state.held.find_or_insert(task, ~[]).push(id.clone());
+                    Some(Ok(()))
+                } else if precise_time_ns() > deadline {
+                    state.waiting.remove(&task);
+                    state.abandoned.insert(wait);
+                    Some(Err(Timeout(id.clone())))
+                } else {
+                    None
+                }
+            };
+            match result {
+                Some(result) => return result,
+                None => task::deschedule()
+            }
+        }
+    }
+}
+
+impl<T: Send, Locks: Clone, Order: Clone> Clone for DebugLockManager<T, Locks, Order> {
+    fn clone(&self) -> DebugLockManager<T, Locks, Order> {
+        DebugLockManager {
+            locks: self.locks.clone(),
+            order: self.order.clone(),
+            state: self.state.clone(),
+            timeout_ms: self.timeout_ms
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Freeze + Send + ToStr,
+     Locks: LockManager<T> + Clone + Send,
+     Order: LockOrder<T>>
+LockManager<T> for DebugLockManager<T, Locks, Order> {
+    fn lock(&self, id: T) {
+        match self.lock_with_timeout(id, self.timeout_ms) {
+            Ok(()) => {}
+            Err(err) => fail!("task {}: {}", task_id(), err.to_str())
+        }
+    }
+    fn lock_shared(&self, id: T) {
+        match self.lock_shared_with_timeout(id, self.timeout_ms) {
+            Ok(()) => {}
+            Err(err) => fail!("task {}: {}", task_id(), err.to_str())
+        }
+    }
+    fn try_lock(&self, id: T) -> bool {
+        self.lock_with_timeout(id, 0).is_ok()
+    }
+    fn try_lock_shared(&self, id: T) -> bool {
+        self.lock_shared_with_timeout(id, 0).is_ok()
+    }
+    fn unlock(&self, id: &T) {
+        match self.try_unlock(id) {
+            Ok(()) => {}
+            Err(err) => fail!("task {}: {}", task_id(), err.to_str())
+        }
+    }
+    fn statistics(&self) -> LockStatistics<T> {
+        do self.state.access |state| {
+            state.counters.snapshot()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DebugLockManager, LockOrder, AnyOrder, IncreasingOrder, TreeOrder, Timeout,
+                Deadlock, WrongOrder, NotLocked};
+    use utils::task_id;
+    use blinktree::blinktree::BTree;
+    use blinktree::blink_ops::DefaultBLinkOps;
+    use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_LEAF, T_INODE};
+    use lock::{LockManager, SimpleLockManager};
+    use node::{Node, Leaf, INode};
+    use persistent::Map;
+    use statistics::AtomicStatistics;
+    use storage::{StorageManager, StupidHashmapStorage};
+    use epoch::EpochStorage;
+    use extra::arc::Arc;
+    use std::comm::{stream, SharedChan};
+    use std::task;
+
+    static TIMEOUT: u64 = 10000;
+
+    #[test]
+    fn test_records_held_locks() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), AnyOrder, TIMEOUT);
+        locks.lock(1u);
+        locks.lock_shared(2u);
+        assert!(locks.held_by_current_task() == ~[1, 2]);
+        locks.unlock(&1);
+        assert!(locks.held_by_current_task() == ~[2]);
+        assert!(locks.try_unlock(&1) == Err(NotLocked(1)));
+        locks.unlock(&2);
+        assert!(locks.held_by_current_task().is_empty());
+    }
+    #[test]
+    fn test_wrong_order() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), IncreasingOrder, TIMEOUT);
+        locks.lock(3u);
+        assert!(locks.lock_with_timeout(5u, TIMEOUT) == Ok(()));
+        assert!(locks.lock_with_timeout(4u, TIMEOUT) == Err(WrongOrder(5, 4)));
+        locks.unlock(&5);
+        locks.unlock(&3);
+    }
+    type UintNode = Node<DefaultBLinkNode<uint, uint, uint>, DefaultBLinkNode<uint, uint, uint>>;
+
+    #[test]
+    fn test_tree_order() {
+        //                  1
+        //  leafs:   5 -> 2 -> 7
+        let pages: StupidHashmapStorage<uint, UintNode> = StupidHashmapStorage::new();
+        for &(ptr, link_ptr, key) in [(5u, Some(2u), 1u), (2, Some(7), 2), (7, None, 3)].iter() {
+            pages.write(&ptr, Leaf(PhysicalNode::new(T_LEAF, ptr, link_ptr, ~[key], ~[key])));
+        }
+        pages.write(&1, INode(PhysicalNode::new(T_INODE, 1u, None, ~[1u, 2], ~[5u, 2, 7])));
+        let order = TreeOrder::new(pages);
+        assert!(order.may_follow(&5, &2) && order.may_follow(&5, &7) && order.may_follow(&2, &7));
+        assert!(!order.may_follow(&2, &5) && !order.may_follow(&7, &5));
+        assert!(order.may_follow(&7, &1) && order.may_follow(&2, &1));
+        assert!(!order.may_follow(&1, &5) && !order.may_follow(&1, &1));
+
+        let locks = DebugLockManager::new(SimpleLockManager::new(), order, TIMEOUT);
+        locks.lock(2u);
+        assert!(locks.lock_with_timeout(5u, TIMEOUT) == Err(WrongOrder(2, 5)));
+        locks.lock(7u);
+        locks.lock(1u);
+        locks.unlock(&2);
+        locks.unlock(&7);
+        locks.unlock(&1);
+    }
+    #[test]
+    fn test_waits_in_fifo_order() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), AnyOrder, TIMEOUT);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        locks.lock(1u);
+        for i in range(0u, 5) {
+            let (task_locks, chan) = (locks.clone(), chan.clone());
+            do task::spawn {
+                task_locks.lock(1u);
+                chan.send(i);
+                task_locks.unlock(&1);
+            }
+            // the next task queues up behind this one in the wrapped manager
+            while locks.locks.waiting(&1) < i + 1 {
+                task::deschedule();
+            }
+        }
+        locks.unlock(&1);
+        for i in range(0u, 5) {
+            assert!(port.recv() == i);
+        }
+    }
+    #[test]
+    fn test_timeout() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), AnyOrder, TIMEOUT);
+        let (port, chan) = stream();
+        let (done_port, done_chan) = stream();
+        let task_locks = locks.clone();
+        do task::spawn {
+            task_locks.lock(1u);
+            chan.send(());
+            done_port.recv();
+            task_locks.unlock(&1);
+        }
+        port.recv();
+        assert!(locks.lock_with_timeout(1u, 20) == Err(Timeout(1)));
+        assert!(!locks.try_lock(1u));
+        assert!(locks.nb_waiting() == 0);
+        done_chan.send(());
+        // the helper of the timed out wait gives the page back
+        locks.lock(1u);
+        locks.unlock(&1);
+    }
+    #[test]
+    fn test_deadlock() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), AnyOrder, TIMEOUT);
+        let (port, chan) = stream();
+        let task_locks = locks.clone();
+        locks.lock(2u);
+        do task::spawn {
+            task_locks.lock(1u);
+            chan.send(task_id());
+            // waits for us
+            chan.send(task_locks.lock_with_timeout(2u, TIMEOUT).is_ok());
+            task_locks.unlock(&2);
+            task_locks.unlock(&1);
+        }
+        let other = port.recv();
+        while locks.nb_waiting() == 0 {
+            task::deschedule();
+        }
+        assert!(locks.lock_with_timeout(1u, TIMEOUT) == Err(Deadlock(~[task_id(), other])));
+        locks.unlock(&2);
+        assert!(port.recv());
+    }
+    #[test]
+    fn test_lock_own_page_again() {
+        let locks = DebugLockManager::new(SimpleLockManager::new(), AnyOrder, TIMEOUT);
+        locks.lock(1u);
+        assert!(locks.lock_with_timeout(1u, TIMEOUT) == Err(Deadlock(~[task_id()])));
+        locks.unlock(&1);
+    }
+    #[test]
+    fn test_tree_with_debug_locks() {
+        // the tasks remove concurrently, the freed pages must outlive the readers and
+        // the lock order checks
+        let pages: StupidHashmapStorage<uint, UintNode> = StupidHashmapStorage::new();
+        let order = TreeOrder::new(pages.clone());
+        let storage = EpochStorage::new(pages);
+        let locks = DebugLockManager::new(SimpleLockManager::new(), order, TIMEOUT);
+        let btree = BTree::new(storage, locks.clone(), AtomicStatistics::new(), DefaultBLinkOps,
+                               BTree::page_size_for(4)).unwrap();
+        let arc_btree = Arc::new(btree);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for t in range(0u, 4) {
+            let (local_btree, chan) = (arc_btree.clone(), chan.clone());
+            do task::spawn {
+                for i in range(0u, 500) {
+                    local_btree.get().insert(i * 4 + t, t);
+                }
+                for i in range(0u, 250) {
+                    local_btree.get().remove(&(i * 8 + t));
+                }
+                chan.send(());
+            }
+        }
+        for _ in range(0u, 4) {
+            port.recv();
+        }
+        let btree = arc_btree.get();
+        assert!(btree.len() == 1000);
+        assert!(locks.nb_waiting() == 0);
+        assert!(locks.statistics().acquisitions >= 3000);
+        for i in range(0u, 2000) {
+            let expected = if i % 8 < 4 { None } else { Some(i % 4) };
+            assert!(btree.find(&i) == expected);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::uint;
+
+/// The number of bytes a value takes up in a serialized page.
+pub trait EncodedSize {
+    fn encoded_size(&self) -> uint;
+}
+
+impl EncodedSize for uint {
+    fn encoded_size(&self) -> uint {
+        uint::bits / 8
+    }
+}
+
+impl EncodedSize for u8 {
+    fn encoded_size(&self) -> uint {
+        1
+    }
+}
+
+// strings and vectors are prefixed with their length
+impl EncodedSize for ~str {
+    fn encoded_size(&self) -> uint {
+        0u.encoded_size() + self.len()
+    }
+}
+
+impl<T: EncodedSize> EncodedSize for ~[T] {
+    fn encoded_size(&self) -> uint {
+        self.iter().fold(0u.encoded_size(), |size, elem| size + elem.encoded_size())
+    }
+}
+
+// one byte for the tag
+impl<T: EncodedSize> EncodedSize for Option<T> {
+    fn encoded_size(&self) -> uint {
+        match self {
+            &Some(ref elem) => 1 + elem.encoded_size(),
+            &None => 1
+        }
+    }
+}
+
+#[test]
+fn test_encoded_size() {
+    let size = uint::bits / 8;
+    assert!(5u.encoded_size() == size);
+    assert!((~"abc").encoded_size() == size + 3);
+    assert!((~[1u, 2, 3]).encoded_size() == 4 * size);
+    assert!((~[~"a", ~"bc"]).encoded_size() == 3 * size + 3);
+    assert!((~[1u8, 2]).encoded_size() == size + 2);
+    assert!(Some(1u).encoded_size() == size + 1);
+    assert!((None::<uint>).encoded_size() == 1);
+}
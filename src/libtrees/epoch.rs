@@ -0,0 +1,303 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::hashmap::HashMap;
+use std::util;
+use extra::arc::MutexArc;
+
+use storage::{StorageManager, PageReaders, PageError};
+use utils::task_id;
+
+/// A task between `enter` and `exit`.
+struct Reader {
+    // the epoch, when the task entered
+    epoch: uint,
+    // the number of nested `enter` calls
+    depth: uint
+}
+
+struct EpochState<Ptr> {
+    epoch: uint,
+    readers: HashMap<uint, Reader>,
+    // the freed pages and the epoch they were freed in
+    retired: ~[(uint, Ptr)],
+    // the pages no reader can see anymore, they are given back to the storage
+    // by the next `new_page` or `free_page`
+    released: ~[Ptr]
+}
+
+impl<Ptr> EpochState<Ptr> {
+    /// moves the pages, that no reader can see anymore, to `released`.
+    /// A reader may have seen a page, if it entered before or in the epoch the page was freed.
+    fn collect(&mut self) {
+        let oldest = self.readers.iter().fold(self.epoch, |oldest, (_, reader)| {
+            if reader.epoch < oldest { reader.epoch } else { oldest }
+        });
+        let mut retired = ~[];
+        for (epoch, ptr) in util::replace(&mut self.retired, ~[]).move_iter() {
+            if epoch < oldest {
+                self.released.push(ptr);
+            } else {
+                retired.push((epoch, ptr));
+            }
+        }
+        self.retired = retired;
+    }
+}
+
+/// Counts the tasks between `enter` and `exit` and holds back the freed pages, until every
+/// task, that entered before a page was freed, has exited. Clones share the state.
+/// A storage, that reuses its pages, keeps one to implement `PageReaders`.
+pub struct Epochs<Ptr> {
+    priv state: MutexArc<EpochState<Ptr>>
+}
+
+impl<Ptr: Freeze + Send> Epochs<Ptr> {
+    pub fn new() -> Epochs<Ptr> {
+        Epochs {
+            state: MutexArc::new(EpochState {
+                epoch: 0,
+                readers: HashMap::new(),
+                retired: ~[],
+                released: ~[]
+            })
+        }
+    }
+    pub fn enter(&self) {
+        let task = task_id();
+        do self.state.access |state| {
+            let epoch = state.epoch;
+            state.readers.find_or_insert(task, Reader { epoch: epoch, depth: 0 }).depth += 1;
+        }
+    }
+    pub fn exit(&self) {
+        let task = task_id();
+        do self.state.access |state| {
+            let done = match state.readers.find_mut(&task) {
+                Some(reader) => {
+                    reader.depth -= 1;
+                    reader.depth == 0
+                }
+                None => fail!("task {} exits without entering", task)
+            };
+            if done {
+                state.readers.remove(&task);
+                state.collect();
+            }
+        }
+    }
+    /// holds back the freed page, until no task can see it anymore
+    pub fn retire(&self, ptr: Ptr) {
+        let mut ptr = Some(ptr);
+        do self.state.access |state| {
+            state.retired.push((state.epoch, ptr.take_unwrap()));
+            state.epoch += 1;
+            state.collect();
+        }
+    }
+    /// takes the freed pages, that no task can see anymore
+    pub fn released(&self) -> ~[Ptr] {
+        do self.state.access |state| {
+            util::replace(&mut state.released, ~[])
+        }
+    }
+    /// the number of freed pages, that are held back
+    pub fn nb_retired(&self) -> uint {
+        do self.state.access |state| {
+            state.retired.len() + state.released.len()
+        }
+    }
+    /// the number of tasks between `enter` and `exit`
+    pub fn nb_readers(&self) -> uint {
+        do self.state.access |state| {
+            state.readers.len()
+        }
+    }
+}
+
+impl<Ptr: Freeze + Send> Clone for Epochs<Ptr> {
+    fn clone(&self) -> Epochs<Ptr> {
+        Epochs { state: self.state.clone() }
+    }
+}
+
+/// Wraps a `StorageManager` and defers `free_page`, until every task that entered
+/// before the page was freed has exited. The tree removes the links to a page before it
+/// frees it, so a task that enters later can't reach it anymore.
+/// `exit` doesn't know the node type of the storage, so the pages are given back by the next
+/// `new_page` or `free_page`. The storages of this crate keep their own `Epochs`, it is
+/// needed for storages, that free their pages immediately.
+pub struct EpochStorage<Ptr, S> {
+    priv storage: S,
+    priv epochs: Epochs<Ptr>
+}
+
+impl<Ptr: Freeze + Send, S> EpochStorage<Ptr, S> {
+    pub fn new(storage: S) -> EpochStorage<Ptr, S> {
+        EpochStorage {
+            storage: storage,
+            epochs: Epochs::new()
+        }
+    }
+    pub fn inner<'a>(&'a self) -> &'a S {
+        &self.storage
+    }
+    /// the number of freed pages, that are not yet given back to the storage
+    pub fn nb_retired(&self) -> uint {
+        self.epochs.nb_retired()
+    }
+    /// the number of tasks between `enter` and `exit`
+    pub fn nb_readers(&self) -> uint {
+        self.epochs.nb_readers()
+    }
+}
+
+// gives the released pages back to the storage
+fn release<Ptr: Freeze + Send, N, S: StorageManager<Ptr, N>>(epochs: &Epochs<Ptr>, storage: &S) {
+    for ptr in epochs.released().iter() {
+        storage.free_page(ptr);
+    }
+}
+
+impl<Ptr: Clone + Freeze + Send, N, S: StorageManager<Ptr, N>>
+StorageManager<Ptr, N> for EpochStorage<Ptr, S> {
+    fn new_page(&self) -> Ptr {
+        release(&self.epochs, &self.storage);
+        self.storage.new_page()
+    }
+    fn read(&self, id: &Ptr) -> Result<Option<N>, PageError<Ptr>> {
+        self.storage.read(id)
+    }
+    fn read_versioned(&self, id: &Ptr) -> Result<Option<(uint, N)>, PageError<Ptr>> {
+        self.storage.read_versioned(id)
+    }
+    fn write(&self, id: &Ptr, node: N) {
+        self.storage.write(id, node)
+    }
+    fn write_all(&self, nodes: ~[(Ptr, N)]) {
+        self.storage.write_all(nodes)
+    }
+    fn free_page(&self, id: &Ptr) {
+        self.epochs.retire(id.clone());
+        release(&self.epochs, &self.storage);
+    }
+    fn version(&self, id: &Ptr) -> uint {
+        self.storage.version(id)
+    }
+    fn root(&self) -> Option<Ptr> {
+        self.storage.root()
+    }
+    fn set_root(&self, root: &Ptr) {
+        self.storage.set_root(root)
+    }
+    fn node_sizes(&self) -> Option<(uint, uint)> {
+        self.storage.node_sizes()
+    }
+    fn set_node_sizes(&self, max_leaf_bytes: uint, max_inode_bytes: uint) {
+        self.storage.set_node_sizes(max_leaf_bytes, max_inode_bytes)
+    }
+    fn scrub(&self) -> ~[PageError<Ptr>] {
+        self.storage.scrub()
+    }
+}
+
+impl<Ptr: Freeze + Send, S> PageReaders for EpochStorage<Ptr, S> {
+    fn enter(&self) {
+        self.epochs.enter()
+    }
+    fn exit(&self) {
+        self.epochs.exit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EpochStorage;
+    use storage::{StorageManager, PageReaders, StupidHashmapStorage};
+    use std::comm::stream;
+    use std::task;
+    use extra::arc::Arc;
+
+    fn storage() -> EpochStorage<uint, StupidHashmapStorage<uint, ~str>> {
+        EpochStorage::new(StupidHashmapStorage::new())
+    }
+
+    #[test]
+    fn test_free_without_readers() {
+        let storage = storage();
+        let page = storage.new_page();
+        storage.write(&page, ~"a");
+        storage.free_page(&page);
+        assert!(storage.read(&page) == Ok(None));
+        assert!(storage.nb_retired() == 0);
+    }
+
+    #[test]
+    fn test_reader_keeps_page() {
+        let storage = storage();
+        let page = storage.new_page();
+        storage.write(&page, ~"a");
+        storage.enter();
+        storage.enter();
+        storage.free_page(&page);
+        assert!(storage.read(&page) == Ok(Some(~"a")));
+        storage.exit();
+        assert!(storage.read(&page) == Ok(Some(~"a")));
+        storage.exit();
+        assert!(storage.nb_retired() == 1 && storage.nb_readers() == 0);
+        // the page is given back by the next allocation
+        storage.new_page();
+        assert!(storage.read(&page) == Ok(None));
+        assert!(storage.nb_retired() == 0);
+    }
+
+    #[test]
+    fn test_later_readers_dont_keep_page() {
+        let arc_storage = Arc::new(storage());
+        let storage = arc_storage.get();
+        let (first, second) = (storage.new_page(), storage.new_page());
+        storage.write(&first, ~"a");
+        storage.write(&second, ~"b");
+        storage.enter();
+        storage.free_page(&first);
+
+        // another task enters after `first` was freed, it only keeps `second`
+        let (port, chan) = stream();
+        let (done_port, done_chan) = stream();
+        let shared = arc_storage.clone();
+        do task::spawn {
+            shared.get().enter();
+            chan.send(());
+            done_port.recv();
+            shared.get().exit();
+            chan.send(());
+        }
+        port.recv();
+        storage.free_page(&second);
+        storage.exit();
+        storage.new_page();
+        assert!(storage.read(&first) == Ok(None));
+        assert!(storage.read(&second) == Ok(Some(~"b")));
+        done_chan.send(());
+        port.recv();
+        storage.new_page();
+        assert!(storage.read(&second) == Ok(None));
+    }
+
+    #[test] #[should_fail]
+    fn test_exit_without_enter() {
+        storage().exit();
+    }
+}
@@ -0,0 +1,1489 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::c_str::ToCStr;
+use std::cmp;
+use std::hashmap::{HashMap, HashSet};
+use std::libc;
+use std::path::Path;
+use std::rt::io::{io_error, Writer, Seek, SeekSet, Open, CreateOrTruncate, ReadWrite};
+use std::rt::io::extensions::ReaderUtil;
+use std::rt::io::file::{open, FileStream};
+use extra::arc::MutexArc;
+
+use page_codec::{PageSize, PageCodec, PageWriter, PageReader, CodecError, InvalidTag, checksum};
+use storage::{StorageManager, PageReaders, PageError, ChecksumMismatch, Malformed};
+use epoch::Epochs;
+
+// the first word of every file, "libtrees" in ascii
+static MAGIC: u64 = 0x6c69627472656573;
+// the page 0 holds the header. A root of 0 means, that no root was stored yet,
+// a free page of 0 ends the free list.
+static HEADER_PAGE: uint = 0;
+// the magic number, the page size, the max node size, the max bytes of a leaf and of an inode
+// of the tree, the number of pages, the root and the first free page
+static HEADER_SIZE: uint = 8 * 8;
+// every page starts with the checksum of the rest of the page, so is every log record
+static CHECKSUM_SIZE: uint = 4;
+// the checksum is followed by the length of the encoded node. A free page has a length
+// of 0, followed by the next free page.
+static LENGTH_SIZE: uint = 8;
+// the tags of the log records
+static PAGE_RECORD: u8 = 1;
+static HEADER_RECORD: u8 = 2;
+static FREE_RECORD: u8 = 3;
+static GROUP_RECORD: u8 = 4;
+// the log is written back into the data file, when it holds that many records
+static CHECKPOINT_INTERVAL: uint = 1024;
+
+struct Header {
+    page_size: uint,
+    max_size: uint,
+    // the limits of the tree, 0 until the tree stored them
+    max_leaf_bytes: uint,
+    max_inode_bytes: uint,
+    // the pages 0 until `nb_pages` exist in the file
+    nb_pages: uint,
+    root: uint,
+    // the head of the free list, every free page holds the next one
+    free: uint
+}
+
+#[deriving(Clone)]
+enum Page {
+    // the encoded node, it is empty until the page is written
+    Used(~[u8]),
+    // the next page of the free list
+    Free(uint)
+}
+
+// a change, that is logged before it reaches the data file
+enum Record {
+    // the page and its encoded node
+    PageRecord(uint, ~[u8]),
+    // the page, that was freed, and the next page of the free list
+    FreeRecord(uint, uint),
+    // the number of pages, the root and the first free page
+    HeaderRecord(uint, uint, uint),
+    // pages and their encoded nodes, that are written together
+    GroupRecord(~[(uint, ~[u8])])
+}
+
+impl Record {
+    // the record is followed by its checksum
+    fn encode(&self) -> ~[u8] {
+        let mut bytes = self.encode_fields();
+        let mut writer = PageWriter::new(CHECKSUM_SIZE);
+        writer.write_u32(checksum(bytes.slice_from(0)));
+        bytes.push_all_move(writer.unwrap());
+        bytes
+    }
+    fn encode_fields(&self) -> ~[u8] {
+        // the writer has the exact size of the record, so the writes can't overflow
+        match *self {
+            PageRecord(page, ref bytes) => {
+                let mut writer = PageWriter::new(1 + 2 * 8 + bytes.len());
+                writer.write_u8(PAGE_RECORD);
+                writer.write_u64(page as u64);
+                writer.write_u64(bytes.len() as u64);
+                writer.write_bytes(bytes.slice_from(0));
+                writer.unwrap()
+            }
+            FreeRecord(page, next) => {
+                let mut writer = PageWriter::new(1 + 2 * 8);
+                writer.write_u8(FREE_RECORD);
+                writer.write_u64(page as u64);
+                writer.write_u64(next as u64);
+                writer.unwrap()
+            }
+            HeaderRecord(nb_pages, root, free) => {
+                let mut writer = PageWriter::new(1 + 3 * 8);
+                writer.write_u8(HEADER_RECORD);
+                writer.write_u64(nb_pages as u64);
+                writer.write_u64(root as u64);
+                writer.write_u64(free as u64);
+                writer.unwrap()
+            }
+            GroupRecord(ref pages) => {
+                let mut size = 1 + 8;
+                for &(_, ref bytes) in pages.iter() {
+                    size += 2 * 8 + bytes.len();
+                }
+                let mut writer = PageWriter::new(size);
+                writer.write_u8(GROUP_RECORD);
+                writer.write_u64(pages.len() as u64);
+                for &(page, ref bytes) in pages.iter() {
+                    writer.write_u64(page as u64);
+                    writer.write_u64(bytes.len() as u64);
+                    writer.write_bytes(bytes.slice_from(0));
+                }
+                writer.unwrap()
+            }
+        }
+    }
+    fn decode(reader: &mut PageReader) -> Result<Record, CodecError> {
+        let tag = try!(reader.read_u8());
+        let first = try!(reader.read_u64()) as uint;
+        if tag == GROUP_RECORD {
+            let mut pages = ~[];
+            for _ in range(0, first) {
+                let page = try!(reader.read_u64()) as uint;
+                let length = try!(reader.read_u64()) as uint;
+                pages.push((page, try!(reader.read_bytes(length))));
+            }
+            return Ok(GroupRecord(pages));
+        }
+        let second = try!(reader.read_u64()) as uint;
+        if tag == PAGE_RECORD {
+            Ok(PageRecord(first, try!(reader.read_bytes(second))))
+        } else if tag == FREE_RECORD {
+            Ok(FreeRecord(first, second))
+        } else if tag == HEADER_RECORD {
+            Ok(HeaderRecord(first, second, try!(reader.read_u64()) as uint))
+        } else {
+            Err(InvalidTag(tag))
+        }
+    }
+}
+
+// prefixes the content of a page with its checksum
+fn seal(content: ~[u8]) -> ~[u8] {
+    let mut writer = PageWriter::new(CHECKSUM_SIZE);
+    writer.write_u32(checksum(content.slice_from(0)));
+    let mut image = writer.unwrap();
+    image.push_all_move(content);
+    image
+}
+
+// returns the content of a page, if it matches the checksum in front of it
+fn unseal(page: uint, image: &[u8]) -> Result<~[u8], PageError<uint>> {
+    let mut reader = PageReader::new(image);
+    let expected = match reader.read_u32() {
+        Ok(expected) => expected,
+        Err(_) => return Err(ChecksumMismatch(page, 0, checksum([])))
+    };
+    let content = image.slice_from(CHECKSUM_SIZE);
+    let actual = checksum(content);
+    if actual == expected {
+        Ok(content.to_owned())
+    } else {
+        Err(ChecksumMismatch(page, expected, actual))
+    }
+}
+
+// the write-ahead log lives next to the data file
+fn log_path(path: &Path) -> Path {
+    path.with_filetype("wal")
+}
+
+fn create_file(path: &Path) -> FileStream {
+    match open(path, CreateOrTruncate, ReadWrite) {
+        Some(file) => file,
+        None => fail!("could not create {}", path.to_str())
+    }
+}
+
+// the stream has no descriptor, so the file is opened again to flush it to the disk
+fn sync_file(path: &Path) {
+    let synced = do path.with_c_str |c_path| {
+        unsafe {
+            let fd = libc::open(c_path, libc::O_RDWR, 0);
+            fd >= 0 && libc::fsync(fd) == 0 && libc::close(fd) == 0
+        }
+    };
+    if !synced {
+        fail!("could not sync {}", path.to_str());
+    }
+}
+
+// returns None, if the file doesn't exist
+fn open_file(path: &Path) -> Option<FileStream> {
+    let mut failed = false;
+    let file = do io_error::cond.trap(|_| failed = true).inside {
+        open(path, Open, ReadWrite)
+    };
+    if failed { None } else { file }
+}
+
+struct Log {
+    path: Path,
+    file: FileStream,
+    // the size in bytes and the number of records
+    size: uint,
+    nb_records: uint
+}
+
+impl Log {
+    // reads the records of an existing log. A crash may have torn the last record, the log
+    // ends before the first record, that is incomplete or doesn't match its checksum.
+    fn open(path: Path, file: FileStream) -> (Log, ~[Record]) {
+        let mut file = file;
+        file.seek(0, SeekSet);
+        let bytes = file.read_to_end();
+        let mut records = ~[];
+        let mut size = 0;
+        {
+            let mut reader = PageReader::new(bytes.slice_from(0));
+            loop {
+                let start = bytes.len() - reader.remaining();
+                let record = match Record::decode(&mut reader) {
+                    Ok(record) => record,
+                    Err(_) => break
+                };
+                let end = bytes.len() - reader.remaining();
+                let valid = match reader.read_u32() {
+                    Ok(expected) => expected == checksum(bytes.slice(start, end)),
+                    Err(_) => false
+                };
+                if !valid {
+                    break;
+                }
+                records.push(record);
+                size = bytes.len() - reader.remaining();
+            }
+        }
+        let nb_records = records.len();
+        (Log { path: path, file: file, size: size, nb_records: nb_records }, records)
+    }
+}
+
+// Every write to the data file and the log goes through here. The tests simulate a crash
+// with `left`: the write at the crash point is torn and every later write is lost.
+// So the tests cover torn writes and crashes between two writes, but they assume that the
+// writes reach the disk in the order they are made. That the disk may reorder the writes
+// between two syncs is not covered, it is handled by the syncs of `checkpoint`.
+struct Writes {
+    count: uint,
+    left: Option<uint>
+}
+
+impl Writes {
+    // returns the number of bytes of a write of `len` bytes, that reach the disk
+    fn next(&mut self, len: uint) -> uint {
+        self.count += 1;
+        match self.left {
+            None => len,
+            Some(0) => 0,
+            Some(1) => {
+                self.left = Some(0);
+                len / 2
+            }
+            Some(left) => {
+                self.left = Some(left - 1);
+                len
+            }
+        }
+    }
+    fn write_at(&mut self, file: &mut FileStream, offset: uint, bytes: &[u8]) {
+        let len = self.next(bytes.len());
+        if len > 0 {
+            file.seek(offset as i64, SeekSet);
+            file.write(bytes.slice(0, len));
+            file.flush();
+        }
+    }
+    // empties the log. A torn truncation leaves the log as it is.
+    fn truncate(&mut self, log: &mut Log) {
+        if self.next(1) == 1 {
+            log.file = create_file(&log.path);
+            log.size = 0;
+            log.nb_records = 0;
+        }
+    }
+}
+
+struct PageFile {
+    path: Path,
+    file: FileStream,
+    log: Log,
+    header: Header,
+    // the pages, that were logged since the last checkpoint
+    logged: HashMap<uint, Page>,
+    // the versions are not stored, no reader outlives the process
+    versions: HashMap<uint, uint>,
+    writes: Writes
+}
+
+impl PageFile {
+    fn seek(&mut self, page: uint) {
+        self.file.seek((page * self.header.page_size) as i64, SeekSet);
+    }
+    // the header page is shorter than the other pages, its size is not known before it is read
+    fn image_size(&self, page: uint) -> uint {
+        if page == HEADER_PAGE {
+            CHECKSUM_SIZE + HEADER_SIZE
+        } else {
+            self.header.page_size
+        }
+    }
+    // reads the page from the data file and checks its checksum
+    fn read_image(&mut self, page: uint) -> Result<~[u8], PageError<uint>> {
+        let size = self.image_size(page);
+        self.seek(page);
+        let image = self.file.read_bytes(size);
+        unseal(page, image.slice_from(0))
+    }
+    fn write_header(&mut self) {
+        // the writer has the exact size of the header, so the writes can't overflow
+        let mut writer = PageWriter::new(HEADER_SIZE);
+        writer.write_u64(MAGIC);
+        writer.write_u64(self.header.page_size as u64);
+        writer.write_u64(self.header.max_size as u64);
+        writer.write_u64(self.header.max_leaf_bytes as u64);
+        writer.write_u64(self.header.max_inode_bytes as u64);
+        writer.write_u64(self.header.nb_pages as u64);
+        writer.write_u64(self.header.root as u64);
+        writer.write_u64(self.header.free as u64);
+        let offset = HEADER_PAGE * self.header.page_size;
+        self.writes.write_at(&mut self.file, offset, seal(writer.unwrap()).slice_from(0));
+    }
+    // returns None, if the file doesn't start with a header or it is damaged
+    fn read_header(file: &mut FileStream) -> Option<Header> {
+        file.seek(0, SeekSet);
+        let image = file.read_bytes(CHECKSUM_SIZE + HEADER_SIZE);
+        let content = match unseal(HEADER_PAGE, image.slice_from(0)) {
+            Ok(content) => content,
+            Err(_) => return None
+        };
+        let mut reader = PageReader::new(content.slice_from(0));
+        // the content has the size of the header, so the reads can't fail
+        if reader.read_u64().unwrap() != MAGIC {
+            return None;
+        }
+        Some(Header {
+            page_size: reader.read_u64().unwrap() as uint,
+            max_size: reader.read_u64().unwrap() as uint,
+            max_leaf_bytes: reader.read_u64().unwrap() as uint,
+            max_inode_bytes: reader.read_u64().unwrap() as uint,
+            nb_pages: reader.read_u64().unwrap() as uint,
+            root: reader.read_u64().unwrap() as uint,
+            free: reader.read_u64().unwrap() as uint
+        })
+    }
+    // writes the record to the log and applies it. The data file sees the change
+    // with the next checkpoint.
+    fn log(&mut self, record: Record) {
+        let bytes = record.encode();
+        self.writes.write_at(&mut self.log.file, self.log.size, bytes.slice_from(0));
+        self.log.size += bytes.len();
+        self.log.nb_records += 1;
+        self.apply(record);
+        if self.log.nb_records >= CHECKPOINT_INTERVAL {
+            self.checkpoint();
+        }
+    }
+    fn apply(&mut self, record: Record) {
+        match record {
+            PageRecord(page, bytes) => {
+                self.logged.insert(page, Used(bytes));
+            }
+            FreeRecord(page, next) => {
+                self.logged.insert(page, Free(next));
+            }
+            GroupRecord(pages) => {
+                for (page, bytes) in pages.move_iter() {
+                    self.logged.insert(page, Used(bytes));
+                }
+            }
+            HeaderRecord(nb_pages, root, free) => {
+                // the appended pages are empty
+                for page in range(self.header.nb_pages, nb_pages) {
+                    self.logged.insert(page, Used(~[]));
+                }
+                self.header.nb_pages = nb_pages;
+                self.header.root = root;
+                self.header.free = free;
+            }
+        }
+    }
+    // writes the logged pages and the header into the data file and empties the log.
+    // The records hold whole pages, so a crash before the log is emptied is repaired by
+    // replaying it again.
+    fn checkpoint(&mut self) {
+        // the records must be on the disk, before the pages they repair are overwritten
+        sync_file(&self.log.path);
+        let page_size = self.header.page_size;
+        for (page, content) in self.logged.iter() {
+            // the length was checked, before the page was logged
+            let mut writer = PageWriter::new(page_size - CHECKSUM_SIZE);
+            match *content {
+                Used(ref bytes) => {
+                    writer.write_u64(bytes.len() as u64);
+                    writer.write_bytes(bytes.slice_from(0));
+                }
+                Free(next) => {
+                    writer.write_u64(0);
+                    writer.write_u64(next as u64);
+                }
+            }
+            let image = seal(writer.unwrap());
+            self.writes.write_at(&mut self.file, *page * page_size, image.slice_from(0));
+        }
+        self.write_header();
+        // the pages must be on the disk, before the log, that repairs them, is emptied
+        sync_file(&self.path);
+        self.writes.truncate(&mut self.log);
+        sync_file(&self.log.path);
+        self.logged.clear();
+    }
+    // takes the first page of the free list or appends a page to the file
+    fn allocate(&mut self) -> uint {
+        let (nb_pages, root, free) = (self.header.nb_pages, self.header.root, self.header.free);
+        if free == 0 {
+            self.log(HeaderRecord(nb_pages + 1, root, 0));
+            return nb_pages;
+        }
+        let next = self.next_free(free);
+        // the page stays marked as free until it is written, a crash before loses it
+        self.log(HeaderRecord(nb_pages, root, next));
+        free
+    }
+    // puts the page in front of the free list. The page is marked as free before the
+    // header points to it, so a crash between never lets the free list reach a used page.
+    fn free(&mut self, page: uint) {
+        assert!(self.exists(page), format!("page {} was not allocated", page));
+        let (nb_pages, root, free) = (self.header.nb_pages, self.header.root, self.header.free);
+        self.log(FreeRecord(page, free));
+        self.log(HeaderRecord(nb_pages, root, page));
+        *self.versions.find_or_insert(page, 0) += 1;
+    }
+    // the page after `page` on the free list. A damaged free list can't be repaired,
+    // the pages behind the damage are lost.
+    fn next_free(&mut self, page: uint) -> uint {
+        match self.page(page) {
+            Ok(Free(next)) => next,
+            Ok(Used(_)) => fail!("page {} is on the free list, but it is used", page),
+            Err(err) => fail!(err.to_str())
+        }
+    }
+    fn nb_free(&mut self) -> uint {
+        let mut nb_free = 0;
+        let mut next = self.header.free;
+        while next != 0 {
+            nb_free += 1;
+            next = self.next_free(next);
+        }
+        nb_free
+    }
+    // puts the pages, that are neither used nor on the free list, back onto the free list.
+    // A crash loses the pages, that were allocated but not written yet, and the freed pages,
+    // whose header record was lost.
+    fn reclaim_lost_pages(&mut self) {
+        let mut listed = HashSet::new();
+        let mut next = self.header.free;
+        while next != 0 {
+            listed.insert(next);
+            next = self.next_free(next);
+        }
+        for page in range(HEADER_PAGE + 1, self.header.nb_pages) {
+            if listed.contains(&page) {
+                continue;
+            }
+            let lost = match self.page(page) {
+                Ok(Used(bytes)) => bytes.is_empty(),
+                Ok(Free(_)) => true,
+                // a damaged page is reported by `scrub`
+                Err(_) => false
+            };
+            if lost {
+                self.free(page);
+            }
+        }
+    }
+    fn exists(&self, page: uint) -> bool {
+        page != HEADER_PAGE && page < self.header.nb_pages
+    }
+    // the content of an existing page
+    fn page(&mut self, page: uint) -> Result<Page, PageError<uint>> {
+        match self.logged.find(&page) {
+            Some(content) => return Ok(content.clone()),
+            None => {}
+        }
+        let content = try!(self.read_image(page));
+        // the content matches its checksum, so it was written by `checkpoint`
+        let mut reader = PageReader::new(content.slice_from(0));
+        let length = reader.read_u64().unwrap() as uint;
+        if length == 0 {
+            Ok(Free(reader.read_u64().unwrap() as uint))
+        } else {
+            Ok(Used(reader.read_bytes(length).unwrap()))
+        }
+    }
+    fn read_page(&mut self, page: uint) -> Result<Option<~[u8]>, PageError<uint>> {
+        if !self.exists(page) {
+            return Ok(None);
+        }
+        match try!(self.page(page)) {
+            Used(bytes) => Ok(if bytes.is_empty() { None } else { Some(bytes) }),
+            Free(_) => Ok(None)
+        }
+    }
+    // checks the pages in the data file, that have no newer copy in the log
+    fn scrub(&mut self) -> ~[PageError<uint>] {
+        let mut damaged = ~[];
+        for page in range(HEADER_PAGE, self.header.nb_pages) {
+            if self.logged.contains_key(&page) {
+                continue;
+            }
+            match self.read_image(page) {
+                Ok(_) => {}
+                Err(err) => damaged.push(err)
+            }
+        }
+        damaged
+    }
+    fn check_page(&self, page: uint, bytes: &[u8]) {
+        assert!(self.exists(page), format!("page {} was not allocated", page));
+        let page_size = self.header.page_size;
+        assert!(CHECKSUM_SIZE + LENGTH_SIZE + bytes.len() <= page_size,
+                format!("a node of {} bytes doesn't fit into a page of {} bytes",
+                        bytes.len(), page_size));
+    }
+    fn write_page(&mut self, page: uint, bytes: ~[u8]) {
+        self.check_page(page, bytes.slice_from(0));
+        self.log(PageRecord(page, bytes));
+        *self.versions.find_or_insert(page, 0) += 1;
+    }
+    // the pages are logged in one record, so a crash keeps all or none of them
+    fn write_pages(&mut self, pages: ~[(uint, ~[u8])]) {
+        for &(page, ref bytes) in pages.iter() {
+            self.check_page(page, bytes.slice_from(0));
+        }
+        let ids: ~[uint] = pages.iter().map(|&(page, _)| page).collect();
+        self.log(GroupRecord(pages));
+        for page in ids.iter() {
+            *self.versions.find_or_insert(*page, 0) += 1;
+        }
+    }
+    fn version(&self, page: uint) -> uint {
+        match self.versions.find(&page) {
+            Some(version) => *version,
+            None => 0
+        }
+    }
+}
+
+impl Drop for PageFile {
+    fn drop(&mut self) {
+        self.checkpoint();
+    }
+}
+
+/// Stores the nodes in a file. The page `n` starts at the offset `n * page_size`, the page 0
+/// holds a header with the page size, the max node sizes of the tree and the root, so the
+/// tree can be opened again by another process.
+/// The codec turns the nodes into pages of `max_size` bytes, a page of the file has room for
+/// a checksum, the encoded node and its length. A page, that doesn't match its checksum,
+/// is returned as an error by `read`, `scrub` checks all pages.
+/// The freed pages form a list, that starts in the header and runs through the pages.
+/// `new_page` takes the first of them, before the file grows.
+///
+/// Every change is appended to a write-ahead log next to the file, before it reaches the
+/// file with the next checkpoint. `open` replays the log, so a crash loses at most the
+/// changes, whose records were not completely written. The tree writes the pages of a split
+/// from right to left, the two pages of a merge in one record and the new root before the
+/// root pointer, so every prefix of the log is a consistent tree. A crash may leave a new
+/// page, that is not linked yet, or a dead page, that is not freed yet. The pages, that
+/// were allocated or freed, but didn't reach the free list or a node, are freed by `open`.
+/// A freed page reaches the free list, when every task, that entered before it was freed,
+/// has exited.
+pub struct FileStorage<C> {
+    priv file: MutexArc<PageFile>,
+    priv codec: C,
+    priv epochs: Epochs<uint>
+}
+
+impl<C: PageSize + Freeze + Send> FileStorage<C> {
+    /// Creates a new file for the pages of the codec. An existing file is truncated.
+    pub fn create(path: &Path, codec: C) -> FileStorage<C> {
+        let max_size = codec.page_size();
+        let page_size = cmp::max(CHECKSUM_SIZE + LENGTH_SIZE + max_size,
+                                 CHECKSUM_SIZE + HEADER_SIZE);
+        let wal_path = log_path(path);
+        let log_file = create_file(&wal_path);
+        let mut page_file = PageFile {
+            path: path.clone(),
+            file: create_file(path),
+            log: Log { path: wal_path, file: log_file, size: 0, nb_records: 0 },
+            header: Header {
+                page_size: page_size,
+                max_size: max_size,
+                max_leaf_bytes: 0,
+                max_inode_bytes: 0,
+                nb_pages: 1,
+                root: 0,
+                free: 0
+            },
+            logged: HashMap::new(),
+            versions: HashMap::new(),
+            writes: Writes { count: 0, left: None }
+        };
+        page_file.write_header();
+        FileStorage { file: MutexArc::new(page_file), codec: codec, epochs: Epochs::new() }
+    }
+    /// Opens a file, that was created by `create` with a codec of the same page size,
+    /// and replays its log. Returns None, if the file doesn't exist, has no header, its
+    /// header is damaged or it was created for another page size.
+    pub fn open(path: &Path, codec: C) -> Option<FileStorage<C>> {
+        let mut file = match open_file(path) {
+            Some(file) => file,
+            None => return None
+        };
+        let header = match PageFile::read_header(&mut file) {
+            Some(header) => header,
+            None => return None
+        };
+        if header.max_size != codec.page_size() {
+            return None;
+        }
+        let wal_path = log_path(path);
+        let (log, records) = match open_file(&wal_path) {
+            Some(log_file) => Log::open(wal_path, log_file),
+            None => {
+                let log_file = create_file(&wal_path);
+                (Log { path: wal_path, file: log_file, size: 0, nb_records: 0 }, ~[])
+            }
+        };
+        let mut page_file = PageFile {
+            path: path.clone(),
+            file: file,
+            log: log,
+            header: header,
+            logged: HashMap::new(),
+            versions: HashMap::new(),
+            writes: Writes { count: 0, left: None }
+        };
+        for record in records.move_iter() {
+            page_file.apply(record);
+        }
+        page_file.reclaim_lost_pages();
+        page_file.checkpoint();
+        Some(FileStorage { file: MutexArc::new(page_file), codec: codec, epochs: Epochs::new() })
+    }
+    // a file stream isn't Freeze, so the safe `access` is not available.
+    // No other MutexArc is accessed inside, so it can't deadlock.
+    fn access<U>(&self, f: &fn(&mut PageFile) -> U) -> U {
+        unsafe { self.file.unsafe_access(f) }
+    }
+    pub fn page_size(&self) -> uint {
+        do self.access |file| {
+            file.header.page_size
+        }
+    }
+    /// the size of the pages of the codec, the max node size of the tree
+    pub fn max_size(&self) -> uint {
+        do self.access |file| {
+            file.header.max_size
+        }
+    }
+    /// the number of pages in the file, including the header
+    pub fn nb_pages(&self) -> uint {
+        do self.access |file| {
+            file.header.nb_pages
+        }
+    }
+    /// the number of pages on the free list
+    pub fn nb_free(&self) -> uint {
+        do self.access |file| {
+            file.nb_free()
+        }
+    }
+    /// the number of records in the log since the last checkpoint
+    pub fn nb_logged(&self) -> uint {
+        do self.access |file| {
+            file.log.nb_records
+        }
+    }
+    /// Writes the logged changes into the file and empties the log.
+    /// It happens on its own every 1024 records and when the storage is dropped.
+    pub fn checkpoint(&self) {
+        do self.access |file| {
+            file.checkpoint()
+        }
+    }
+    // the number of writes to the file and the log
+    #[cfg(test)]
+    fn nb_writes(&self) -> uint {
+        do self.access |file| {
+            file.writes.count
+        }
+    }
+    // simulates a crash: `nb_writes` more writes reach the disk, the next one is torn and
+    // the later ones are lost
+    #[cfg(test)]
+    fn crash_after(&self, nb_writes: uint) {
+        do self.access |file| {
+            file.writes.left = Some(nb_writes + 1);
+        }
+    }
+    // puts the freed pages, that no task can see anymore, on the free list
+    fn release(&self) {
+        let released = self.epochs.released();
+        if released.is_empty() {
+            return;
+        }
+        do self.access |file| {
+            for id in released.iter() {
+                file.free(*id);
+            }
+        }
+    }
+}
+
+impl<C: PageSize + Freeze + Send> PageReaders for FileStorage<C> {
+    fn enter(&self) {
+        self.epochs.enter();
+    }
+    fn exit(&self) {
+        self.epochs.exit();
+        self.release();
+    }
+}
+
+fn encode<N, C: PageCodec<N>>(codec: &C, id: uint, node: &N) -> ~[u8] {
+    match codec.encode(node) {
+        Ok(bytes) => bytes,
+        Err(err) => fail!("the node of page {} can't be encoded: {}", id, err.to_str())
+    }
+}
+
+fn decode<N, C: PageCodec<N>>(codec: &C, id: uint, page: ~[u8]) -> Result<N, PageError<uint>> {
+    match codec.decode(page) {
+        Ok(node) => Ok(node),
+        Err(err) => Err(Malformed(id, err))
+    }
+}
+
+impl<N, C: PageCodec<N>> StorageManager<uint, N> for FileStorage<C> {
+    fn new_page(&self) -> uint {
+        do self.access |file| {
+            file.allocate()
+        }
+    }
+    fn read(&self, id: &uint) -> Result<Option<N>, PageError<uint>> {
+        let bytes = do self.access |file| {
+            file.read_page(*id)
+        };
+        match try!(bytes) {
+            Some(bytes) => Ok(Some(try!(decode(&self.codec, *id, bytes)))),
+            None => Ok(None)
+        }
+    }
+    fn read_versioned(&self, id: &uint) -> Result<Option<(uint, N)>, PageError<uint>> {
+        let page = do self.access |file| {
+            match file.read_page(*id) {
+                Ok(Some(bytes)) => Ok(Some((file.version(*id), bytes))),
+                Ok(None) => Ok(None),
+                Err(err) => Err(err)
+            }
+        };
+        match try!(page) {
+            Some((version, bytes)) => Ok(Some((version, try!(decode(&self.codec, *id, bytes))))),
+            None => Ok(None)
+        }
+    }
+    fn write(&self, id: &uint, node: N) {
+        let bytes = encode(&self.codec, *id, &node);
+        do self.access |file| {
+            file.write_page(*id, bytes.clone());
+        }
+    }
+    fn write_all(&self, nodes: ~[(uint, N)]) {
+        let mut pages = Some(nodes.iter().map(|&(id, ref node)| (id, encode(&self.codec, id, node)))
+                                  .collect::<~[(uint, ~[u8])]>());
+        do self.access |file| {
+            file.write_pages(pages.take_unwrap());
+        }
+    }
+    fn free_page(&self, id: &uint) {
+        self.epochs.retire(*id);
+        self.release();
+    }
+    fn version(&self, id: &uint) -> uint {
+        do self.access |file| {
+            file.version(*id)
+        }
+    }
+    fn root(&self) -> Option<uint> {
+        do self.access |file| {
+            match file.header.root {
+                0 => None,
+                root => Some(root)
+            }
+        }
+    }
+    fn set_root(&self, root: &uint) {
+        do self.access |file| {
+            let (nb_pages, free) = (file.header.nb_pages, file.header.free);
+            file.log(HeaderRecord(nb_pages, *root, free));
+        }
+    }
+    fn node_sizes(&self) -> Option<(uint, uint)> {
+        do self.access |file| {
+            match (file.header.max_leaf_bytes, file.header.max_inode_bytes) {
+                (0, 0) => None,
+                sizes => Some(sizes)
+            }
+        }
+    }
+    // the sizes are only stored once, the checkpoint writes them with the header
+    fn set_node_sizes(&self, max_leaf_bytes: uint, max_inode_bytes: uint) {
+        do self.access |file| {
+            file.header.max_leaf_bytes = max_leaf_bytes;
+            file.header.max_inode_bytes = max_inode_bytes;
+            file.checkpoint();
+        }
+    }
+    fn scrub(&self) -> ~[PageError<uint>] {
+        do self.access |file| {
+            file.scrub()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileStorage, CHECKPOINT_INTERVAL, log_path};
+    use storage::{StorageManager, PageError, ChecksumMismatch, Malformed};
+    use blinktree::blinktree::{BTree, TreeError, NoRoot, WrongNodeSizes, NodeSizeTooSmall};
+    use blinktree::blink_ops::DefaultBLinkOps;
+    use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_LEAF};
+    use buffer_pool::BufferPool;
+    use lock::SimpleLockManager;
+    use node::{Node, Leaf};
+    use page_codec::{NodeCodec, InvalidLength};
+    use persistent::{Map, IteratableMap};
+    use statistics::{StatisticsManager, AtomicStatistics};
+    use std::os;
+    use std::path::Path;
+    use std::rt::io::{Writer, Seek, SeekSet, Open, ReadWrite};
+    use std::rt::io::extensions::ReaderUtil;
+    use std::rt::io::file::open;
+    use extra::time::precise_time_ns;
+
+    type UintNode = Node<DefaultBLinkNode<uint, uint, uint>, DefaultBLinkNode<uint, uint, uint>>;
+    type FileBTree = BTree<uint, FileStorage<NodeCodec>, SimpleLockManager<uint>,
+                           AtomicStatistics,
+                           DefaultBLinkOps<uint, uint, uint,
+                               DefaultBLinkNode<uint, uint, uint>,
+                               DefaultBLinkNode<uint, uint, uint>>>;
+
+    fn temp_path(name: &str) -> Path {
+        os::tmpdir().push(format!("libtrees-{}-{}.db", name, precise_time_ns()))
+    }
+
+    fn remove_files(path: &Path) {
+        os::remove_file(path);
+        os::remove_file(&log_path(path));
+    }
+
+    // flips a bit of the byte at `offset`, like a bad disk would
+    fn damage(path: &Path, offset: uint) {
+        let mut file = open(path, Open, ReadWrite).unwrap();
+        file.seek(offset as i64, SeekSet);
+        let byte = file.read_bytes(1)[0];
+        file.seek(offset as i64, SeekSet);
+        file.write([byte ^ 1]);
+    }
+
+    fn leaf(ptr: uint, keys: ~[uint]) -> UintNode {
+        let values = keys.clone();
+        Leaf(PhysicalNode::new(T_LEAF, ptr, None, keys, values))
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = temp_path("write_and_read");
+        let storage = FileStorage::create(&path, NodeCodec::new(128));
+        let page = storage.new_page();
+        assert!(page == 1);
+        assert!(storage.read(&page) == Ok(None::<UintNode>));
+        storage.write(&page, leaf(page, ~[1]));
+        storage.write(&page, leaf(page, ~[1, 2]));
+        assert!(storage.read_versioned(&page) == Ok(Some((2, leaf(page, ~[1, 2])))));
+        storage.free_page(&page);
+        assert!(storage.read(&page) == Ok(None::<UintNode>));
+        assert!(storage.version(&page) == 3);
+        assert!(storage.read(&5) == Ok(None::<UintNode>));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_malformed_page() {
+        let path = temp_path("malformed");
+        let storage = FileStorage::create(&path, NodeCodec::new(128));
+        let page = storage.new_page();
+        // the bytes match their checksum, but they are not a node
+        do storage.access |file| {
+            file.write_page(page, ~[0u8, ..8]);
+        }
+        let result: Result<Option<UintNode>, PageError<uint>> = storage.read(&page);
+        assert!(result == Err(Malformed(page, InvalidLength(8))));
+        let result: Result<Option<(uint, UintNode)>, PageError<uint>> =
+            storage.read_versioned(&page);
+        assert!(result == Err(Malformed(page, InvalidLength(8))));
+        remove_files(&path);
+    }
+
+    #[test] #[should_fail]
+    fn test_node_too_big() {
+        let path = temp_path("too_big");
+        let storage = FileStorage::create(&path, NodeCodec::new(64));
+        let page = storage.new_page();
+        remove_files(&path);
+        storage.write(&page, leaf(page, ~[1, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = temp_path("reopen");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let (first, second) = (storage.new_page(), storage.new_page());
+            storage.write(&first, leaf(first, ~[1, 2, 3]));
+            storage.write(&second, leaf(second, ~[4]));
+            storage.set_root(&second);
+        }
+        assert!(FileStorage::open(&path, NodeCodec::new(256)).is_none());
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.max_size() == 128 && storage.page_size() == 140);
+        assert!(storage.nb_pages() == 3);
+        assert!(storage.root() == Some(2u));
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[1, 2, 3]))));
+        assert!(storage.read(&2) == Ok(Some(leaf(2, ~[4]))));
+        // new pages are appended after the existing ones
+        assert!(storage.new_page() == 3);
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        assert!(FileStorage::open(&temp_path("missing"), NodeCodec::new(128)).is_none());
+    }
+
+    #[test]
+    fn test_tree_survives_reopen() {
+        let path = temp_path("tree");
+        let page_size = BTree::page_size_for(4);
+        {
+            let btree: FileBTree = BTree::new(FileStorage::create(&path, NodeCodec::new(page_size)),
+                                              SimpleLockManager::new(), AtomicStatistics::new(),
+                                              DefaultBLinkOps, page_size).unwrap();
+            for i in range(0u, 500) {
+                btree.insert(i, i * 2);
+            }
+            for i in range(0u, 100) {
+                btree.remove(&(i * 5));
+            }
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(page_size)).unwrap();
+        let btree: FileBTree = BTree::open(storage, SimpleLockManager::new(),
+                                           AtomicStatistics::new(), DefaultBLinkOps,
+                                           page_size).unwrap();
+        assert!(btree.len() == 400);
+        assert!(btree.height() > 1);
+        for i in range(0u, 500) {
+            let expected = if i % 5 == 0 { None } else { Some(i * 2) };
+            assert!(btree.find(&i) == expected);
+        }
+        btree.insert(1000, 1);
+        assert!(btree.find(&1000) == Some(1));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_open_checks_node_sizes() {
+        let path = temp_path("node_sizes");
+        let (leaf_size, inode_size) = (BTree::page_size_for(4), BTree::page_size_for(6));
+        let result: Result<FileBTree, TreeError> =
+            BTree::open(FileStorage::create(&path, NodeCodec::new(inode_size)),
+                        SimpleLockManager::new(), AtomicStatistics::new(), DefaultBLinkOps,
+                        leaf_size);
+        match result {
+            Err(NoRoot) => {}
+            _ => fail!("a new file has no root")
+        }
+        {
+            let btree: FileBTree =
+                BTree::new_with_node_sizes(FileStorage::create(&path, NodeCodec::new(inode_size)),
+                                           SimpleLockManager::new(), AtomicStatistics::new(),
+                                           DefaultBLinkOps, leaf_size, inode_size).unwrap();
+            for i in range(0u, 200) {
+                btree.insert(i, i);
+            }
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(inode_size)).unwrap();
+        let result: Result<FileBTree, TreeError> =
+            BTree::open(storage, SimpleLockManager::new(), AtomicStatistics::new(),
+                        DefaultBLinkOps, leaf_size);
+        match result {
+            Err(WrongNodeSizes(leaf_bytes, inode_bytes)) =>
+                assert!(leaf_bytes == leaf_size && inode_bytes == inode_size),
+            _ => fail!("the tree was opened with the wrong inode size")
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(inode_size)).unwrap();
+        let result: Result<FileBTree, TreeError> =
+            BTree::open(storage, SimpleLockManager::new(), AtomicStatistics::new(),
+                        DefaultBLinkOps, BTree::page_size_for(0));
+        match result {
+            Err(NodeSizeTooSmall(_)) => {}
+            _ => fail!("an empty node leaves no room for an entry")
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(inode_size)).unwrap();
+        let btree: FileBTree = BTree::open_with_node_sizes(storage, SimpleLockManager::new(),
+                                                           AtomicStatistics::new(),
+                                                           DefaultBLinkOps, leaf_size,
+                                                           inode_size).unwrap();
+        assert!(btree.len() == 200);
+        assert!(btree.max_inode_bytes == inode_size);
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_free_pages_are_reused() {
+        let path = temp_path("free_pages");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let pages = ~[storage.new_page(), storage.new_page(), storage.new_page()];
+            for page in pages.iter() {
+                storage.write(page, leaf(*page, ~[*page]));
+            }
+            storage.free_page(&pages[0]);
+            storage.free_page(&pages[2]);
+            assert!(storage.nb_free() == 2);
+            assert!(storage.read(&pages[2]) == Ok(None::<UintNode>));
+            storage.checkpoint();
+        }
+        // the free list survives the reopen
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.nb_free() == 2);
+        assert!(storage.new_page() == 3);
+        assert!(storage.new_page() == 1);
+        assert!(storage.nb_free() == 0);
+        assert!(storage.new_page() == 4);
+        assert!(storage.nb_pages() == 5);
+        assert!(storage.read(&2) == Ok(Some(leaf(2, ~[2]))));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_tree_reuses_merged_pages() {
+        let path = temp_path("reuse");
+        {
+            let btree = new_tree(&path, 1000);
+            for i in range(0u, 1000) {
+                if i % 4 != 0 {
+                    btree.remove(&i);
+                }
+            }
+            assert!(btree.storage.nb_free() > 0);
+        }
+        let btree = reopen_tree(&path);
+        let nb_pages = btree.storage.nb_pages();
+        for i in range(0u, 1000) {
+            btree.insert(i, i);
+        }
+        // the splits take the pages of the merges, before the file grows
+        assert!(btree.storage.nb_pages() == nb_pages || btree.storage.nb_free() == 0);
+        for i in range(0u, 1000) {
+            assert!(btree.find(&i) == Some(i));
+        }
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = temp_path("checkpoint");
+        let storage = FileStorage::create(&path, NodeCodec::new(128));
+        let page = storage.new_page();
+        storage.write(&page, leaf(page, ~[1]));
+        assert!(storage.nb_logged() == 2);
+        storage.checkpoint();
+        assert!(storage.nb_logged() == 0);
+        assert!(storage.read(&page) == Ok(Some(leaf(page, ~[1]))));
+        for i in range(0, CHECKPOINT_INTERVAL - 1) {
+            storage.write(&page, leaf(page, ~[i]));
+        }
+        assert!(storage.nb_logged() == CHECKPOINT_INTERVAL - 1);
+        storage.write(&page, leaf(page, ~[2]));
+        assert!(storage.nb_logged() == 0);
+        assert!(storage.read(&page) == Ok(Some(leaf(page, ~[2]))));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_log_repairs_torn_page() {
+        let path = temp_path("torn_page");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let page = storage.new_page();
+            storage.write(&page, leaf(page, ~[1, 2, 3]));
+            storage.set_root(&page);
+            // the checkpoint of the drop tears the page and loses the header
+            storage.crash_after(0);
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.nb_pages() == 2);
+        assert!(storage.root() == Some(1u));
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[1, 2, 3]))));
+        assert!(storage.nb_logged() == 0);
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_torn_record_is_ignored() {
+        let path = temp_path("torn_record");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let page = storage.new_page();
+            storage.write(&page, leaf(page, ~[1]));
+            storage.crash_after(0);
+            storage.write(&page, leaf(page, ~[1, 2]));
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[1]))));
+        // the log continues after the replay
+        storage.write(&1, leaf(1, ~[3]));
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[3]))));
+        remove_files(&path);
+    }
+
+    fn new_tree(path: &Path, nb_keys: uint) -> FileBTree {
+        let page_size = BTree::page_size_for(4);
+        let btree: FileBTree = BTree::new(FileStorage::create(path, NodeCodec::new(page_size)),
+                                          SimpleLockManager::new(), AtomicStatistics::new(),
+                                          DefaultBLinkOps, page_size).unwrap();
+        for i in range(0, nb_keys) {
+            btree.insert(i, i);
+        }
+        btree.storage.checkpoint();
+        btree
+    }
+
+    fn reopen_tree(path: &Path) -> FileBTree {
+        let page_size = BTree::page_size_for(4);
+        let storage = FileStorage::open(path, NodeCodec::new(page_size)).unwrap();
+        BTree::open(storage, SimpleLockManager::new(), AtomicStatistics::new(), DefaultBLinkOps,
+                    page_size).unwrap()
+    }
+
+    // the number of keys, whose insertion grows the tree to `height`
+    fn keys_until_height(height: uint) -> uint {
+        let path = temp_path("height");
+        let mut nb_keys = 0;
+        {
+            let btree = new_tree(&path, 0);
+            while btree.height() < height {
+                btree.insert(nb_keys, nb_keys);
+                nb_keys += 1;
+            }
+        }
+        remove_files(&path);
+        nb_keys
+    }
+
+    // inserts the key `nb_keys - 1`, that splits the tree, into a tree of the smaller keys and
+    // crashes at every write of the insertion and of the checkpoint after it.
+    // The reopened tree must hold the smaller keys and stay usable.
+    fn crash_at_every_write(nb_keys: uint) {
+        let last = nb_keys - 1;
+        let path = temp_path("count_writes");
+        let nb_writes = {
+            let btree = new_tree(&path, last);
+            let before = btree.storage.nb_writes();
+            btree.insert(last, last);
+            btree.storage.checkpoint();
+            btree.storage.nb_writes() - before
+        };
+        remove_files(&path);
+        assert!(nb_writes > 3);
+
+        for crash in range(0, nb_writes) {
+            let name = format!("crash_{}", crash);
+            let path = temp_path(name.slice_from(0));
+            {
+                let btree = new_tree(&path, last);
+                btree.storage.crash_after(crash);
+                btree.insert(last, last);
+                btree.storage.checkpoint();
+            }
+            let btree = reopen_tree(&path);
+            for i in range(0, last) {
+                assert!(btree.find(&i) == Some(i));
+            }
+            let found = btree.find(&last);
+            assert!(found.is_none() || found == Some(last));
+            let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+            assert!(keys.len() == btree.len());
+            assert!(keys.len() == last || keys.len() == nb_keys);
+            for i in range(0, keys.len()) {
+                assert!(keys[i] == i);
+            }
+            for i in range(last, nb_keys + 50) {
+                btree.insert(i, i);
+            }
+            for i in range(0, nb_keys + 50) {
+                assert!(btree.find(&i) == Some(i));
+            }
+            remove_files(&path);
+        }
+    }
+
+    #[test]
+    fn test_crash_during_root_leaf_split() {
+        crash_at_every_write(keys_until_height(2));
+    }
+
+    #[test]
+    fn test_crash_during_inode_split() {
+        crash_at_every_write(keys_until_height(3));
+    }
+
+    type PoolBTree = BTree<uint, BufferPool<uint, UintNode, FileStorage<NodeCodec>>,
+                           SimpleLockManager<uint>, AtomicStatistics,
+                           DefaultBLinkOps<uint, uint, uint,
+                               DefaultBLinkNode<uint, uint, uint>,
+                               DefaultBLinkNode<uint, uint, uint>>>;
+
+    fn new_pool_tree(path: &Path, nb_keys: uint) -> PoolBTree {
+        let page_size = BTree::page_size_for(4);
+        let pool = BufferPool::new(FileStorage::create(path, NodeCodec::new(page_size)), 4);
+        let btree: PoolBTree = BTree::new(pool, SimpleLockManager::new(), AtomicStatistics::new(),
+                                          DefaultBLinkOps, page_size).unwrap();
+        for i in range(0, nb_keys) {
+            btree.insert(i, i);
+        }
+        btree.storage.flush();
+        btree.storage.inner().checkpoint();
+        btree
+    }
+
+    // the inserts split and the removals merge, while the pool evicts the pages
+    fn change_pool_tree(btree: &PoolBTree, from: uint, to: uint) {
+        for i in range(from, to) {
+            btree.insert(i, i);
+        }
+        for i in range(from, to) {
+            if i % 2 == 0 {
+                btree.remove(&i);
+            }
+        }
+        btree.storage.flush();
+        btree.storage.inner().checkpoint();
+    }
+
+    #[test]
+    fn test_crash_under_buffer_pool() {
+        let (nb_keys, more) = (20, 60);
+        let path = temp_path("count_pool");
+        let nb_writes = {
+            let btree = new_pool_tree(&path, nb_keys);
+            let before = btree.storage.inner().nb_writes();
+            change_pool_tree(&btree, nb_keys, nb_keys + more);
+            btree.storage.inner().nb_writes() - before
+        };
+        remove_files(&path);
+
+        for crash in range(0, nb_writes) {
+            let name = format!("pool_crash_{}", crash);
+            let path = temp_path(name.slice_from(0));
+            {
+                let btree = new_pool_tree(&path, nb_keys);
+                btree.storage.inner().crash_after(crash);
+                change_pool_tree(&btree, nb_keys, nb_keys + more);
+            }
+            let btree = reopen_tree(&path);
+            // the tree is consistent: every key is seen once and found by a search
+            let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+            assert!(keys.len() == btree.len());
+            for i in range(1, keys.len()) {
+                assert!(keys[i - 1] < keys[i], format!("unsorted keys: {}", keys.to_str()));
+            }
+            for key in keys.iter() {
+                assert!(btree.find(key) == Some(*key));
+            }
+            for i in range(0, nb_keys) {
+                assert!(btree.find(&i) == Some(i));
+            }
+            remove_files(&path);
+        }
+    }
+
+    #[test]
+    fn test_crash_during_merge() {
+        let nb_keys = 40;
+        // the keys are removed from the left, until a removal merges two leafs
+        let path = temp_path("count_merge");
+        let (last, nb_writes) = {
+            let btree = new_tree(&path, nb_keys);
+            let (mut key, mut nb_writes, mut merged) = (0, 0, false);
+            while !merged {
+                let (leafs, before) = (btree.statistics.leafs(), btree.storage.nb_writes());
+                btree.remove(&key);
+                btree.storage.checkpoint();
+                nb_writes = btree.storage.nb_writes() - before;
+                merged = btree.statistics.leafs() < leafs;
+                key += 1;
+            }
+            (key - 1, nb_writes)
+        };
+        remove_files(&path);
+        assert!(last + 1 < nb_keys);
+
+        for crash in range(0, nb_writes) {
+            let name = format!("merge_crash_{}", crash);
+            let path = temp_path(name.slice_from(0));
+            {
+                let btree = new_tree(&path, nb_keys);
+                for i in range(0, last) {
+                    btree.remove(&i);
+                }
+                btree.storage.checkpoint();
+                btree.storage.crash_after(crash);
+                btree.remove(&last);
+                btree.storage.checkpoint();
+            }
+            let btree = reopen_tree(&path);
+            let first = match btree.find(&last) {
+                Some(value) => {
+                    assert!(value == last);
+                    last
+                }
+                None => last + 1
+            };
+            // no key is lost or seen twice
+            let keys: ~[uint] = btree.iter_all().map(|(k, _)| k).collect();
+            let expected: ~[uint] = range(first, nb_keys).collect();
+            assert!(keys == expected, format!("{} != {}", keys.to_str(), expected.to_str()));
+            assert!(btree.len() == keys.len());
+            for i in range(first, nb_keys) {
+                assert!(btree.remove(&i) == Some(i));
+            }
+            assert!(btree.len() == 0 && btree.iter_all().next().is_none());
+            remove_files(&path);
+        }
+    }
+
+    #[test]
+    fn test_open_frees_lost_pages() {
+        let path = temp_path("lost_pages");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let pages = ~[storage.new_page(), storage.new_page(), storage.new_page()];
+            for page in pages.iter() {
+                storage.write(page, leaf(*page, ~[1]));
+            }
+            storage.set_root(&pages[0]);
+            storage.free_page(&pages[1]);
+            // one page is taken from the free list and one is appended, the process ends
+            // before they are written
+            assert!(storage.new_page() == pages[1]);
+            assert!(storage.new_page() == 4);
+            assert!(storage.nb_free() == 0);
+        }
+        {
+            let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+            assert!(storage.nb_pages() == 5 && storage.nb_free() == 2);
+            assert!(storage.read(&3) == Ok(Some(leaf(3, ~[1]))));
+            // the page is marked as free, but the crash loses the head of the free list
+            storage.crash_after(1);
+            storage.free_page(&3);
+        }
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.nb_free() == 3);
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[1]))));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_damaged_page() {
+        let path = temp_path("damaged_page");
+        let storage = FileStorage::create(&path, NodeCodec::new(128));
+        let (first, second) = (storage.new_page(), storage.new_page());
+        storage.write(&first, leaf(first, ~[1]));
+        storage.write(&second, leaf(second, ~[2]));
+        storage.checkpoint();
+        assert!(storage.scrub().is_empty());
+        damage(&path, second * storage.page_size() + 30);
+        let read: Result<Option<UintNode>, PageError<uint>> = storage.read(&second);
+        let errors = storage.scrub();
+        assert!(errors.len() == 1 && Err(errors[0].clone()) == read);
+        match errors[0] {
+            ChecksumMismatch(page, expected, actual) =>
+                assert!(page == second && expected != actual),
+            _ => fail!("the page is damaged, not malformed")
+        }
+        let read_versioned: Result<Option<(uint, UintNode)>, PageError<uint>> =
+            storage.read_versioned(&second);
+        assert!(read_versioned.is_err());
+        assert!(storage.read(&first) == Ok(Some(leaf(first, ~[1]))));
+        // a write replaces the damaged page
+        storage.write(&second, leaf(second, ~[3]));
+        storage.checkpoint();
+        assert!(storage.read(&second) == Ok(Some(leaf(second, ~[3]))));
+        assert!(storage.scrub().is_empty());
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_damaged_header() {
+        let path = temp_path("damaged_header");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            storage.new_page();
+        }
+        damage(&path, 20);
+        assert!(FileStorage::open(&path, NodeCodec::new(128)).is_none());
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_damaged_log_record_is_ignored() {
+        let path = temp_path("damaged_record");
+        {
+            let storage = FileStorage::create(&path, NodeCodec::new(128));
+            let page = storage.new_page();
+            storage.write(&page, leaf(page, ~[1]));
+            storage.write(&page, leaf(page, ~[1, 2]));
+            // the checkpoint of the drop doesn't reach the disk
+            storage.crash_after(0);
+        }
+        // the header record of `new_page` has 1 + 3 * 8 bytes, a page record
+        // 1 + 2 * 8 + 128 bytes, both are followed by a checksum of 4 bytes
+        damage(&log_path(&path), (1 + 3 * 8 + 4) + (1 + 2 * 8 + 128 + 4) + 50);
+        let storage = FileStorage::open(&path, NodeCodec::new(128)).unwrap();
+        assert!(storage.read(&1) == Ok(Some(leaf(1, ~[1]))));
+        remove_files(&path);
+    }
+
+    #[test]
+    fn test_tree_returns_damaged_page() {
+        let path = temp_path("damaged_tree");
+        let btree = new_tree(&path, 100);
+        let root = btree.storage.root().unwrap();
+        damage(&path, root * btree.storage.page_size() + 30);
+        let is_damaged_root = |result: Result<Option<uint>, PageError<uint>>| {
+            match result {
+                Err(ChecksumMismatch(page, _, _)) => page == root,
+                _ => false
+            }
+        };
+        assert!(is_damaged_root(btree.try_find(&1)));
+        assert!(is_damaged_root(btree.try_insert(1000, 1000)));
+        // no lock of the failed insert is left, the remove would wait for it
+        assert!(is_damaged_root(btree.try_remove(&1)));
+        remove_files(&path);
+    }
+}
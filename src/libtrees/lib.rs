@@ -25,15 +25,32 @@
 extern mod std;
 extern mod extra;
 
+// returns the error of a `Result` from the enclosing function
+macro_rules! try(
+    ($e:expr) => (
+        match $e {
+            Ok(value) => value,
+            Err(err) => return Err(err)
+        }
+    )
+)
 
 mod algorithm;
 mod blinktree {
     pub mod blinktree;
     pub mod physical_node;
+    mod anchor;
     mod blink_ops;
+    mod split_policy;
 }
+mod buffer_pool;
+mod debug_lock;
+mod encoded_size;
+mod epoch;
+mod file_storage;
 mod lock;
 mod node;
+mod page_codec;
 mod persistent;
 mod storage;
 mod statistics;
@@ -14,49 +14,453 @@
  */
 
 
-use std::hashmap::HashSet;
-use std::cast;
-use extra::sync::Mutex;
+use std::comm::{stream, Chan};
+use std::hashmap::HashMap;
+use std::vec;
+use extra::arc::MutexArc;
+use extra::time::precise_time_ns;
 
+use statistics::{LockStatistics, LockCounters};
+
+/// Locks on pages. A page is either locked exclusively by one task or shared by many tasks.
 pub trait LockManager<T> {
+    /// waits until the page can be locked exclusively
     fn lock(&self, id: T);
+    /// waits until the page can be shared with other readers
+    fn lock_shared(&self, id: T);
+    /// locks the page exclusively, if it is possible without waiting
+    fn try_lock(&self, id: T) -> bool;
+    /// shares the page, if it is possible without waiting
+    fn try_lock_shared(&self, id: T) -> bool;
+    /// releases a shared or an exclusive lock
     fn unlock(&self, id: &T);
+    /// a snapshot of the acquisitions and wait times
+    fn statistics(&self) -> LockStatistics<T>;
+}
+
+static NB_SHARDS: uint = 16;
+
+// A task that waits for a page. The task that grants it the lock wakes it over the channel.
+struct Waiter {
+    exclusive: bool,
+    chan: Chan<()>
+}
+
+// A locked page. The tickets are handed out in the order the tasks arrive and granted in the
+// same order, so a waiting task is never overtaken by a task that came later. Readers, that
+// follow each other in the queue, share the page.
+struct PageLock {
+    next_ticket: uint,
+    now_serving: uint,
+    readers: uint,
+    writer: bool,
+    // the tasks with the tickets from `now_serving` on
+    waiters: ~[Waiter]
+}
+
+impl PageLock {
+    fn new() -> PageLock {
+        PageLock { next_ticket: 0, now_serving: 0, readers: 0, writer: false, waiters: ~[] }
+    }
+    fn can_grant(&self, ticket: uint, exclusive: bool) -> bool {
+        self.now_serving == ticket && !self.writer && (!exclusive || self.readers == 0)
+    }
+    fn grant(&mut self, exclusive: bool) {
+        self.now_serving += 1;
+        if exclusive {
+            self.writer = true;
+        } else {
+            self.readers += 1;
+        }
+    }
+    fn is_unused(&self) -> bool {
+        !self.writer && self.readers == 0 && self.now_serving == self.next_ticket
+    }
+    // grants the page to the waiting tasks at the head of the queue and wakes only them
+    fn wake_waiters(&mut self) {
+        while !self.waiters.is_empty() && self.can_grant(self.now_serving, self.waiters[0].exclusive) {
+            let waiter = self.waiters.shift();
+            self.grant(waiter.exclusive);
+            waiter.chan.send(());
+        }
+    }
+}
+
+// the locked pages of a shard and the counters of their acquisitions
+struct Shard<T> {
+    pages: HashMap<T, PageLock>,
+    counters: LockCounters<T>
 }
 
-struct SimpleLockManager<T> {
-    set: HashSet<T>,
-    mutex: Mutex
+/// Shared and exclusive locks on pages. The locked pages are spread over shards, every shard
+/// has its own mutex. A waiting task is only woken, when the page is granted to it.
+/// Clones share the same locks.
+pub struct SimpleLockManager<T> {
+    shards: ~[MutexArc<Shard<T>>]
 }
 
-impl<T: Hash + Eq + Freeze> SimpleLockManager<T> {
+impl<T: Hash + Eq + Clone + Freeze + Send> SimpleLockManager<T> {
     pub fn new() -> SimpleLockManager<T> {
+        SimpleLockManager::with_shards(NB_SHARDS)
+    }
+    pub fn with_shards(nb_shards: uint) -> SimpleLockManager<T> {
+        SimpleLockManager {
+            shards: vec::from_fn(nb_shards, |_| MutexArc::new(Shard {
+                pages: HashMap::new(),
+                counters: LockCounters::new()
+            }))
+        }
+    }
+    /// the number of tasks that wait for the lock of the page
+    pub fn waiting(&self, id: &T) -> uint {
+        do self.access(id) |shard| {
+            match shard.pages.find(id) {
+                Some(page) => page.next_ticket - page.now_serving,
+                None => 0
+            }
+        }
+    }
+    /// the number of tasks that share the page
+    pub fn readers(&self, id: &T) -> uint {
+        do self.access(id) |shard| {
+            match shard.pages.find(id) {
+                Some(page) => page.readers,
+                None => 0
+            }
+        }
+    }
+    fn shard<'a>(&'a self, id: &T) -> &'a MutexArc<Shard<T>> {
+        &self.shards[(id.hash() % self.shards.len() as u64) as uint]
+    }
+    // a channel isn't Freeze, so the safe `access` is not available.
+    // No other MutexArc is accessed inside, so it can't deadlock.
+    fn access<U>(&self, id: &T, f: &fn(&mut Shard<T>) -> U) -> U {
+        unsafe { self.shard(id).unsafe_access(f) }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Freeze + Send + ToStr> SimpleLockManager<T> {
+    fn acquire(&self, id: T, exclusive: bool) {
+        let port = do self.access(&id) |shard| {
+            let page = shard.pages.find_or_insert(id.clone(), PageLock::new());
+            let ticket = page.next_ticket;
+            page.next_ticket += 1;
+            if page.can_grant(ticket, exclusive) {
+                page.grant(exclusive);
+                shard.counters.acquired();
+                None
+            } else {
+                let (port, chan) = stream();
+                page.waiters.push(Waiter { exclusive: exclusive, chan: chan });
+                Some(port)
+            }
+        };
+        match port {
+            Some(port) => {
+                let start = precise_time_ns();
+                // the task, that grants us the page, wakes us
+                port.recv();
+                let wait = precise_time_ns() - start;
+                do self.access(&id) |shard| {
+                    shard.counters.acquired_after(&id, wait);
+                }
+            }
+            None => {}
+        }
+    }
+    // a fresh page can always be granted, so we never leave an unused page behind
+    fn try_acquire(&self, id: T, exclusive: bool) -> bool {
+        do self.access(&id) |shard| {
+            let page = shard.pages.find_or_insert(id.clone(), PageLock::new());
+            if page.can_grant(page.next_ticket, exclusive) {
+                page.next_ticket += 1;
+                page.grant(exclusive);
+                shard.counters.acquired();
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+impl<T: Send> Clone for SimpleLockManager<T> {
+    fn clone(&self) -> SimpleLockManager<T> {
         SimpleLockManager {
-            set: HashSet::new(),
-            mutex: Mutex::new()
+            shards: self.shards.iter().map(|shard| shard.clone()).collect()
         }
     }
 }
-impl<T: Hash + Eq + Clone + Freeze + ToStr> LockManager<T> for SimpleLockManager<T> {
+
+impl<T: Hash + Eq + Clone + Freeze + Send + ToStr> LockManager<T> for SimpleLockManager<T> {
     fn lock(&self, id: T) {
         debug!("locking ptr: {}", id.to_str());
-       /* do self.mutex.lock_cond |cond| {
-            while self.set.contains(&id) {
-                cond.wait();
-            }
-            unsafe {
-                let mut_set = cast::transmute_mut(&self.set);
-                mut_set.insert(id.clone());
-            }
-        }*/
+        self.acquire(id, true);
+    }
+    fn lock_shared(&self, id: T) {
+        debug!("sharing ptr: {}", id.to_str());
+        self.acquire(id, false);
+    }
+    fn try_lock(&self, id: T) -> bool {
+        self.try_acquire(id, true)
+    }
+    fn try_lock_shared(&self, id: T) -> bool {
+        self.try_acquire(id, false)
     }
     fn unlock(&self, id: &T) {
         debug!("unlocking ptr: {}", id.to_str());
-        /*do self.mutex.lock_cond |cond| {
+        do self.access(id) |shard| {
+            let is_unused = match shard.pages.find_mut(id) {
+                Some(page) => {
+                    if page.writer {
+                        page.writer = false;
+                    } else if page.readers > 0 {
+                        page.readers -= 1;
+                    } else {
+                        fail!("unlocking ptr {}, that is not locked", id.to_str());
+                    }
+                    page.wake_waiters();
+                    page.is_unused()
+                }
+                None => fail!("unlocking ptr {}, that is not locked", id.to_str())
+            };
+            if is_unused {
+                shard.pages.remove(id);
+            }
+        }
+    }
+    fn statistics(&self) -> LockStatistics<T> {
+        let mut counters = LockCounters::new();
+        for shard in self.shards.iter() {
             unsafe {
-                let mut_set = cast::transmute_mut(&self.set);
-                mut_set.remove(id);
-                cond.signal();
+                do shard.unsafe_access |shard| {
+                    counters.add(&shard.counters);
+                }
+            }
+        }
+        counters.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LockManager, SimpleLockManager};
+    use std::comm::{stream, SharedChan};
+    use std::task;
+
+    #[test]
+    fn test_lock_unlock() {
+        let locks = SimpleLockManager::new();
+        locks.lock(1u);
+        locks.lock(2u);
+        locks.unlock(&1);
+        locks.lock(1u);
+        assert!(locks.waiting(&1) == 0);
+        locks.unlock(&1);
+        locks.unlock(&2);
+    }
+    #[test] #[should_fail]
+    fn test_unlock_without_lock() {
+        let locks = SimpleLockManager::new();
+        locks.unlock(&1u);
+    }
+    #[test]
+    fn test_mutual_exclusion() {
+        let locks = SimpleLockManager::with_shards(2);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        for i in range(0u, 8) {
+            let (task_locks, chan) = (locks.clone(), chan.clone());
+            do task::spawn {
+                for _ in range(0u, 100) {
+                    task_locks.lock(1u);
+                    chan.send(i);
+                    task::deschedule();
+                    chan.send(i);
+                    task_locks.unlock(&1);
+                }
+            }
+        }
+        // no other task enters, while a task holds the lock
+        for _ in range(0u, 8 * 100) {
+            let entered = port.recv();
+            assert!(port.recv() == entered);
+        }
+    }
+    #[test]
+    fn test_waits_for_unlock() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        locks.lock(1u);
+        let task_locks = locks.clone();
+        do task::spawn {
+            // other pages are not blocked
+            task_locks.lock(2u);
+            task_locks.unlock(&2);
+            chan.send(1u);
+            task_locks.lock(1u);
+            chan.send(2u);
+            task_locks.unlock(&1);
+        }
+        assert!(port.recv() == 1);
+        while locks.waiting(&1) == 0 {
+            task::deschedule();
+        }
+        assert!(!port.peek());
+        locks.unlock(&1);
+        assert!(port.recv() == 2);
+    }
+    #[test]
+    fn test_fifo() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        locks.lock(1u);
+        for i in range(0u, 5) {
+            let (task_locks, chan) = (locks.clone(), chan.clone());
+            do task::spawn {
+                task_locks.lock(1u);
+                chan.send(i);
+                task_locks.unlock(&1);
+            }
+            // the next task queues up behind this one
+            while locks.waiting(&1) < i + 1 {
+                task::deschedule();
             }
-        }*/
+        }
+        locks.unlock(&1);
+        for i in range(0u, 5) {
+            assert!(port.recv() == i);
+        }
+    }
+    #[test]
+    fn test_unlock_wakes_only_its_page() {
+        // both pages are in the same shard
+        let locks = SimpleLockManager::with_shards(1);
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        locks.lock(1u);
+        locks.lock(2u);
+        for &page in [1u, 2].iter() {
+            let (task_locks, chan) = (locks.clone(), chan.clone());
+            do task::spawn {
+                task_locks.lock(page);
+                chan.send(page);
+                task_locks.unlock(&page);
+            }
+            while locks.waiting(&page) == 0 {
+                task::deschedule();
+            }
+        }
+        locks.unlock(&2);
+        assert!(port.recv() == 2);
+        assert!(locks.waiting(&1) == 1);
+        locks.unlock(&1);
+        assert!(port.recv() == 1);
+    }
+    #[test]
+    fn test_shared_and_try_lock() {
+        let locks = SimpleLockManager::new();
+        locks.lock_shared(1u);
+        locks.lock_shared(1u);
+        assert!(locks.readers(&1) == 2);
+        assert!(!locks.try_lock(1u));
+        assert!(locks.try_lock_shared(1u));
+        locks.unlock(&1);
+        locks.unlock(&1);
+        locks.unlock(&1);
+        assert!(locks.try_lock(1u));
+        assert!(!locks.try_lock(1u));
+        assert!(!locks.try_lock_shared(1u));
+        locks.unlock(&1);
+        assert!(locks.readers(&1) == 0 && locks.waiting(&1) == 0);
+    }
+    #[test]
+    fn test_readers_share_page() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        let chan = SharedChan::new(chan);
+        locks.lock_shared(1u);
+        for i in range(0u, 4) {
+            let (task_locks, chan) = (locks.clone(), chan.clone());
+            do task::spawn {
+                task_locks.lock_shared(1u);
+                chan.send(i);
+                task_locks.unlock(&1);
+            }
+        }
+        // all readers enter, while we are still reading
+        let mut entered = ~[];
+        for _ in range(0u, 4) {
+            entered.push(port.recv());
+        }
+        assert!(entered.len() == 4);
+        locks.unlock(&1);
+    }
+    #[test]
+    fn test_writer_excludes_readers() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        locks.lock(1u);
+        let task_locks = locks.clone();
+        do task::spawn {
+            task_locks.lock_shared(1u);
+            chan.send(());
+            task_locks.unlock(&1);
+        }
+        while locks.waiting(&1) == 0 {
+            task::deschedule();
+        }
+        assert!(!port.peek());
+        locks.unlock(&1);
+        port.recv();
+    }
+    #[test]
+    fn test_writer_waits_for_readers() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        locks.lock_shared(1u);
+        let task_locks = locks.clone();
+        do task::spawn {
+            task_locks.lock(1u);
+            chan.send(());
+            task_locks.unlock(&1);
+        }
+        while locks.waiting(&1) == 0 {
+            task::deschedule();
+        }
+        // a new reader does not overtake the waiting writer
+        assert!(!locks.try_lock_shared(1u));
+        assert!(!port.peek());
+        locks.unlock(&1);
+        port.recv();
+    }
+    #[test]
+    fn test_statistics() {
+        let locks = SimpleLockManager::new();
+        let (port, chan) = stream();
+        locks.lock(1u);
+        locks.unlock(&1);
+        assert!(locks.try_lock_shared(2u));
+        locks.lock(1u);
+        let task_locks = locks.clone();
+        do task::spawn {
+            task_locks.lock(1u);
+            task_locks.unlock(&1);
+            chan.send(());
+        }
+        while locks.waiting(&1) == 0 {
+            task::deschedule();
+        }
+        locks.unlock(&1);
+        port.recv();
+        locks.unlock(&2);
+
+        let stats = locks.statistics();
+        assert!(stats.acquisitions == 4);
+        assert!(stats.contended == 1);
+        assert!(stats.max_wait_ns > 0 && stats.total_wait_ns == stats.max_wait_ns);
+        assert!(stats.average_wait_ns() == stats.max_wait_ns);
+        assert!(stats.hot_pages == ~[(1, 1)]);
     }
 }
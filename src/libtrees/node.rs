@@ -15,7 +15,7 @@
 
 use blinktree::physical_node::{PhysicalNode};
 
-#[deriving(Clone)]
+#[deriving(Clone, Eq)]
 pub enum Node<I, L> {
     INode(I),
     Leaf(L)
@@ -46,6 +46,18 @@ impl <I,L> Node<I,L> {
             &Leaf(*)  => fail!("called getINode on a Leaf"),
         }
     }
+    pub fn getMutLeaf<'a>(&'a mut self) -> &'a mut L {
+        match self {
+            &Leaf(ref mut l)  => l,
+            &INode(*) => fail!("called getMutLeaf on an INode"),
+        }
+    }
+    pub fn getMutINode<'a>(&'a mut self) -> &'a mut I {
+        match self {
+            &INode(ref mut i) => i,
+            &Leaf(*)  => fail!("called getMutINode on a Leaf"),
+        }
+    }
 }
 
 macro_rules! node_method(
@@ -101,14 +113,20 @@ Node<INODE, LEAF> {
     pub fn is_inode(&self) -> bool {
         node_method!(is_inode)
     }
+    pub fn is_dead(&self) -> bool {
+        node_method!(is_dead)
+    }
     pub fn is_most_right_node(&self) -> bool {
         self.link_ptr().is_none()
     }
 
-    pub fn needs_split(&self, max_size: uint) -> bool {
+    pub fn byte_size(&self) -> uint {
+        node_method!(byte_size)
+    }
+    pub fn needs_split(&self, max_bytes: uint) -> bool {
         match self {
-            &INode(ref inode) => inode.needs_split(max_size),
-            &Leaf(ref leaf) => leaf.needs_split(max_size)
+            &INode(ref inode) => inode.needs_split(max_bytes),
+            &Leaf(ref leaf) => leaf.needs_split(max_bytes)
         }
     }
 }
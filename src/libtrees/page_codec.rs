@@ -0,0 +1,446 @@
+/* Copyright 2013 Leon Sixt
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str;
+use std::vec;
+
+use node::{Node, INode, Leaf};
+use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_ROOT, T_LEAF, T_INODE, T_DEAD};
+
+#[deriving(Clone, Eq, ToStr)]
+pub enum CodecError {
+    // the node needs at least the first number of bytes, the page has the second
+    PageOverflow(uint, uint),
+    // the page ended at the offset, while a value was read
+    UnexpectedEnd(uint),
+    // the node type is neither a leaf nor an inode or has unknown flags
+    InvalidNodeType(uint),
+    // the tag of an optional value is neither 0 nor 1
+    InvalidTag(u8),
+    // a length, that doesn't fit into the rest of the page or to the other lengths
+    InvalidLength(uint),
+    InvalidUtf8
+}
+
+/// Writes the values into a page of a fixed size. The rest of the page stays zeroed.
+pub struct PageWriter {
+    priv page: ~[u8],
+    priv pos: uint
+}
+
+impl PageWriter {
+    pub fn new(page_size: uint) -> PageWriter {
+        PageWriter { page: vec::from_elem(page_size, 0u8), pos: 0 }
+    }
+    pub fn write_u8(&mut self, value: u8) -> Result<(), CodecError> {
+        self.write_bytes(&[value])
+    }
+    pub fn write_u32(&mut self, value: u32) -> Result<(), CodecError> {
+        let bytes = vec::from_fn(4, |i| (value >> (24 - 8 * i)) as u8);
+        self.write_bytes(bytes.slice_from(0))
+    }
+    pub fn write_u64(&mut self, value: u64) -> Result<(), CodecError> {
+        let bytes = vec::from_fn(8, |i| (value >> (56 - 8 * i)) as u8);
+        self.write_bytes(bytes.slice_from(0))
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        let end = self.pos + bytes.len();
+        if end > self.page.len() {
+            return Err(PageOverflow(end, self.page.len()));
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.page[self.pos + i] = *byte;
+        }
+        self.pos = end;
+        Ok(())
+    }
+    /// the page, that has always the size given to `new`
+    pub fn unwrap(self) -> ~[u8] {
+        self.page
+    }
+}
+
+/// Reads the values of a page. It never reads behind the end of the page.
+pub struct PageReader<'a> {
+    priv page: &'a [u8],
+    priv pos: uint
+}
+
+impl<'a> PageReader<'a> {
+    pub fn new(page: &'a [u8]) -> PageReader<'a> {
+        PageReader { page: page, pos: 0 }
+    }
+    /// the number of bytes, that were not read yet
+    pub fn remaining(&self) -> uint {
+        self.page.len() - self.pos
+    }
+    pub fn read_u8(&mut self) -> Result<u8, CodecError> {
+        let bytes = try!(self.read_slice(1));
+        Ok(bytes[0])
+    }
+    pub fn read_u32(&mut self) -> Result<u32, CodecError> {
+        let bytes = try!(self.read_slice(4));
+        Ok(bytes.iter().fold(0u32, |value, byte| (value << 8) | *byte as u32))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, CodecError> {
+        let bytes = try!(self.read_slice(8));
+        Ok(bytes.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64))
+    }
+    pub fn read_bytes(&mut self, len: uint) -> Result<~[u8], CodecError> {
+        let bytes = try!(self.read_slice(len));
+        Ok(bytes.to_owned())
+    }
+    // reads the length of a sequence. Every element takes at least one byte, so a length
+    // bigger than the rest of the page is rejected before anything is allocated.
+    pub fn read_len(&mut self) -> Result<uint, CodecError> {
+        let len = try!(self.read_u64());
+        if len > self.remaining() as u64 {
+            return Err(InvalidLength(len as uint));
+        }
+        Ok(len as uint)
+    }
+    fn read_slice(&mut self, len: uint) -> Result<&'a [u8], CodecError> {
+        if len > self.remaining() {
+            return Err(UnexpectedEnd(self.page.len()));
+        }
+        let bytes = self.page.slice(self.pos, self.pos + len);
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// The CRC-32 of the bytes, as it is used by zlib and ethernet
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in bytes.iter() {
+        crc = crc ^ (*byte as u32);
+        for _ in range(0, 8) {
+            let mask = if (crc & 1) == 1 { 0xedb88320u32 } else { 0 };
+            crc = (crc >> 1) ^ mask;
+        }
+    }
+    crc ^ 0xffffffff
+}
+
+/// A key, value or pointer, that can be written into a page.
+/// It takes as many bytes as its `EncodedSize`.
+pub trait PageEncodable {
+    fn encode(&self, writer: &mut PageWriter) -> Result<(), CodecError>;
+    fn decode(reader: &mut PageReader) -> Result<Self, CodecError>;
+}
+
+impl PageEncodable for uint {
+    fn encode(&self, writer: &mut PageWriter) -> Result<(), CodecError> {
+        writer.write_u64(*self as u64)
+    }
+    fn decode(reader: &mut PageReader) -> Result<uint, CodecError> {
+        let value = try!(reader.read_u64());
+        Ok(value as uint)
+    }
+}
+
+impl PageEncodable for u64 {
+    fn encode(&self, writer: &mut PageWriter) -> Result<(), CodecError> {
+        writer.write_u64(*self)
+    }
+    fn decode(reader: &mut PageReader) -> Result<u64, CodecError> {
+        reader.read_u64()
+    }
+}
+
+// byte strings are prefixed with their length
+impl PageEncodable for ~[u8] {
+    fn encode(&self, writer: &mut PageWriter) -> Result<(), CodecError> {
+        try!(writer.write_u64(self.len() as u64));
+        writer.write_bytes(self.slice_from(0))
+    }
+    fn decode(reader: &mut PageReader) -> Result<~[u8], CodecError> {
+        let len = try!(reader.read_len());
+        reader.read_bytes(len)
+    }
+}
+
+impl PageEncodable for ~str {
+    fn encode(&self, writer: &mut PageWriter) -> Result<(), CodecError> {
+        try!(writer.write_u64(self.len() as u64));
+        writer.write_bytes(self.as_bytes())
+    }
+    fn decode(reader: &mut PageReader) -> Result<~str, CodecError> {
+        let len = try!(reader.read_len());
+        let bytes = try!(reader.read_bytes(len));
+        if !str::is_utf8(bytes) {
+            return Err(InvalidUtf8);
+        }
+        Ok(str::from_utf8(bytes))
+    }
+}
+
+fn encode_seq<T: PageEncodable>(values: &[T], writer: &mut PageWriter) -> Result<(), CodecError> {
+    try!(writer.write_u64(values.len() as u64));
+    for value in values.iter() {
+        try!(value.encode(writer));
+    }
+    Ok(())
+}
+
+fn decode_seq<T: PageEncodable>(reader: &mut PageReader) -> Result<~[T], CodecError> {
+    let len = try!(reader.read_len());
+    let mut values = vec::with_capacity(len);
+    for _ in range(0, len) {
+        values.push(try!(PageEncodable::decode(reader)));
+    }
+    Ok(values)
+}
+
+// one byte for the tag
+fn encode_option<T: PageEncodable>(value: &Option<T>, writer: &mut PageWriter)
+    -> Result<(), CodecError> {
+    match value {
+        &Some(ref value) => {
+            try!(writer.write_u8(1));
+            value.encode(writer)
+        }
+        &None => writer.write_u8(0)
+    }
+}
+
+fn decode_option<T: PageEncodable>(reader: &mut PageReader) -> Result<Option<T>, CodecError> {
+    match try!(reader.read_u8()) {
+        0 => Ok(None),
+        1 => Ok(Some(try!(PageEncodable::decode(reader)))),
+        tag => Err(InvalidTag(tag))
+    }
+}
+
+/// The size of the pages, that a codec writes. It doesn't depend on the type of the nodes.
+pub trait PageSize {
+    fn page_size(&self) -> uint;
+}
+
+/// Turns nodes into pages of a fixed size and back.
+/// A page, that was not written by the codec, is rejected with an error.
+pub trait PageCodec<N>: PageSize + Freeze + Send {
+    fn encode(&self, node: &N) -> Result<~[u8], CodecError>;
+    fn decode(&self, page: &[u8]) -> Result<N, CodecError>;
+}
+
+/// Encodes the `DefaultBLinkNode`s of a tree in the order of their fields, so a node takes
+/// `byte_size` bytes. The root flag is not stored, the tree knows its root.
+#[deriving(Clone)]
+pub struct NodeCodec {
+    priv page_size: uint
+}
+
+impl NodeCodec {
+    /// a codec for the nodes of a tree, that was created with `page_size`
+    pub fn new(page_size: uint) -> NodeCodec {
+        NodeCodec { page_size: page_size }
+    }
+}
+
+fn encode_node<K: PageEncodable, V: PageEncodable, Ptr: PageEncodable>(
+    node: &DefaultBLinkNode<K, V, Ptr>, writer: &mut PageWriter) -> Result<(), CodecError> {
+    try!(writer.write_u64((node.node_type & !T_ROOT) as u64));
+    try!(node.my_ptr.encode(writer));
+    try!(encode_option(&node.link_ptr, writer));
+    try!(encode_seq(node.keys, writer));
+    encode_seq(node.values, writer)
+}
+
+fn decode_node<K: PageEncodable, V: PageEncodable, Ptr: PageEncodable>(
+    node_type: uint, reader: &mut PageReader) -> Result<DefaultBLinkNode<K, V, Ptr>, CodecError> {
+    let my_ptr = try!(PageEncodable::decode(reader));
+    let link_ptr = try!(decode_option(reader));
+    let keys: ~[K] = try!(decode_seq(reader));
+    let values: ~[V] = try!(decode_seq(reader));
+    // a leaf has a value for every key, an inode may have one child more than keys
+    let valid = if (node_type & T_LEAF) != 0 {
+        values.len() == keys.len()
+    } else {
+        values.len() == keys.len() || values.len() == keys.len() + 1
+    };
+    if !valid {
+        return Err(InvalidLength(values.len()));
+    }
+    Ok(PhysicalNode::new(node_type, my_ptr, link_ptr, keys, values))
+}
+
+impl PageSize for NodeCodec {
+    fn page_size(&self) -> uint {
+        self.page_size
+    }
+}
+
+impl<K: PageEncodable, V: PageEncodable, Ptr: PageEncodable>
+PageCodec<Node<DefaultBLinkNode<K, Ptr, Ptr>, DefaultBLinkNode<K, V, Ptr>>> for NodeCodec {
+    fn encode(&self, node: &Node<DefaultBLinkNode<K, Ptr, Ptr>, DefaultBLinkNode<K, V, Ptr>>)
+        -> Result<~[u8], CodecError> {
+        let mut writer = PageWriter::new(self.page_size);
+        match node {
+            &INode(ref inode) => try!(encode_node(inode, &mut writer)),
+            &Leaf(ref leaf) => try!(encode_node(leaf, &mut writer))
+        }
+        Ok(writer.unwrap())
+    }
+    fn decode(&self, page: &[u8])
+        -> Result<Node<DefaultBLinkNode<K, Ptr, Ptr>, DefaultBLinkNode<K, V, Ptr>>, CodecError> {
+        if page.len() != self.page_size {
+            return Err(InvalidLength(page.len()));
+        }
+        let mut reader = PageReader::new(page);
+        let node_type = try!(reader.read_u64()) as uint;
+        if (node_type & !(T_LEAF | T_INODE | T_DEAD)) != 0 {
+            return Err(InvalidNodeType(node_type));
+        }
+        let kind = node_type & (T_LEAF | T_INODE);
+        if kind == T_LEAF {
+            Ok(Leaf(try!(decode_node(node_type, &mut reader))))
+        } else if kind == T_INODE {
+            Ok(INode(try!(decode_node(node_type, &mut reader))))
+        } else {
+            Err(InvalidNodeType(node_type))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PageSize, PageCodec, NodeCodec, PageWriter, PageReader, PageEncodable, CodecError,
+                checksum,
+                PageOverflow, UnexpectedEnd, InvalidNodeType, InvalidTag, InvalidLength,
+                InvalidUtf8};
+    use node::{Node, INode, Leaf};
+    use blinktree::physical_node::{PhysicalNode, DefaultBLinkNode, T_LEAF, T_INODE, T_DEAD};
+    use std::vec;
+
+    type UintNode = Node<DefaultBLinkNode<uint, uint, uint>, DefaultBLinkNode<uint, uint, uint>>;
+    type BytesNode = Node<DefaultBLinkNode<~[u8], uint, uint>,
+                          DefaultBLinkNode<~[u8], ~[u8], uint>>;
+
+    fn uint_leaf(keys: ~[uint], link_ptr: Option<uint>) -> UintNode {
+        let values = keys.iter().map(|k| *k * 10).collect();
+        Leaf(PhysicalNode::new(T_LEAF, 3u, link_ptr, keys, values))
+    }
+
+    fn check_round_trip<N: Eq, C: PageCodec<N>>(codec: &C, node: &N) {
+        let page = codec.encode(node).unwrap();
+        assert!(page.len() == codec.page_size());
+        assert!(&codec.decode(page.slice_from(0)).unwrap() == node);
+    }
+
+    fn decode_error(codec: &NodeCodec, page: &[u8]) -> Option<CodecError> {
+        let node: Result<UintNode, CodecError> = codec.decode(page);
+        match node {
+            Ok(_) => None,
+            Err(err) => Some(err)
+        }
+    }
+
+    #[test]
+    fn test_uint_nodes() {
+        let codec = NodeCodec::new(256);
+        check_round_trip(&codec, &uint_leaf(~[1, 2, 3], Some(7)));
+        check_round_trip(&codec, &uint_leaf(~[], None));
+        let inode: UintNode = INode(PhysicalNode::new(T_INODE, 5u, None, ~[10u, 20], ~[1u, 2, 3]));
+        check_round_trip(&codec, &inode);
+        let dead: UintNode = Leaf(PhysicalNode::new(T_LEAF | T_DEAD, 6u, Some(5u), ~[], ~[]));
+        check_round_trip(&codec, &dead);
+    }
+
+    #[test]
+    fn test_byte_string_nodes() {
+        let codec = NodeCodec::new(256);
+        let leaf: BytesNode = Leaf(PhysicalNode::new(T_LEAF, 1u, Some(2u),
+                                                     ~[~[1u8, 2], ~[]], ~[~[3u8], ~[4u8, 5, 6]]));
+        check_round_trip(&codec, &leaf);
+        let inode: BytesNode = INode(PhysicalNode::new(T_INODE, 1u, None, ~[~[9u8]], ~[4u, 5]));
+        check_round_trip(&codec, &inode);
+    }
+
+    #[test]
+    fn test_node_takes_byte_size() {
+        let node = uint_leaf(~[1, 2, 3, 4], Some(1));
+        let size = node.byte_size();
+        check_round_trip(&NodeCodec::new(size), &node);
+        assert!(NodeCodec::new(size - 1).encode(&node) == Err(PageOverflow(size, size - 1)));
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut writer = PageWriter::new(32);
+        (~"abc").encode(&mut writer).unwrap();
+        let page = writer.unwrap();
+        let decoded: Result<~str, CodecError> = PageEncodable::decode(&mut PageReader::new(page));
+        assert!(decoded == Ok(~"abc"));
+
+        let mut writer = PageWriter::new(32);
+        (~[0xffu8, 0xfe]).encode(&mut writer).unwrap();
+        let page = writer.unwrap();
+        let decoded: Result<~str, CodecError> = PageEncodable::decode(&mut PageReader::new(page));
+        assert!(decoded == Err(InvalidUtf8));
+    }
+
+    #[test]
+    fn test_malformed_pages() {
+        let codec = NodeCodec::new(128);
+        // a page of another size
+        assert!(decode_error(&codec, [0u8, ..8]) == Some(InvalidLength(8)));
+        // an empty page has no node type
+        assert!(decode_error(&codec, vec::from_elem(128, 0u8)) == Some(InvalidNodeType(0)));
+
+        // the node type is the first word, the link pointer starts with its tag at 16 and
+        // the number of keys follows
+        let mut page = codec.encode(&uint_leaf(~[1, 2], Some(7))).unwrap();
+        page[7] = 0xff;
+        assert!(decode_error(&codec, page) == Some(InvalidNodeType(0xff)));
+
+        let mut page = codec.encode(&uint_leaf(~[1, 2], Some(7))).unwrap();
+        page[16] = 2;
+        assert!(decode_error(&codec, page) == Some(InvalidTag(2)));
+
+        let mut page = codec.encode(&uint_leaf(~[1, 2], None)).unwrap();
+        page[17] = 0xff;
+        assert!(decode_error(&codec, page) == Some(InvalidLength(0xff << 56 | 2)));
+
+        // three keys, the values are read from the wrong place
+        let mut page = codec.encode(&uint_leaf(~[1, 2], None)).unwrap();
+        page[24] = 3;
+        assert!(decode_error(&codec, page).is_some());
+
+        // a leaf with more values than keys
+        let leaf: UintNode = Leaf(PhysicalNode::new(T_LEAF, 1u, None, ~[1u], ~[1u, 2]));
+        let page = codec.encode(&leaf).unwrap();
+        assert!(decode_error(&codec, page) == Some(InvalidLength(2)));
+    }
+
+    #[test]
+    fn test_reader_stops_at_the_end() {
+        let page = [1u8, 2, 3];
+        let mut reader = PageReader::new(page);
+        assert!(reader.read_u64() == Err(UnexpectedEnd(3)));
+        assert!(reader.read_u8() == Ok(1));
+        assert!(reader.remaining() == 2);
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert!(checksum([]) == 0);
+        assert!(checksum("123456789".as_bytes()) == 0xcbf43926);
+        let mut page = vec::from_elem(64, 7u8);
+        let before = checksum(page.slice_from(0));
+        page[20] ^= 4;
+        assert!(checksum(page.slice_from(0)) != before);
+    }
+}
@@ -1,14 +1,28 @@
 
 pub trait Map<K,V> {
-    fn find<'a>(&'a self, key: &K) -> Option<&'a V>;
+    /// returns a copy of the value, it may be changed concurrently after the call.
+    fn find(&self, key: &K) -> Option<V>;
     fn contains_key(&self, key: &K) -> bool {
         self.find(key).is_some()
     }
-    fn insert(&self, key: K, value: V);
-    fn remove(&self, key: &K);
+    /// inserts the key or replaces the value of an equal key. Returns the replaced value.
+    fn insert(&self, key: K, value: V) -> Option<V>;
+    fn remove(&self, key: &K) -> Option<V>;
 }
 
-trait IteratableMap<'self, K,V, I: Iterator<(K,V)>> {
-    fn iter(from: &K, to: &K) -> I;
-    fn iter_all() -> I;
+/// A map that keeps every value of equal keys.
+pub trait MultiMap<K,V> {
+    /// returns the values of the key in insertion order
+    fn get_all(&self, key: &K) -> ~[V];
+    /// removes one pair of the key and the value. Returns false, if there is no such pair.
+    fn remove_one(&self, key: &K, value: &V) -> bool;
+    fn count(&self, key: &K) -> uint {
+        self.get_all(key).len()
+    }
+}
+
+pub trait IteratableMap<'a, K, V, I: Iterator<(K,V)>> {
+    /// iterates in order over all pairs with `from <= key <= to`
+    fn iter(&'a self, from: &K, to: &K) -> I;
+    fn iter_all(&'a self) -> I;
 }
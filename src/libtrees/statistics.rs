@@ -16,6 +16,9 @@
 
 use std::unstable::atomics::{AtomicUint, Relaxed};
 use std::cast;
+use std::cmp;
+use std::hashmap::HashMap;
+use extra::sort::merge_sort;
 
 pub trait StatisticsManager: Freeze {
     fn elements(&self) -> uint;
@@ -35,6 +38,10 @@ pub trait StatisticsManager: Freeze {
 
     fn insertions(&self) -> uint;
     fn inc_insertions(&self);
+
+    // the number of lock-free reads, that had to start again after a concurrent write
+    fn restarts(&self) -> uint;
+    fn inc_restarts(&self);
 }
 pub struct AtomicStatistics {
     elements: AtomicUint,
@@ -42,6 +49,7 @@ pub struct AtomicStatistics {
     leafs: AtomicUint,
     insertions: AtomicUint,
     deletions: AtomicUint,
+    restarts: AtomicUint,
 
 }
 
@@ -75,6 +83,14 @@ impl StatisticsManager for AtomicStatistics {
             cast::transmute_mut(&self.deletions).fetch_add(1, Relaxed);
         }
     }
+    fn restarts(&self) -> uint {
+        self.restarts.load(Relaxed)
+    }
+    fn inc_restarts(&self) {
+        unsafe {
+            cast::transmute_mut(&self.restarts).fetch_add(1, Relaxed);
+        }
+    }
     fn inodes(&self) -> uint {
         self.inodes.load(Relaxed)
     }
@@ -111,8 +127,172 @@ impl AtomicStatistics {
             inodes: AtomicUint::new(0),
             leafs: AtomicUint::new(0),
             insertions: AtomicUint::new(0),
-            deletions: AtomicUint::new(0)
+            deletions: AtomicUint::new(0),
+            restarts: AtomicUint::new(0)
+        }
+
+    }
+}
+
+// the number of pages in `LockStatistics::hot_pages`
+static NB_HOT_PAGES: uint = 8;
+// the number of pages, whose contended acquisitions are counted
+static NB_COUNTED_PAGES: uint = NB_HOT_PAGES * 4;
+
+/// A snapshot of the lock statistics of a lock manager.
+#[deriving(Clone)]
+pub struct LockStatistics<T> {
+    acquisitions: uint,
+    // acquisitions, that had to wait for another task
+    contended: uint,
+    total_wait_ns: u64,
+    max_wait_ns: u64,
+    // the pages with the most contended acquisitions and their number, the hottest first
+    hot_pages: ~[(T, uint)]
+}
+
+impl<T> LockStatistics<T> {
+    /// the average wait time of the contended acquisitions
+    pub fn average_wait_ns(&self) -> u64 {
+        if self.contended == 0 {
+            0
+        } else {
+            self.total_wait_ns / self.contended as u64
         }
+    }
+}
+
+/// Counts the lock acquisitions. A lock manager updates them while it holds its mutex.
+/// The contended acquisitions are only counted for the `NB_COUNTED_PAGES` hottest pages,
+/// a new page replaces the coldest one.
+pub struct LockCounters<T> {
+    acquisitions: uint,
+    contended: uint,
+    total_wait_ns: u64,
+    max_wait_ns: u64,
+    contended_pages: HashMap<T, uint>
+}
 
+impl<T: Hash + Eq + Clone> LockCounters<T> {
+    pub fn new() -> LockCounters<T> {
+        LockCounters {
+            acquisitions: 0,
+            contended: 0,
+            total_wait_ns: 0,
+            max_wait_ns: 0,
+            contended_pages: HashMap::new()
+        }
+    }
+    /// the lock of the page was granted without waiting
+    pub fn acquired(&mut self) {
+        self.acquisitions += 1;
+    }
+    /// the lock of the page was granted after waiting `wait_ns` nanoseconds
+    pub fn acquired_after(&mut self, id: &T, wait_ns: u64) {
+        self.acquisitions += 1;
+        self.contended += 1;
+        self.total_wait_ns += wait_ns;
+        self.max_wait_ns = cmp::max(self.max_wait_ns, wait_ns);
+        self.count_contended(id, 1);
+    }
+    pub fn add(&mut self, other: &LockCounters<T>) {
+        self.acquisitions += other.acquisitions;
+        self.contended += other.contended;
+        self.total_wait_ns += other.total_wait_ns;
+        self.max_wait_ns = cmp::max(self.max_wait_ns, other.max_wait_ns);
+        for (id, count) in other.contended_pages.iter() {
+            self.count_contended(id, *count);
+        }
+    }
+    // adds `count` contended acquisitions of the page, the coldest page makes room for it
+    fn count_contended(&mut self, id: &T, count: uint) {
+        match self.contended_pages.find_mut(id) {
+            Some(counted) => {
+                *counted += count;
+                return;
+            }
+            None => {}
+        }
+        if self.contended_pages.len() >= NB_COUNTED_PAGES {
+            let coldest = self.contended_pages.iter()
+                .min_by(|&(_, count)| *count)
+                .map(|(id, _)| id.clone())
+                .unwrap();
+            self.contended_pages.remove(&coldest);
+        }
+        self.contended_pages.insert(id.clone(), count);
+    }
+    pub fn snapshot(&self) -> LockStatistics<T> {
+        let pages: ~[(T, uint)] = self.contended_pages.iter()
+            .map(|(id, count)| (id.clone(), *count)).collect();
+        let mut hot_pages = merge_sort(pages, |&(_, a), &(_, b)| a >= b);
+        hot_pages.truncate(NB_HOT_PAGES);
+        LockStatistics {
+            acquisitions: self.acquisitions,
+            contended: self.contended,
+            total_wait_ns: self.total_wait_ns,
+            max_wait_ns: self.max_wait_ns,
+            hot_pages: hot_pages
+        }
+    }
+}
+
+/// A snapshot of the counters of a buffer pool.
+#[deriving(Clone, Eq)]
+pub struct BufferStatistics {
+    // reads of pages, that were in a frame
+    hits: uint,
+    // reads of pages, that had to be read from the storage
+    misses: uint,
+    evictions: uint,
+    // dirty pages, that were written back, when they left their frame
+    write_backs: uint
+}
+
+impl BufferStatistics {
+    pub fn new() -> BufferStatistics {
+        BufferStatistics { hits: 0, misses: 0, evictions: 0, write_backs: 0 }
+    }
+    /// the part of the reads, that were served from a frame
+    pub fn hit_ratio(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LockCounters, NB_HOT_PAGES, NB_COUNTED_PAGES};
+
+    #[test]
+    fn test_counted_pages_are_bounded() {
+        let mut counters = LockCounters::new();
+        for page in range(0u, NB_HOT_PAGES) {
+            for _ in range(0, 10) {
+                counters.acquired_after(&page, 1);
+            }
+        }
+        // every page after the hot ones is contended once
+        for page in range(NB_HOT_PAGES, 10000) {
+            counters.acquired_after(&page, 1);
+        }
+        assert!(counters.contended_pages.len() == NB_COUNTED_PAGES);
+        let mut other = LockCounters::new();
+        for page in range(20000u, 20000 + NB_COUNTED_PAGES) {
+            other.acquired_after(&page, 1);
+        }
+        counters.add(&other);
+        assert!(counters.contended_pages.len() == NB_COUNTED_PAGES);
+
+        let stats = counters.snapshot();
+        assert!(stats.contended == 10 * NB_HOT_PAGES + 10000 - NB_HOT_PAGES + NB_COUNTED_PAGES);
+        assert!(stats.hot_pages.len() == NB_HOT_PAGES);
+        for &(page, count) in stats.hot_pages.iter() {
+            assert!(page < NB_HOT_PAGES && count == 10);
+        }
     }
 }
@@ -14,44 +14,263 @@
  */
 
 
-use std::cast;
 use std::hash::Hash;
 use std::hashmap::HashMap;
+use extra::arc::RWArc;
 
-use std::unstable::atomics::{AtomicUint, Relaxed};
+use page_codec::CodecError;
+use epoch::Epochs;
 
-pub trait StorageManager<Ptr, N>: Freeze {
+/// A page, that was damaged on the disk
+#[deriving(Clone, Eq)]
+pub enum PageError<Ptr> {
+    /// the page, the checksum that was stored with it and the checksum of its content
+    ChecksumMismatch(Ptr, u32, u32),
+    /// the page matches its checksum, but its content is not a node
+    Malformed(Ptr, CodecError)
+}
+
+impl<Ptr: ToStr> ToStr for PageError<Ptr> {
+    fn to_str(&self) -> ~str {
+        match *self {
+            ChecksumMismatch(ref page, expected, actual) =>
+                format!("page {} is damaged, its checksum is {:x} instead of {:x}",
+                        page.to_str(), actual, expected),
+            Malformed(ref page, ref err) =>
+                format!("page {} is malformed: {}", page.to_str(), err.to_str())
+        }
+    }
+}
+
+/// Stores the nodes in pages. All methods take `&self`, the storage is shared between tasks.
+/// A write replaces a page atomically, `read` returns a copy of the page, that stays
+/// unchanged when the page is written afterwards.
+/// Every page has a version word, that is incremented by every write and when the page is
+/// freed. Readers without locks compare it to detect concurrent writers.
+/// A storage, that keeps checksums of its pages, returns an error for a damaged page.
+pub trait StorageManager<Ptr, N>: PageReaders + Freeze + Send {
     fn new_page(&self) -> Ptr;
-    fn read<'a>(&'a self, id: &Ptr) -> Option<&'a N>;
-    fn write(&mut self, id: &Ptr, node: &N);
+    /// reads the page, None if it was never written or is freed
+    fn read(&self, id: &Ptr) -> Result<Option<N>, PageError<Ptr>>;
+    /// reads the page and the version it had at that time
+    fn read_versioned(&self, id: &Ptr) -> Result<Option<(uint, N)>, PageError<Ptr>>;
+    fn write(&self, id: &Ptr, node: N);
+    /// writes the pages, a storage that survives a crash keeps all or none of them.
+    /// The others write them one after another.
+    fn write_all(&self, nodes: ~[(Ptr, N)]) {
+        for (id, node) in nodes.move_iter() {
+            self.write(&id, node);
+        }
+    }
+    /// the page is no longer referenced by the tree. A later `new_page` may return it again.
+    fn free_page(&self, id: &Ptr);
+    /// the current version of the page, 0 if it was never written
+    fn version(&self, id: &Ptr) -> uint;
+    /// the root, that was stored by `set_root`. A storage, that doesn't outlive the
+    /// process, doesn't remember it.
+    fn root(&self) -> Option<Ptr> { None }
+    /// called whenever the tree gets a new root
+    fn set_root(&self, _root: &Ptr) {}
+    /// the max bytes of a leaf and of an inode, that were stored by `set_node_sizes`
+    fn node_sizes(&self) -> Option<(uint, uint)> { None }
+    /// called, when the tree is created
+    fn set_node_sizes(&self, _max_leaf_bytes: uint, _max_inode_bytes: uint) {}
+    /// checks every stored page and returns the damaged ones. A storage without
+    /// checksums has nothing to check.
+    fn scrub(&self) -> ~[PageError<Ptr>] { ~[] }
 }
 
-pub struct StupidHashmapStorage<Ptr, N> {
-    last_page_ptr: AtomicUint,
+/// The tasks announce, when they start and stop to look at pages. A storage that reclaims
+/// freed pages lazily must not reuse a page, that a task may have seen, until it calls `exit`.
+/// The calls can be nested. A storage that frees pages immediately ignores them.
+pub trait PageReaders {
+    fn enter(&self) {}
+    fn exit(&self) {}
+}
+
+struct Pages<Ptr, N> {
+    last_page_ptr: uint,
     map: HashMap<Ptr, N>,
+    // the freed pages, `new_page` takes the last one first
+    free: ~[Ptr],
+    // the versions outlive the pages, so a version is never seen twice for a page id
+    versions: HashMap<Ptr, uint>
 }
 
-impl<Ptr: Eq + Hash + Freeze,
-     N: Freeze>
+impl<Ptr: Hash + Eq + Clone, N> Pages<Ptr, N> {
+    fn version(&self, id: &Ptr) -> uint {
+        match self.versions.find(id) {
+            Some(version) => *version,
+            None => 0
+        }
+    }
+    fn bump_version(&mut self, id: &Ptr) {
+        *self.versions.find_or_insert(id.clone(), 0) += 1;
+    }
+}
+
+/// Keeps the pages in memory. A freed page is kept, until every task, that entered before
+/// it was freed, has exited.
+pub struct StupidHashmapStorage<Ptr, N> {
+    pages: RWArc<Pages<Ptr, N>>,
+    epochs: Epochs<Ptr>
+}
+
+impl<Ptr: Eq + Hash + Clone + Freeze + Send,
+     N: Freeze + Send>
 StupidHashmapStorage<Ptr, N>  {
     pub fn new() -> StupidHashmapStorage<Ptr, N> {
         StupidHashmapStorage {
-            last_page_ptr: AtomicUint::new(1),
-            map: HashMap::new()
+            pages: RWArc::new(Pages {
+                last_page_ptr: 1,
+                map: HashMap::new(),
+                free: ~[],
+                versions: HashMap::new()
+            }),
+            epochs: Epochs::new()
         }
     }
+    // removes the freed pages, that no task can see anymore
+    fn release(&self) {
+        let released = self.epochs.released();
+        if released.is_empty() {
+            return;
+        }
+        do self.pages.write |pages| {
+            for id in released.iter() {
+                pages.map.remove(id);
+                pages.free.push(id.clone());
+                pages.bump_version(id);
+            }
+        }
+    }
+    /// the number of pages that are written and not freed
+    pub fn nb_pages(&self) -> uint {
+        do self.pages.read |pages| {
+            pages.map.len()
+        }
+    }
+    /// the number of freed pages, that were not reused yet
+    pub fn nb_free(&self) -> uint {
+        do self.pages.read |pages| {
+            pages.free.len()
+        }
+    }
+    /// the number of freed pages, that a task may still see
+    pub fn nb_retired(&self) -> uint {
+        self.epochs.nb_retired()
+    }
+}
+
+/// Clones share the pages.
+impl<Ptr: Freeze + Send, N: Freeze + Send> Clone for StupidHashmapStorage<Ptr, N> {
+    fn clone(&self) -> StupidHashmapStorage<Ptr, N> {
+        StupidHashmapStorage { pages: self.pages.clone(), epochs: self.epochs.clone() }
+    }
+}
+
+impl<Ptr: Eq + Hash + Clone + Freeze + Send, N: Freeze + Send>
+PageReaders for StupidHashmapStorage<Ptr, N> {
+    fn enter(&self) {
+        self.epochs.enter();
+    }
+    fn exit(&self) {
+        self.epochs.exit();
+        self.release();
+    }
 }
 
-impl<N: Freeze + Clone> StorageManager<uint, N> for StupidHashmapStorage<uint, N> {
+impl<N: Freeze + Send + Clone> StorageManager<uint, N> for StupidHashmapStorage<uint, N> {
     fn new_page(&self) -> uint {
-        unsafe {
-            cast::transmute_mut(self).last_page_ptr.fetch_add(1, Relaxed)
+        do self.pages.write |pages| {
+            match pages.free.pop_opt() {
+                Some(id) => id,
+                None => {
+                    pages.last_page_ptr += 1;
+                    pages.last_page_ptr - 1
+                }
+            }
+        }
+    }
+    fn read(&self, id: &uint) -> Result<Option<N>, PageError<uint>> {
+        do self.pages.read |pages| {
+            Ok(pages.map.find(id).map(|node| node.clone()))
+        }
+    }
+    fn read_versioned(&self, id: &uint) -> Result<Option<(uint, N)>, PageError<uint>> {
+        do self.pages.read |pages| {
+            match pages.map.find(id) {
+                Some(node) => Ok(Some((pages.version(id), node.clone()))),
+                None => Ok(None)
+            }
+        }
+    }
+    fn write(&self, id: &uint, node: N) {
+        let mut node = Some(node);
+        do self.pages.write |pages| {
+            pages.map.insert(id.clone(), node.take_unwrap());
+            pages.bump_version(id);
+        }
+    }
+    fn free_page(&self, id: &uint) {
+        self.epochs.retire(*id);
+        self.release();
+    }
+    fn version(&self, id: &uint) -> uint {
+        do self.pages.read |pages| {
+            pages.version(id)
         }
     }
-    fn read<'a>(&'a self, id: &uint) -> Option<&'a N> {
-        self.map.find(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StorageManager, PageReaders, StupidHashmapStorage};
+
+    #[test]
+    fn test_versions() {
+        let storage: StupidHashmapStorage<uint, ~str> = StupidHashmapStorage::new();
+        let page = storage.new_page();
+        assert!(storage.version(&page) == 0);
+        assert!(storage.read_versioned(&page) == Ok(None));
+        storage.write(&page, ~"a");
+        storage.write(&page, ~"b");
+        assert!(storage.read_versioned(&page) == Ok(Some((2, ~"b"))));
+        storage.free_page(&page);
+        assert!(storage.read(&page) == Ok(None));
+        assert!(storage.version(&page) == 3);
+        storage.write(&page, ~"c");
+        assert!(storage.read_versioned(&page) == Ok(Some((4, ~"c"))));
     }
-    fn write(&mut self, id: &uint, node: &N) {
-        self.map.insert(id.clone(), node.clone());
+
+    #[test]
+    fn test_free_pages_are_reused() {
+        let storage: StupidHashmapStorage<uint, ~str> = StupidHashmapStorage::new();
+        let (first, second) = (storage.new_page(), storage.new_page());
+        storage.write(&first, ~"a");
+        storage.write(&second, ~"b");
+        storage.free_page(&first);
+        assert!(storage.nb_free() == 1);
+        let page = storage.new_page();
+        assert!(page == first && storage.nb_free() == 0);
+        // the version of the reused page keeps counting
+        assert!(storage.version(&page) == 2);
+        assert!(storage.new_page() == second + 1);
+    }
+
+    #[test]
+    fn test_reader_keeps_freed_page() {
+        let storage: StupidHashmapStorage<uint, ~str> = StupidHashmapStorage::new();
+        let page = storage.new_page();
+        storage.write(&page, ~"a");
+        storage.enter();
+        storage.free_page(&page);
+        // the page isn't reused, while the reader may still see it
+        assert!(storage.read(&page) == Ok(Some(~"a")));
+        assert!(storage.new_page() != page);
+        assert!(storage.nb_retired() == 1);
+        storage.exit();
+        assert!(storage.read(&page) == Ok(None));
+        assert!(storage.nb_retired() == 0 && storage.nb_free() == 1);
     }
 }
@@ -14,9 +14,26 @@
  */
 
 
+use std::local_data;
+use std::unstable::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
 use std::vec;
 
 
+static TASK_ID: local_data::Key<uint> = &local_data::Key;
+static mut NEXT_TASK_ID: AtomicUint = INIT_ATOMIC_UINT;
+
+/// A number, that identifies the current task.
+pub fn task_id() -> uint {
+    match local_data::get(TASK_ID, |id| id.map(|id| *id)) {
+        Some(id) => id,
+        None => {
+            let id = unsafe { NEXT_TASK_ID.fetch_add(1, SeqCst) };
+            local_data::set(TASK_ID, id);
+            id
+        }
+    }
+}
+
 pub fn split_at<T>(vec: &mut~[T], i: uint) ->  ~[T] {
     let len = vec.len();
     if len == 0 { return ~[] }